@@ -0,0 +1,245 @@
+//! Agent mode: fetch the configuration periodically and converge the machine to it.
+//!
+//! Every interval the agent fetches the configuration, from a file or over HTTP, and compares its hash with the last
+//! configuration it applied successfully. When it changed, the recipes run again, except those whose configuration is
+//! the same as in the previous state: they keep their progress, so only new and changed recipes run.
+//!
+//! Every interval the recipes that kept their progress are also checked for drift, see `Plugin::check`, whether the
+//! configuration changed or not. The parts of them that drifted run again.
+//!
+//! The configuration must set `state_path`, so that a restarted agent picks up the progress of its last run.
+
+use std::{collections::HashMap, path::Path, time::Duration};
+
+use serde_json::Value;
+
+use crate::{
+  control::Control,
+  events::EventSink,
+  exit::config_error,
+  include,
+  plugins::{Config, State},
+  utils::{cancel, http_client, lock},
+};
+
+pub struct Agent {
+  /// Path or `http(s)://` URL of the configuration.
+  pub source: String,
+  pub interval: Duration,
+  /// Variables set on the command line, see `Config::resolve_vars`.
+  pub vars: HashMap<String, String>,
+  /// Control API the runs are observed and steered through, if it is enabled.
+  pub control: Option<Control>,
+}
+
+/// A fetched configuration, with its includes merged in.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fetched {
+  pub document: Value,
+  /// Hex SHA-256 of the document.
+  pub hash: String,
+}
+
+/// Fetch the configuration at `source`. Configurations fetched over HTTP cannot use `include`.
+pub async fn fetch(source: &str) -> anyhow::Result<Fetched> {
+  let document = if source.starts_with("http://") || source.starts_with("https://") {
+    log::debug!("Fetching configuration from {source}");
+    let content = http_client().get(source).send().await?.error_for_status()?.text().await?;
+    let name = source.split(['?', '#']).next().unwrap_or(source);
+    let document = include::parse_document(&content, name).map_err(config_error)?;
+    if document.get("include").is_some() {
+      return Err(config_error(anyhow::anyhow!(
+        "{source}: `include` is only supported for configuration files on disk"
+      )));
+    }
+    document
+  } else {
+    include::load_document(Path::new(source)).map_err(config_error)?
+  };
+  let digest = openssl::sha::sha256(&serde_json::to_vec(&document)?);
+  let hash = digest.iter().map(|v| format!("{v:02x}")).collect();
+  Ok(Fetched { document, hash })
+}
+
+impl Agent {
+  /// Converge every interval until the process is cancelled. Failed runs, and runs cancelled through the control API,
+  /// are retried at the next interval.
+  pub async fn run(&self, events: &dyn EventSink) -> anyhow::Result<()> {
+    let mut applied: Option<String> = None;
+    let mut previous: Option<State> = None;
+    loop {
+      if let Err(e) = self.converge(&mut applied, &mut previous, events).await {
+        if cancel::is_cancelled() {
+          return Err(e);
+        }
+        if e.is::<cancel::Interrupted>() {
+          log::warn!("Run cancelled, converging again in {:?}", self.interval);
+        } else {
+          log::error!("Failed to converge, retrying in {:?}: {e:#}", self.interval);
+        }
+      }
+      tokio::select! {
+        _ = tokio::time::sleep(self.interval) => {}
+        _ = cancel::cancelled() => {
+          log::info!("Agent stopped");
+          return Ok(());
+        }
+      }
+    }
+  }
+
+  async fn converge(
+    &self, applied: &mut Option<String>, previous: &mut Option<State>, events: &dyn EventSink,
+  ) -> anyhow::Result<()> {
+    let fetched = fetch(&self.source).await?;
+    if applied.as_deref() == Some(fetched.hash.as_str()) &&
+      let Some(state) = previous.as_mut()
+    {
+      return locked(async {
+        let drift = state.check_drift().await?;
+        let drifted = drift.iter().filter(|(_, v)| v.as_ref().is_some_and(|v| !v.is_empty())).count();
+        if drifted == 0 {
+          log::info!("Configuration unchanged ({}), nothing to do", &fetched.hash[..12]);
+          return Ok(());
+        }
+        log::info!(
+          "{drifted} recipe(s) drifted from configuration {}, applying them again",
+          &fetched.hash[..12]
+        );
+        self.invoke(state, events).await
+      })
+      .await;
+    }
+    log::info!("Applying configuration {} from {}", &fetched.hash[..12], self.source);
+
+    let (mut config, ignored) = Config::from_document(fetched.document, &self.source)?;
+    for field in ignored {
+      log::warn!("Ignoring unknown configuration field: {field}");
+    }
+    config.resolve_vars(&self.vars)?;
+    let issues = config.validate();
+    for issue in &issues {
+      log::error!("{issue}");
+    }
+    let errors = issues.iter().filter(|v| v.is_error()).count();
+    if errors > 0 {
+      return Err(config_error(anyhow::anyhow!(
+        "Configuration is invalid: {errors} error(s)"
+      )));
+    }
+    if config.state_path.is_none() {
+      return Err(config_error(anyhow::anyhow!(
+        "Agent mode needs a `state_path` to keep the progress of its runs"
+      )));
+    }
+
+    locked(async {
+      let mut state = config.into_state();
+      if previous.is_none() &&
+        let Some(path) = state.config.state_path.as_ref().filter(|v| Path::new(v).exists())
+      {
+        match State::from_path(path) {
+          Ok(loaded) => *previous = Some(loaded),
+          Err(e) => log::warn!("Ignoring the previous state in {path}: {e}"),
+        }
+      }
+      if let Some(previous) = previous.as_ref() {
+        let kept = state.carry_over(previous);
+        log::info!(
+          "{} of {} recipe(s) are unchanged since the last run",
+          kept.len(),
+          state.recipes.len()
+        );
+        state.check_drift().await?;
+      }
+
+      let result = self.invoke(&mut state, events).await;
+      *previous = Some(state);
+      result?;
+      *applied = Some(fetched.hash);
+      Ok(())
+    })
+    .await
+  }
+
+  async fn invoke(&self, state: &mut State, events: &dyn EventSink) -> anyhow::Result<()> {
+    let result = match &self.control {
+      Some(control) => state.invoke_controlled(&Default::default(), events, control).await,
+      None => state.invoke_with(events).await,
+    };
+    result.map(|_| ())
+  }
+}
+
+/// Run `work` holding the machine lock, released between intervals so that `apply` can run in the meantime. Drift
+/// checks hold it too: they read the machine and the state file while another run may be changing them.
+async fn locked<T>(work: impl Future<Output = anyhow::Result<T>>) -> anyhow::Result<T> {
+  lock::lock_instance()?;
+  let result = work.await;
+  lock::unlock_instance();
+  result
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::utils::test_http::serve;
+
+  #[tokio::test]
+  async fn fetch_over_http() {
+    let (base, _) = serve(vec![
+      (200, "recipe:\n  - { id: a, use: shell, with: { script: \"true\" } }\n"),
+      (200, "recipe: [{ id: a, use: shell, with: { script: \"true\" } }]\n"),
+      (200, "recipe:\n  - { id: a, use: shell, with: { script: \"false\" } }\n"),
+      (200, "include: [base.yaml]\n"),
+    ])
+    .await;
+    let url = format!("{base}/config.yaml");
+
+    let first = fetch(&url).await.unwrap();
+    assert_eq!(first.hash.len(), 64);
+    // The hash is over the parsed document, so formatting does not count as a change.
+    assert_eq!(fetch(&url).await.unwrap().hash, first.hash);
+    let changed = fetch(&url).await.unwrap();
+    assert_ne!(changed.hash, first.hash);
+    assert!(fetch(&url).await.is_err());
+  }
+
+  #[tokio::test]
+  async fn require_state_path() {
+    let (base, _) = serve(vec![(
+      200,
+      "recipe:\n  - { id: a, use: shell, with: { script: \"true\" } }\n",
+    )])
+    .await;
+    let agent = Agent {
+      source: format!("{base}/config.yaml"),
+      interval: Duration::from_secs(60),
+      vars: HashMap::new(),
+      control: None,
+    };
+    let error = agent.converge(&mut None, &mut None, &crate::events::NoEvents).await.unwrap_err();
+    assert!(error.to_string().contains("`state_path`"), "{error}");
+  }
+
+  #[test]
+  fn carry_over_unchanged_recipes() {
+    let config = |script: &str| {
+      let yaml = r#"
+recipe:
+  - { id: a, use: shell, with: { script: "true" } }
+  - { id: b, use: shell, with: { script: SCRIPT } }
+"#;
+      Config::from_yaml(&yaml.replace("SCRIPT", script)).unwrap()
+    };
+    let mut previous = config("'true'").into_state();
+    for recipe_state in previous.states.values_mut() {
+      recipe_state.mark_done();
+    }
+
+    let mut state = config("'echo changed'").into_state();
+    assert_eq!(state.carry_over(&previous), vec!["a"]);
+    assert!(state.states["a"].is_completed());
+    assert!(!state.states["b"].is_completed());
+  }
+}
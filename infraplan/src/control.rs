@@ -0,0 +1,418 @@
+//! Local HTTP API for observing and steering a run, enabled with `--control`.
+//!
+//! The API listens on a Unix socket (`unix:/run/infraplan/control.sock`) or a loopback address (`127.0.0.1:9300`).
+//! It has no authentication, so it never listens on other addresses.
+//!
+//! - `GET /state`: the state of the run, as written to the state file.
+//! - `GET /events`: the events of the run from now on, one JSON object per line, as with `--output json`. For a single
+//!   run, as with `apply`, the stream ends after the `run_finish` event; in agent mode it follows every run.
+//! - `POST /pause`: start no new recipes once the running ones finished, until `POST /resume`.
+//! - `POST /resume`
+//! - `POST /cancel`: cancel the current run. In agent mode only that run stops: the agent applies the configuration
+//!   again at the next interval. SIGTERM stops the agent itself.
+//! - `POST /skip/<id>`: skip the recipe instead of starting it.
+
+use std::{
+  collections::BTreeSet,
+  convert::Infallible,
+  net::SocketAddr,
+  path::Path,
+  sync::{Arc, Mutex},
+};
+
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full, StreamBody, combinators::BoxBody};
+use hyper::{
+  Method, Request, Response, StatusCode,
+  body::{Frame, Incoming},
+  server::conn::http1,
+  service::service_fn,
+};
+use hyper_util::rt::TokioIo;
+use tokio::{
+  io::{AsyncRead, AsyncWrite},
+  net::{TcpListener, UnixListener},
+  sync::{broadcast, watch},
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+  events::{self, Event},
+  plugins::State,
+};
+
+type Body = BoxBody<Bytes, Infallible>;
+
+/// What the API shares with the run it controls. Clones share the same run.
+#[derive(Clone)]
+pub struct Control(Arc<Shared>);
+
+struct Shared {
+  state: Mutex<Option<State>>,
+  running: Mutex<BTreeSet<String>>,
+  skip: Mutex<BTreeSet<String>>,
+  events: broadcast::Sender<Event>,
+  paused: watch::Sender<bool>,
+  /// The token of the run in progress, which `POST /cancel` cancels.
+  cancel: Mutex<Option<CancellationToken>>,
+  /// Whether the API serves a single run, whose end ends the event streams.
+  single_run: bool,
+}
+
+enum Listener {
+  Tcp(TcpListener),
+  Unix(UnixListener),
+}
+
+impl Default for Control {
+  fn default() -> Self { Self::new() }
+}
+
+impl Control {
+  /// An API for a series of runs, as in agent mode.
+  pub fn new() -> Self { Self::with_runs(false) }
+
+  /// An API for a single run: the event streams end with it.
+  pub fn single_run() -> Self { Self::with_runs(true) }
+
+  fn with_runs(single_run: bool) -> Self {
+    Control(Arc::new(Shared {
+      state: Mutex::new(None),
+      running: Mutex::new(BTreeSet::new()),
+      skip: Mutex::new(BTreeSet::new()),
+      events: broadcast::channel(256).0,
+      paused: watch::Sender::new(false),
+      cancel: Mutex::new(None),
+      single_run,
+    }))
+  }
+
+  /// Start serving the API on `address` in the background. Returns the address it listens on.
+  pub async fn serve(&self, address: &str) -> anyhow::Result<String> {
+    let (listener, address) = match address.strip_prefix("unix:") {
+      Some(path) => {
+        if let Some(parent) = Path::new(path).parent() {
+          std::fs::create_dir_all(parent)?;
+        }
+        // A socket left behind by an earlier run would make the bind fail.
+        _ = std::fs::remove_file(path);
+        let listener = UnixListener::bind(path).map_err(|e| anyhow::anyhow!("Failed to listen on {path}: {e}"))?;
+        (Listener::Unix(listener), address.to_string())
+      }
+      None => {
+        let address: SocketAddr =
+          address.parse().map_err(|e| anyhow::anyhow!("Invalid control address {address}: {e}"))?;
+        if !address.ip().is_loopback() {
+          anyhow::bail!("The control API only listens on loopback addresses or Unix sockets, not {address}");
+        }
+        let listener = TcpListener::bind(address)
+          .await
+          .map_err(|e| anyhow::anyhow!("Failed to listen on {address}: {e}"))?;
+        let address = listener.local_addr()?.to_string();
+        (Listener::Tcp(listener), address)
+      }
+    };
+    log::info!("Control API listening on {address}");
+
+    let control = self.clone();
+    tokio::spawn(async move {
+      loop {
+        let accepted = match &listener {
+          Listener::Tcp(listener) => listener.accept().await.map(|(stream, _)| control.serve_connection(stream)),
+          Listener::Unix(listener) => listener.accept().await.map(|(stream, _)| control.serve_connection(stream)),
+        };
+        if let Err(e) = accepted {
+          log::warn!("Failed to accept a control API connection: {e}");
+        }
+      }
+    });
+    Ok(address)
+  }
+
+  fn serve_connection<I: AsyncRead + AsyncWrite + Unpin + Send + 'static>(&self, stream: I) {
+    let control = self.clone();
+    tokio::spawn(async move {
+      let service = service_fn(|request| {
+        let control = control.clone();
+        async move { Ok::<_, Infallible>(control.handle(&request)) }
+      });
+      if let Err(e) = http1::Builder::new().serve_connection(TokioIo::new(stream), service).await {
+        log::debug!("Control API connection closed: {e}");
+      }
+    });
+  }
+
+  fn handle(&self, request: &Request<Incoming>) -> Response<Body> {
+    let path = request.uri().path();
+    log::debug!("Control API request: {} {path}", request.method());
+    match (request.method(), path) {
+      (&Method::GET, "/state") => match self.0.state.lock().unwrap().as_ref() {
+        Some(state) => json(StatusCode::OK, state),
+        None => error(StatusCode::SERVICE_UNAVAILABLE, "No run has started yet"),
+      },
+      (&Method::GET, "/events") => self.event_stream(),
+      (&Method::POST, "/pause") => {
+        log::info!("Pausing the run, as requested through the control API");
+        self.0.paused.send_replace(true);
+        empty()
+      }
+      (&Method::POST, "/resume") => {
+        log::info!("Resuming the run, as requested through the control API");
+        self.0.paused.send_replace(false);
+        empty()
+      }
+      (&Method::POST, "/cancel") => match self.0.cancel.lock().unwrap().as_ref() {
+        Some(token) => {
+          log::warn!("Cancelling the run, as requested through the control API");
+          token.cancel();
+          empty()
+        }
+        None => error(StatusCode::CONFLICT, "No run is in progress"),
+      },
+      (&Method::POST, path) if path.starts_with("/skip/") => self.request_skip(&path["/skip/".len()..]),
+      (_, "/state" | "/events" | "/pause" | "/resume" | "/cancel") => {
+        error(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed")
+      }
+      _ => error(StatusCode::NOT_FOUND, "Not found"),
+    }
+  }
+
+  fn request_skip(&self, id: &str) -> Response<Body> {
+    {
+      let state = self.0.state.lock().unwrap();
+      let Some(state) = state.as_ref() else {
+        return error(StatusCode::SERVICE_UNAVAILABLE, "No run has started yet");
+      };
+      let Some(recipe_state) = state.states.get(id) else {
+        return error(StatusCode::NOT_FOUND, &format!("No recipe '{id}'"));
+      };
+      if recipe_state.is_completed() || recipe_state.skipped.is_some() {
+        return error(StatusCode::CONFLICT, &format!("Recipe '{id}' is already finished"));
+      }
+    }
+    if self.0.running.lock().unwrap().contains(id) {
+      return error(StatusCode::CONFLICT, &format!("Recipe '{id}' is already running"));
+    }
+    log::info!("Recipe '{id}' will be skipped, as requested through the control API");
+    self.0.skip.lock().unwrap().insert(id.to_string());
+    empty()
+  }
+
+  fn event_stream(&self) -> Response<Body> {
+    let receiver = self.0.events.subscribe();
+    let single_run = self.0.single_run;
+    let lines = futures_util::stream::unfold(Some(receiver), move |receiver| async move {
+      let mut receiver = receiver?;
+      loop {
+        match receiver.recv().await {
+          Ok(event) => match events::json_line(&event) {
+            Ok(line) => {
+              let finished = single_run && matches!(event, Event::RunFinish { .. });
+              return Some((
+                Ok(Frame::data(Bytes::from(line + "\n"))),
+                (!finished).then_some(receiver),
+              ));
+            }
+            Err(e) => log::error!("Failed to serialize event: {e}"),
+          },
+          Err(broadcast::error::RecvError::Lagged(count)) => {
+            log::warn!("A control API client fell behind, {count} event(s) were dropped for it");
+          }
+          Err(broadcast::error::RecvError::Closed) => return None,
+        }
+      }
+    });
+    Response::builder()
+      .header("content-type", "application/x-ndjson")
+      .body(StreamBody::new(lines).boxed())
+      .unwrap()
+  }
+
+  /// Make `state` the state served by the API.
+  pub fn publish_state(&self, state: &State) {
+    // Served without the notify targets, whose headers often carry credentials.
+    let mut state = state.clone();
+    state.take_notify();
+    *self.0.state.lock().unwrap() = Some(state);
+  }
+
+  /// Start a run that `POST /cancel` cancels, along with `parent`. Returns the token of the run.
+  pub fn start_run(&self, parent: &CancellationToken) -> CancellationToken {
+    let token = parent.child_token();
+    *self.0.cancel.lock().unwrap() = Some(token.clone());
+    token
+  }
+
+  /// Send `event` to the clients following the events.
+  pub fn broadcast(&self, event: &Event) {
+    match event {
+      Event::RecipeStart { recipe, .. } => _ = self.0.running.lock().unwrap().insert(recipe.clone()),
+      Event::RecipeFinish { recipe, .. } | Event::RecipeFail { recipe, .. } => {
+        _ = self.0.running.lock().unwrap().remove(recipe)
+      }
+      Event::RunFinish { .. } => _ = self.0.cancel.lock().unwrap().take(),
+      _ => {}
+    }
+    // Sending only fails when nobody is following.
+    _ = self.0.events.send(event.clone());
+  }
+
+  pub fn is_paused(&self) -> bool { *self.0.paused.borrow() }
+
+  /// Wait until the run is resumed.
+  pub async fn resumed(&self) {
+    let mut paused = self.0.paused.subscribe();
+    _ = paused.wait_for(|v| !*v).await;
+  }
+
+  /// Whether skipping the recipe `id` was requested. The request is consumed.
+  pub fn take_skip(&self, id: &str) -> bool { self.0.skip.lock().unwrap().remove(id) }
+}
+
+fn json(status: StatusCode, value: &impl serde::Serialize) -> Response<Body> {
+  match serde_json::to_vec(value) {
+    Ok(body) => Response::builder()
+      .status(status)
+      .header("content-type", "application/json")
+      .body(Full::new(Bytes::from(body)).boxed())
+      .unwrap(),
+    Err(e) => error(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+  }
+}
+
+fn error(status: StatusCode, message: &str) -> Response<Body> { json(status, &serde_json::json!({ "error": message })) }
+
+fn empty() -> Response<Body> {
+  Response::builder().status(StatusCode::NO_CONTENT).body(Full::new(Bytes::new()).boxed()).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    events::NoEvents,
+    plugins::Config,
+    select::Selection,
+    utils::{cancel, test_http::Client},
+  };
+
+  #[tokio::test]
+  async fn control_a_run() {
+    let control = Control::single_run();
+    assert!(control.serve("0.0.0.0:0").await.is_err());
+    let address = control.serve("127.0.0.1:0").await.unwrap();
+    let client = Client::new(format!("http://{address}"));
+
+    let response = client.get("/state").await;
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(client.post("/pause").await.status(), StatusCode::NO_CONTENT);
+
+    let mut events = client.get("/events").await;
+    let mut state = Config::from_yaml(
+      r#"
+recipe:
+  - { id: a, use: shell, with: { script: "true" } }
+  - { id: b, use: shell, with: { script: "true" } }
+  - { id: c, use: shell, with: { script: "true" } }
+"#,
+    )
+    .unwrap()
+    .into_state();
+    let selection = Selection::default();
+    let run = state.invoke_controlled(&selection, &NoEvents, &control);
+
+    let steer = async {
+      // Read events up to the one named `until`, returning their names.
+      let mut buffer = String::new();
+      let mut next_events = async |until: &str| {
+        let mut names = Vec::new();
+        loop {
+          while let Some((line, rest)) = buffer.split_once('\n') {
+            let event: serde_json::Value = serde_json::from_str(line).unwrap();
+            buffer = rest.to_string();
+            let name = event["event"].as_str().unwrap().to_string();
+            names.push(name.clone());
+            if name == until {
+              return names;
+            }
+          }
+          let chunk = events.chunk().await.unwrap().unwrap();
+          buffer.push_str(std::str::from_utf8(&chunk).unwrap());
+        }
+      };
+      assert_eq!(next_events("run_pause").await, vec!["run_start", "run_pause"]);
+
+      let state = client.get("/state").await.text().await.unwrap();
+      let state: State = serde_json::from_str(&state).unwrap();
+      assert_eq!(state.recipes, vec!["a", "b", "c"]);
+      assert_eq!(client.post("/skip/missing").await.status(), StatusCode::NOT_FOUND);
+      assert_eq!(client.post("/skip/b").await.status(), StatusCode::NO_CONTENT);
+      assert_eq!(client.get("/pause").await.status(), StatusCode::METHOD_NOT_ALLOWED);
+      assert_eq!(client.post("/resume").await.status(), StatusCode::NO_CONTENT);
+
+      assert_eq!(
+        next_events("run_finish").await,
+        vec![
+          "run_resume",
+          "recipe_start",
+          "recipe_finish",
+          "recipe_skip",
+          "recipe_start",
+          "recipe_finish",
+          "run_finish"
+        ]
+      );
+      // The stream ends with the run.
+      assert!(events.chunk().await.unwrap().is_none());
+    };
+    let (report, ()) = tokio::join!(run, steer);
+    assert_eq!(
+      report.unwrap().recipes[1].reason.as_deref(),
+      Some("Skipped through the control API")
+    );
+  }
+
+  /// Read the stream of `/events` up to an event named `until`.
+  async fn read_until(events: &mut reqwest::Response, until: &str) {
+    let mut buffer = String::new();
+    while !buffer.contains(&format!("\"event\":\"{until}\"")) {
+      let chunk = events.chunk().await.unwrap().unwrap();
+      buffer.push_str(std::str::from_utf8(&chunk).unwrap());
+    }
+  }
+
+  #[tokio::test]
+  async fn cancel_one_run() {
+    let control = Control::new();
+    let address = control.serve("127.0.0.1:0").await.unwrap();
+    let client = Client::new(format!("http://{address}"));
+    assert_eq!(client.post("/cancel").await.status(), StatusCode::CONFLICT);
+
+    let mut events = client.get("/events").await;
+    let config = |script: &str| {
+      Config::from_yaml(&format!(
+        "recipe:\n  - {{ id: a, use: shell, with: {{ script: \"{script}\" }} }}\n"
+      ))
+      .unwrap()
+      .into_state()
+    };
+    let selection = Selection::default();
+    let mut state = config("sleep 30");
+    let run = state.invoke_controlled(&selection, &NoEvents, &control);
+    let cancel = async {
+      read_until(&mut events, "recipe_start").await;
+      assert_eq!(client.post("/cancel").await.status(), StatusCode::NO_CONTENT);
+      read_until(&mut events, "run_finish").await;
+    };
+    let (result, ()) = tokio::join!(run, cancel);
+    assert!(result.unwrap_err().is::<cancel::Interrupted>());
+    assert!(!cancel::is_cancelled());
+    assert_eq!(client.post("/cancel").await.status(), StatusCode::CONFLICT);
+
+    // The next run, as the agent starts at the next interval, is not cancelled, and the stream follows it.
+    let mut state = config("true");
+    let run = state.invoke_controlled(&selection, &NoEvents, &control);
+    let (report, ()) = tokio::join!(run, read_until(&mut events, "run_finish"));
+    assert_eq!(report.unwrap().status, events::RunStatus::Success);
+  }
+}
@@ -0,0 +1,156 @@
+//! Differences between the configuration embedded in a state and the configuration it was loaded from.
+
+use std::fmt;
+
+use serde_json::Value;
+
+use crate::plugins::Config;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Difference {
+  /// A setting outside of the recipes, e.g. `global.distro_hint`.
+  Setting {
+    path: String,
+    old: Value,
+    new: Value,
+  },
+  RecipeAdded(String),
+  RecipeRemoved(String),
+  RecipeChanged {
+    id: String,
+    path: String,
+    old: Value,
+    new: Value,
+  },
+  /// The same recipes are listed in another order.
+  RecipeOrder,
+}
+
+impl fmt::Display for Difference {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Difference::Setting { path, old, new } => write!(f, "~ {path}: {old} -> {new}"),
+      Difference::RecipeAdded(id) => write!(f, "+ recipe '{id}'"),
+      Difference::RecipeRemoved(id) => write!(f, "- recipe '{id}'"),
+      Difference::RecipeChanged { id, path, old, new } => write!(f, "~ recipe '{id}': {path}: {old} -> {new}"),
+      Difference::RecipeOrder => write!(f, "~ recipes are listed in another order"),
+    }
+  }
+}
+
+/// Compare `old`, the configuration a state was created from, with `new`.
+pub fn diff(old: &Config, new: &Config) -> anyhow::Result<Vec<Difference>> {
+  let mut differences = Vec::new();
+
+  let settings = |config: &Config| -> anyhow::Result<Value> {
+    let mut value = serde_json::to_value(config)?;
+    if let Some(map) = value.as_object_mut() {
+      map.remove("recipe");
+      map.remove("include");
+    }
+    Ok(value)
+  };
+  let mut changes = Vec::new();
+  diff_values(&settings(old)?, &settings(new)?, "", &mut changes);
+  differences.extend(changes.into_iter().map(|(path, old, new)| Difference::Setting { path, old, new }));
+
+  for recipe in &old.recipe {
+    let Some(other) = new.recipe.iter().find(|v| v.id == recipe.id) else {
+      differences.push(Difference::RecipeRemoved(recipe.id.clone()));
+      continue;
+    };
+    let mut changes = Vec::new();
+    diff_values(
+      &serde_json::to_value(recipe)?,
+      &serde_json::to_value(other)?,
+      "",
+      &mut changes,
+    );
+    differences.extend(changes.into_iter().map(|(path, old, new)| Difference::RecipeChanged {
+      id: recipe.id.clone(),
+      path,
+      old,
+      new,
+    }));
+  }
+  for recipe in new.recipe.iter().filter(|v| !old.recipe.iter().any(|r| r.id == v.id)) {
+    differences.push(Difference::RecipeAdded(recipe.id.clone()));
+  }
+
+  let common = |a: &Config, b: &Config| -> Vec<String> {
+    a.recipe.iter().filter(|v| b.recipe.iter().any(|r| r.id == v.id)).map(|v| v.id.clone()).collect()
+  };
+  if common(old, new) != common(new, old) {
+    differences.push(Difference::RecipeOrder);
+  }
+  Ok(differences)
+}
+
+/// Collect `(path, old, new)` for every leaf that differs. Arrays of different lengths are compared as a whole.
+fn diff_values(old: &Value, new: &Value, path: &str, out: &mut Vec<(String, Value, Value)>) {
+  let join = |key: &str| {
+    if path.is_empty() {
+      key.to_string()
+    } else {
+      format!("{path}.{key}")
+    }
+  };
+  match (old, new) {
+    (Value::Object(old), Value::Object(new)) => {
+      for (key, value) in old {
+        diff_values(value, new.get(key).unwrap_or(&Value::Null), &join(key), out);
+      }
+      for (key, value) in new.iter().filter(|(key, _)| !old.contains_key(*key)) {
+        diff_values(&Value::Null, value, &join(key), out);
+      }
+    }
+    (Value::Array(old), Value::Array(new)) if old.len() == new.len() => {
+      for (i, (old, new)) in old.iter().zip(new).enumerate() {
+        diff_values(old, new, &format!("{path}[{i}]"), out);
+      }
+    }
+    (old, new) if old != new => out.push((path.to_string(), old.clone(), new.clone())),
+    _ => {}
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn diff_configs() {
+    let old = Config::from_yaml(
+      r#"
+global: { distro_hint: ubuntu }
+recipe:
+  - { id: deploy, use: shell, with: { commands: [[mkfs, /dev/vdb]] } }
+  - { id: sysconf, use: shell, with: { script: "true" } }
+  - { id: reboot, use: shell, with: { script: reboot } }
+"#,
+    )
+    .unwrap();
+    assert_eq!(diff(&old, &old).unwrap(), vec![]);
+
+    let new = Config::from_yaml(
+      r#"
+global: { distro_hint: debian }
+recipe:
+  - { id: deploy, use: shell, with: { commands: [[mkfs, /dev/sda]] } }
+  - { id: packages, use: shell, with: { script: "true" } }
+  - { id: reboot, use: shell, with: { script: reboot } }
+"#,
+    )
+    .unwrap();
+    let differences: Vec<String> = diff(&old, &new).unwrap().iter().map(|v| v.to_string()).collect();
+    assert_eq!(
+      differences,
+      vec![
+        r#"~ global.distro_hint: "ubuntu" -> "debian""#,
+        r#"~ recipe 'deploy': with.commands[0][1]: "/dev/vdb" -> "/dev/sda""#,
+        "- recipe 'sysconf'",
+        "+ recipe 'packages'",
+      ]
+    );
+  }
+}
@@ -0,0 +1,261 @@
+//! Events emitted while a `State` is invoked, and the report that ends every run.
+
+use std::{
+  fmt,
+  io::Write,
+  time::{SystemTime, UNIX_EPOCH},
+};
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+  RunStart {
+    recipes: Vec<String>,
+  },
+  RecipeStart {
+    recipe: String,
+    plugin: String,
+  },
+  RecipeFinish {
+    recipe: String,
+    plugin: String,
+    duration_ms: u64,
+    attempts: usize,
+  },
+  RecipeSkip {
+    recipe: String,
+    plugin: String,
+    reason: String,
+  },
+  RecipeFail {
+    recipe: String,
+    plugin: String,
+    duration_ms: u64,
+    attempts: usize,
+    error: String,
+  },
+  /// No new recipes are started until the run is resumed, see `control`.
+  RunPause,
+  RunResume,
+  RunFinish {
+    report: Report,
+  },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RecipeStatus {
+  /// Not started, because an earlier recipe failed or the run was interrupted.
+  Pending,
+  Running,
+  /// Not run because its condition is false or it was completed in an earlier run, see `reason`.
+  Skipped,
+  Completed,
+  Failed,
+  /// Still running when the run was interrupted.
+  Interrupted,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RunStatus {
+  Success,
+  Failed,
+  Interrupted,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct RecipeReport {
+  pub recipe: String,
+  pub plugin: String,
+  pub status: RecipeStatus,
+  pub duration_ms: Option<u64>,
+  pub attempts: usize,
+  pub reason: Option<String>,
+  pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Report {
+  pub status: RunStatus,
+  pub duration_ms: u64,
+  pub error: Option<String>,
+  pub recipes: Vec<RecipeReport>,
+}
+
+impl Report {
+  pub fn new(recipes: impl IntoIterator<Item = (String, String)>) -> Self {
+    Report {
+      status: RunStatus::Success,
+      duration_ms: 0,
+      error: None,
+      recipes: recipes
+        .into_iter()
+        .map(|(recipe, plugin)| RecipeReport {
+          recipe,
+          plugin,
+          status: RecipeStatus::Pending,
+          duration_ms: None,
+          attempts: 0,
+          reason: None,
+          error: None,
+        })
+        .collect(),
+    }
+  }
+
+  /// Record `event` for the recipe it concerns.
+  pub fn record(&mut self, event: &Event) {
+    let (recipe, status) = match event {
+      Event::RecipeStart { recipe, .. } => (recipe, RecipeStatus::Running),
+      Event::RecipeFinish { recipe, .. } => (recipe, RecipeStatus::Completed),
+      Event::RecipeSkip { recipe, .. } => (recipe, RecipeStatus::Skipped),
+      Event::RecipeFail { recipe, .. } => (recipe, RecipeStatus::Failed),
+      Event::RunStart { .. } | Event::RunPause | Event::RunResume | Event::RunFinish { .. } => return,
+    };
+    let Some(entry) = self.recipes.iter_mut().find(|v| &v.recipe == recipe) else {
+      return;
+    };
+    entry.status = status;
+    match event {
+      Event::RecipeFinish {
+        duration_ms, attempts, ..
+      } => {
+        entry.duration_ms = Some(*duration_ms);
+        entry.attempts = *attempts;
+      }
+      Event::RecipeSkip { reason, .. } => entry.reason = Some(reason.clone()),
+      Event::RecipeFail {
+        duration_ms,
+        attempts,
+        error,
+        ..
+      } => {
+        entry.duration_ms = Some(*duration_ms);
+        entry.attempts = *attempts;
+        entry.error = Some(error.clone());
+      }
+      _ => {}
+    }
+  }
+
+  pub fn finish(&mut self, duration_ms: u64, result: &anyhow::Result<()>, interrupted: bool) {
+    self.duration_ms = duration_ms;
+    self.status = match (result, interrupted) {
+      (_, true) => RunStatus::Interrupted,
+      (Ok(()), false) => RunStatus::Success,
+      (Err(_), false) => RunStatus::Failed,
+    };
+    self.error = result.as_ref().err().map(|e| e.to_string());
+    if interrupted {
+      for entry in &mut self.recipes {
+        if entry.status == RecipeStatus::Running {
+          entry.status = RecipeStatus::Interrupted;
+        }
+      }
+    }
+  }
+
+  /// Whether any recipe ran to completion in this run.
+  pub fn changed(&self) -> bool { self.recipes.iter().any(|v| v.status == RecipeStatus::Completed) }
+}
+
+impl fmt::Display for RecipeStatus {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let status = match self {
+      RecipeStatus::Pending => "pending",
+      RecipeStatus::Running => "running",
+      RecipeStatus::Skipped => "skipped",
+      RecipeStatus::Completed => "completed",
+      RecipeStatus::Failed => "failed",
+      RecipeStatus::Interrupted => "interrupted",
+    };
+    f.write_str(status)
+  }
+}
+
+impl fmt::Display for Report {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let status = match self.status {
+      RunStatus::Success => "succeeded",
+      RunStatus::Failed => "failed",
+      RunStatus::Interrupted => "was interrupted",
+    };
+    writeln!(f, "Run {status} in {:.1}s", self.duration_ms as f64 / 1000.0)?;
+    for entry in &self.recipes {
+      write!(f, "  {:<24} {:<22} {}", entry.recipe, entry.plugin, entry.status)?;
+      if let Some(duration_ms) = entry.duration_ms {
+        write!(f, " in {:.1}s", duration_ms as f64 / 1000.0)?;
+      }
+      if entry.attempts > 1 {
+        write!(f, " after {} attempts", entry.attempts)?;
+      }
+      if let Some(reason) = &entry.reason {
+        write!(f, ": {reason}")?;
+      }
+      if let Some(error) = &entry.error {
+        write!(f, ": {error}")?;
+      }
+      writeln!(f)?;
+    }
+    Ok(())
+  }
+}
+
+/// Receives the events of a run as they happen.
+pub trait EventSink: Send + Sync {
+  fn emit(&self, event: &Event);
+}
+
+impl<T: EventSink + ?Sized> EventSink for Box<T> {
+  fn emit(&self, event: &Event) { (**self).emit(event) }
+}
+
+/// Discards all events.
+pub struct NoEvents;
+
+impl EventSink for NoEvents {
+  fn emit(&self, _event: &Event) {}
+}
+
+/// Prints the report to stdout when the run finishes.
+pub struct Summary;
+
+impl EventSink for Summary {
+  fn emit(&self, event: &Event) {
+    if let Event::RunFinish { report } = event {
+      print!("{report}");
+    }
+  }
+}
+
+/// Writes every event to stdout as one JSON object per line, with a `timestamp` in milliseconds since the Unix epoch.
+pub struct JsonLines;
+
+#[derive(serde::Serialize)]
+struct TimestampedEvent<'a> {
+  timestamp: u64,
+  #[serde(flatten)]
+  event: &'a Event,
+}
+
+/// Serialize `event` as it is written by `JsonLines`, without the trailing newline.
+pub fn json_line(event: &Event) -> serde_json::Result<String> {
+  serde_json::to_string(&TimestampedEvent {
+    timestamp: SystemTime::now().duration_since(UNIX_EPOCH).map(|v| v.as_millis() as u64).unwrap_or(0),
+    event,
+  })
+}
+
+impl EventSink for JsonLines {
+  fn emit(&self, event: &Event) {
+    match json_line(event) {
+      Ok(line) => {
+        let mut stdout = std::io::stdout().lock();
+        _ = writeln!(stdout, "{line}");
+        _ = stdout.flush();
+      }
+      Err(e) => log::error!("Failed to serialize event: {e}"),
+    }
+  }
+}
@@ -0,0 +1,223 @@
+//! Running a configuration from another program, which is also how the `apply` and `recover` commands run it.
+//!
+//! ```no_run
+//! # async fn example() -> anyhow::Result<()> {
+//! use infraplan::{Config, Executor, events::JsonLines};
+//!
+//! let config = Config::from_path("deploy.yaml")?;
+//! let report = Executor::new(config).events(JsonLines).run().await?;
+//! println!("{report}");
+//! # Ok(())
+//! # }
+//! ```
+
+use serde::{Serialize, de::DeserializeOwned};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+  control::Control,
+  events::{EventSink, NoEvents, Report},
+  exit::config_error,
+  plugins::{Config, Plugin, State, external},
+  select::Selection,
+  store::StateStore,
+  utils::cancel,
+};
+
+/// Runs the recipes of a configuration, or resumes the run of a saved state.
+pub struct Executor {
+  state: State,
+  events: Box<dyn EventSink>,
+  cancel: Option<CancellationToken>,
+  store: Option<Box<dyn StateStore>>,
+  selection: Selection,
+  control: Option<Control>,
+}
+
+impl Executor {
+  /// Run `config` from the start.
+  pub fn new(config: Config) -> Self { Self::from_state(config.into_state()) }
+
+  /// Continue the run `state` was saved from. Completed recipes are skipped.
+  pub fn from_state(state: State) -> Self {
+    Executor {
+      state,
+      events: Box::new(NoEvents),
+      cancel: None,
+      store: None,
+      selection: Selection::default(),
+      control: None,
+    }
+  }
+
+  /// Continue the run saved in `store`, saving its progress back there.
+  pub fn resume(store: impl StateStore + 'static) -> anyhow::Result<Self> {
+    let state = store.load()?.ok_or_else(|| config_error(anyhow::anyhow!("No state to resume")))?;
+    Ok(Self::from_state(state).state_store(store))
+  }
+
+  /// Report the progress of the run to `sink`. Events are discarded by default.
+  pub fn events(mut self, sink: impl EventSink + 'static) -> Self {
+    self.events = Box::new(sink);
+    self
+  }
+
+  /// Cancel the run when `token` is cancelled. Other runs in the process are not affected. Without a token, the run is
+  /// only cancelled along with the process, as SIGTERM does for the CLI.
+  pub fn cancel_token(mut self, token: CancellationToken) -> Self {
+    self.cancel = Some(token);
+    self
+  }
+
+  /// Save the state to `store` instead of the configuration's `state_path`.
+  pub fn state_store(mut self, store: impl StateStore + 'static) -> Self {
+    self.store = Some(Box::new(store));
+    self
+  }
+
+  /// Run only the recipes `selection` selects.
+  pub fn selection(mut self, selection: Selection) -> Self {
+    self.selection = selection;
+    self
+  }
+
+  /// Let `control` observe and steer the run, see `Control::serve`.
+  pub fn control(mut self, control: Control) -> Self {
+    self.control = Some(control);
+    self
+  }
+
+  /// Run `plugin` for the recipes that `use: external` with `plugin: <name>`. Registered plugins are shared by every
+  /// run in the process, see `external::register`.
+  pub fn plugin<P>(self, name: &str, plugin: P) -> Self
+  where
+    P: Plugin + Send + Sync + 'static,
+    P::Config: DeserializeOwned,
+    P::State: Serialize + DeserializeOwned + Default,
+  {
+    external::register(name, plugin);
+    self
+  }
+
+  pub fn state(&self) -> &State { &self.state }
+
+  pub fn into_state(self) -> State { self.state }
+
+  /// Run the recipes that are not completed yet, see `State::invoke_selected`.
+  pub async fn run(&mut self) -> anyhow::Result<Report> {
+    let token = self.cancel.clone().unwrap_or_else(cancel::run_token);
+    self
+      .state
+      .invoke_inner(
+        &self.selection,
+        self.events.as_ref(),
+        self.control.as_ref(),
+        self.store.as_deref(),
+        token,
+      )
+      .await
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::{Arc, Mutex};
+
+  use super::*;
+  use crate::events::{Event, RunStatus};
+
+  struct Greeter;
+
+  #[derive(serde::Deserialize)]
+  struct GreeterConfig {
+    name: String,
+  }
+
+  impl Plugin for Greeter {
+    type Config = GreeterConfig;
+    type State = Vec<String>;
+
+    async fn invoke(&self, config: &Self::Config, state: &mut Self::State) -> anyhow::Result<()> {
+      state.push(format!("Hello, {}", config.name));
+      Ok(())
+    }
+
+    fn plan(&self, config: &Self::Config, _state: &Self::State) -> anyhow::Result<Vec<String>> {
+      Ok(vec![format!("Greet {}", config.name)])
+    }
+  }
+
+  #[derive(Clone, Default)]
+  struct Memory(Arc<Mutex<Option<State>>>);
+
+  impl StateStore for Memory {
+    fn load(&self) -> anyhow::Result<Option<State>> { Ok(self.0.lock().unwrap().clone()) }
+
+    fn save(&self, state: &State) -> anyhow::Result<()> {
+      *self.0.lock().unwrap() = Some(state.clone());
+      Ok(())
+    }
+  }
+
+  #[derive(Clone, Default)]
+  struct Recorder(Arc<Mutex<Vec<Event>>>);
+
+  impl EventSink for Recorder {
+    fn emit(&self, event: &Event) { self.0.lock().unwrap().push(event.clone()); }
+  }
+
+  #[tokio::test]
+  async fn run_embedded() {
+    let config = Config::from_yaml(
+      r#"
+recipe:
+  - { id: greet, use: external, with: { plugin: greeter-executor-test, config: { name: world } } }
+"#,
+    )
+    .unwrap();
+    let store = Memory::default();
+    let events = Recorder::default();
+    let mut executor = Executor::new(config)
+      .plugin("greeter-executor-test", Greeter)
+      .state_store(store.clone())
+      .events(events.clone())
+      .cancel_token(CancellationToken::new());
+    assert_eq!(executor.state().states["greet"].plan().unwrap(), vec!["Greet world"]);
+
+    let report = executor.run().await.unwrap();
+    assert_eq!(report.status, RunStatus::Success);
+    assert!(matches!(events.0.lock().unwrap().last(), Some(Event::RunFinish { .. })));
+    let saved = store.load().unwrap().unwrap();
+    assert_eq!(&saved, executor.state());
+    assert!(saved.states["greet"].is_completed());
+    let crate::plugins::PluginState::External(state) = &saved.states["greet"].state else {
+      panic!("Unexpected state {:?}", saved.states["greet"].state);
+    };
+    assert_eq!(state.state, serde_json::json!(["Hello, world"]));
+
+    // Resuming a finished run has nothing left to do.
+    let report = Executor::resume(store).unwrap().run().await.unwrap();
+    assert!(!report.changed());
+  }
+
+  #[tokio::test]
+  async fn cancel_one_run() {
+    let config = |script: &str| {
+      Config::from_yaml(&format!(
+        "recipe:\n  - {{ id: a, use: shell, with: {{ script: \"{script}\" }} }}\n"
+      ))
+      .unwrap()
+    };
+    let token = CancellationToken::new();
+    let mut cancelled = Executor::new(config("sleep 30")).cancel_token(token.clone());
+    let mut other = Executor::new(config("sleep 1"));
+    let cancel = async {
+      tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+      token.cancel();
+    };
+    let (cancelled, other, ()) = tokio::join!(cancelled.run(), other.run(), cancel);
+    assert!(cancelled.unwrap_err().is::<cancel::Interrupted>());
+    assert_eq!(other.unwrap().status, RunStatus::Success);
+    assert!(!cancel::is_cancelled());
+  }
+}
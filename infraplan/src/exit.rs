@@ -0,0 +1,102 @@
+//! Process exit codes, and the errors that select them.
+//!
+//! | Code | Command                      | Meaning                                                       |
+//! | ---- | ---------------------------- | ------------------------------------------------------------- |
+//! | 0    | `apply`, `recover`           | Success, at least one recipe made changes                     |
+//! | 0    | `check`                      | No recipe drifted                                             |
+//! | 0    | `state diff`                 | The configuration matches the state                           |
+//! | 0    | Others, e.g. `validate`      | Success                                                       |
+//! | 1    | All                          | Unexpected error, e.g. the state file could not be saved      |
+//! | 2    | All                          | The configuration or state could not be loaded, or is invalid |
+//! | 3    | `apply`, `recover`           | A recipe failed                                               |
+//! | 4    | `apply`, `recover`           | Success, nothing to do                                        |
+//! | 5    | `check`                      | A recipe drifted                                              |
+//! | 5    | `state diff`                 | The configuration differs from the state                      |
+//! | 130  | All                          | Interrupted by SIGINT or SIGTERM                              |
+
+use std::fmt;
+
+use crate::{events::Report, utils::cancel};
+
+/// A command that applies nothing, such as `validate` or `state show`, succeeded.
+pub const SUCCESS: u8 = 0;
+pub const CHANGED: u8 = 0;
+pub const ERROR: u8 = 1;
+pub const CONFIG_ERROR: u8 = 2;
+pub const RECIPE_FAILED: u8 = 3;
+pub const UNCHANGED: u8 = 4;
+/// `check` found drift, or `state diff` a difference.
+pub const DRIFTED: u8 = 5;
+pub const INTERRUPTED: u8 = cancel::EXIT_INTERRUPTED as u8;
+
+/// An error tagged with the kind of failure it is, so that it maps to its exit code.
+#[derive(Debug)]
+pub enum RunError {
+  Config(anyhow::Error),
+  Recipe(anyhow::Error),
+}
+
+impl fmt::Display for RunError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      RunError::Config(e) | RunError::Recipe(e) => write!(f, "{e}"),
+    }
+  }
+}
+
+impl std::error::Error for RunError {
+  fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+    match self {
+      RunError::Config(e) | RunError::Recipe(e) => e.source(),
+    }
+  }
+}
+
+pub fn config_error(e: anyhow::Error) -> anyhow::Error {
+  match e.downcast_ref::<RunError>() {
+    Some(_) => e,
+    None => RunError::Config(e).into(),
+  }
+}
+
+pub fn recipe_error(e: anyhow::Error) -> anyhow::Error {
+  match e.downcast_ref::<RunError>() {
+    Some(_) => e,
+    None => RunError::Recipe(e).into(),
+  }
+}
+
+/// The exit code for an error.
+pub fn code_for_error(e: &anyhow::Error) -> u8 {
+  if cancel::is_cancelled() || e.is::<cancel::Interrupted>() {
+    return INTERRUPTED;
+  }
+  match e.downcast_ref::<RunError>() {
+    Some(RunError::Config(_)) => CONFIG_ERROR,
+    Some(RunError::Recipe(_)) => RECIPE_FAILED,
+    None => ERROR,
+  }
+}
+
+/// The exit code for a run that finished without errors.
+pub fn code_for_report(report: &Report) -> u8 { if report.changed() { CHANGED } else { UNCHANGED } }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn error_codes() {
+    assert_eq!(code_for_error(&config_error(anyhow::anyhow!("bad yaml"))), CONFIG_ERROR);
+    assert_eq!(
+      code_for_error(&recipe_error(anyhow::anyhow!("disk failed"))),
+      RECIPE_FAILED
+    );
+    assert_eq!(
+      code_for_error(&recipe_error(config_error(anyhow::anyhow!("bad yaml")))),
+      CONFIG_ERROR
+    );
+    assert_eq!(code_for_error(&anyhow::anyhow!("disk full")), ERROR);
+    assert_eq!(config_error(anyhow::anyhow!("bad yaml")).to_string(), "bad yaml");
+  }
+}
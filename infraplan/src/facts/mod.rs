@@ -0,0 +1,282 @@
+use std::path::Path;
+
+/// Facts about the running host, exposed to recipe conditions and `${facts.*}` references as `facts.*`.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Facts {
+  pub hostname: Option<String>,
+  /// From `os-release` of the root the facts were gathered for, if it has one.
+  pub os: Option<OsRelease>,
+  pub arch: String,
+  pub efi: bool,
+  pub cpu: Cpu,
+  pub memory: Memory,
+  pub disks: Vec<BlockDevice>,
+  pub nics: Vec<Nic>,
+  pub dmi: Dmi,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct OsRelease {
+  pub id: Option<String>,
+  pub id_like: Vec<String>,
+  pub name: Option<String>,
+  pub pretty_name: Option<String>,
+  pub version_id: Option<String>,
+  pub version_codename: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Cpu {
+  pub model: Option<String>,
+  /// Number of logical CPUs.
+  pub count: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Memory {
+  pub total_bytes: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct BlockDevice {
+  pub name: String,
+  pub path: String,
+  pub size_bytes: u64,
+  pub model: Option<String>,
+  pub serial: Option<String>,
+  pub removable: bool,
+  pub rotational: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Nic {
+  pub name: String,
+  pub mac: Option<String>,
+  /// `operstate` from sysfs, e.g. `up` or `down`.
+  pub state: Option<String>,
+  /// Whether the interface is backed by a device, as opposed to a bridge, bond or other virtual interface.
+  pub physical: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Dmi {
+  pub vendor: Option<String>,
+  pub product: Option<String>,
+  /// Only readable by root.
+  pub serial: Option<String>,
+}
+
+fn read_trimmed<P: AsRef<Path>>(path: P) -> Option<String> {
+  std::fs::read_to_string(path).ok().map(|v| v.trim().to_string()).filter(|v| !v.is_empty())
+}
+
+/// List the entries of a sysfs class directory, sorted by name.
+fn list_dir(path: &Path) -> Vec<String> {
+  let Ok(entries) = std::fs::read_dir(path) else {
+    log::warn!("Failed to list {}", path.display());
+    return Vec::new();
+  };
+  let mut names: Vec<String> =
+    entries.filter_map(|v| v.ok()).map(|v| v.file_name().to_string_lossy().to_string()).collect();
+  names.sort();
+  names
+}
+
+/// Parse the `KEY=value` lines of an `os-release` file.
+pub fn parse_os_release(content: &str) -> OsRelease {
+  let mut os = OsRelease::default();
+  for line in content.lines().map(str::trim).filter(|v| !v.is_empty() && !v.starts_with('#')) {
+    let Some((key, value)) = line.split_once('=') else {
+      continue;
+    };
+    let value = value.trim().trim_matches(|c| c == '"' || c == '\'').to_string();
+    match key.trim() {
+      "ID" => os.id = Some(value),
+      "ID_LIKE" => os.id_like = value.split_whitespace().map(str::to_string).collect(),
+      "NAME" => os.name = Some(value),
+      "PRETTY_NAME" => os.pretty_name = Some(value),
+      "VERSION_ID" => os.version_id = Some(value),
+      "VERSION_CODENAME" => os.version_codename = Some(value),
+      _ => {}
+    }
+  }
+  os
+}
+
+/// Read the `os-release` of the system installed in `root`.
+pub fn read_os_release<P: AsRef<Path>>(root: P) -> Option<OsRelease> {
+  ["etc/os-release", "usr/lib/os-release"]
+    .iter()
+    .find_map(|v| std::fs::read_to_string(root.as_ref().join(v)).ok())
+    .map(|v| parse_os_release(&v))
+}
+
+fn gather_cpu(host: &Path) -> Cpu {
+  let cpuinfo = std::fs::read_to_string(host.join("proc/cpuinfo")).unwrap_or_default();
+  let mut model = None;
+  let mut count = 0;
+  for (key, value) in cpuinfo.lines().filter_map(|v| v.split_once(':')) {
+    match key.trim() {
+      "processor" => count += 1,
+      "model name" | "Model" | "cpu model" if model.is_none() => model = Some(value.trim().to_string()),
+      _ => {}
+    }
+  }
+  Cpu { model, count }
+}
+
+fn gather_memory(host: &Path) -> Memory {
+  let meminfo = std::fs::read_to_string(host.join("proc/meminfo")).unwrap_or_default();
+  let total_kb = meminfo
+    .lines()
+    .find_map(|v| v.strip_prefix("MemTotal:"))
+    .and_then(|v| v.trim().trim_end_matches("kB").trim().parse::<u64>().ok())
+    .unwrap_or(0);
+  Memory {
+    total_bytes: total_kb * 1024,
+  }
+}
+
+fn gather_disks(host: &Path) -> Vec<BlockDevice> {
+  let sys_block = host.join("sys/block");
+  list_dir(&sys_block)
+    .into_iter()
+    .filter(|name| !["loop", "ram", "zram", "dm-", "md", "sr"].iter().any(|p| name.starts_with(p)))
+    .map(|name| {
+      let dir = sys_block.join(&name);
+      BlockDevice {
+        path: format!("/dev/{name}"),
+        // Sizes in sysfs are always in 512-byte sectors, whatever the logical block size is.
+        size_bytes: read_trimmed(dir.join("size")).and_then(|v| v.parse::<u64>().ok()).unwrap_or(0) * 512,
+        model: read_trimmed(dir.join("device/model")),
+        serial: read_trimmed(dir.join("device/serial")).or_else(|| read_trimmed(dir.join("serial"))),
+        removable: read_trimmed(dir.join("removable")).as_deref() == Some("1"),
+        rotational: read_trimmed(dir.join("queue/rotational")).as_deref() == Some("1"),
+        name,
+      }
+    })
+    .collect()
+}
+
+fn gather_nics(host: &Path) -> Vec<Nic> {
+  let sys_net = host.join("sys/class/net");
+  list_dir(&sys_net)
+    .into_iter()
+    .filter(|name| name != "lo")
+    .map(|name| {
+      let dir = sys_net.join(&name);
+      Nic {
+        mac: read_trimmed(dir.join("address")).filter(|v| v != "00:00:00:00:00:00"),
+        state: read_trimmed(dir.join("operstate")),
+        physical: dir.join("device").exists(),
+        name,
+      }
+    })
+    .collect()
+}
+
+impl Facts {
+  /// Gather facts about the running host.
+  pub fn gather() -> Self { Self::gather_for("/") }
+
+  /// Gather facts about the running host, with the operating system read from `root` instead, e.g. a freshly
+  /// deployed system mounted there.
+  pub fn gather_for<P: AsRef<Path>>(root: P) -> Self { Self::gather_from(Path::new("/"), root.as_ref()) }
+
+  /// Gather hardware facts from `/sys` and `/proc` under `host` and the operating system from `root`.
+  fn gather_from(host: &Path, root: &Path) -> Self {
+    let dmi = host.join("sys/class/dmi/id");
+    Facts {
+      hostname: read_trimmed(host.join("proc/sys/kernel/hostname")),
+      os: read_os_release(root),
+      arch: std::env::consts::ARCH.to_string(),
+      efi: host.join("sys/firmware/efi").exists(),
+      cpu: gather_cpu(host),
+      memory: gather_memory(host),
+      disks: gather_disks(host),
+      nics: gather_nics(host),
+      dmi: Dmi {
+        vendor: read_trimmed(dmi.join("sys_vendor")),
+        product: read_trimmed(dmi.join("product_name")),
+        serial: read_trimmed(dmi.join("product_serial")),
+      },
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn gather_facts() {
+    let host = std::env::temp_dir().join(format!("infraplan-facts-{}", std::process::id()));
+    let files = [
+      (
+        "etc/os-release",
+        "NAME=\"Ubuntu\"\nID=ubuntu\nID_LIKE=debian\nVERSION_ID=\"24.04\"\nVERSION_CODENAME=noble\n",
+      ),
+      (
+        "proc/cpuinfo",
+        "processor\t: 0\nmodel name\t: QEMU Virtual CPU\n\nprocessor\t: 1\nmodel name\t: QEMU Virtual CPU\n",
+      ),
+      (
+        "proc/meminfo",
+        "MemTotal:        2048000 kB\nMemFree:          100000 kB\n",
+      ),
+      ("proc/sys/kernel/hostname", "node-1\n"),
+      ("sys/block/vda/size", "41943040\n"),
+      ("sys/block/vda/serial", "disk-1\n"),
+      ("sys/block/vda/removable", "0\n"),
+      ("sys/block/vda/queue/rotational", "1\n"),
+      ("sys/block/nvme0n1/size", "2048\n"),
+      ("sys/block/nvme0n1/device/model", "Samsung SSD 980\n"),
+      ("sys/block/nvme0n1/device/serial", "S64DNX0R\n"),
+      ("sys/block/loop0/size", "0\n"),
+      ("sys/class/net/lo/address", "00:00:00:00:00:00\n"),
+      ("sys/class/net/eth0/address", "52:54:00:12:34:56\n"),
+      ("sys/class/net/eth0/operstate", "up\n"),
+      ("sys/class/net/eth0/device/vendor", "0x1af4\n"),
+      ("sys/class/net/br0/address", "52:54:00:ab:cd:ef\n"),
+      ("sys/class/dmi/id/sys_vendor", "QEMU\n"),
+      ("sys/class/dmi/id/product_serial", "\n"),
+    ];
+    for (path, content) in files {
+      let path = host.join(path);
+      std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+      std::fs::write(path, content).unwrap();
+    }
+    std::fs::create_dir_all(host.join("sys/firmware/efi")).unwrap();
+
+    let facts = Facts::gather_from(&host, &host);
+    assert_eq!(facts.hostname.as_deref(), Some("node-1"));
+    let os = facts.os.as_ref().unwrap();
+    assert_eq!(os.id.as_deref(), Some("ubuntu"));
+    assert_eq!(os.id_like, vec!["debian"]);
+    assert_eq!(os.version_id.as_deref(), Some("24.04"));
+    assert!(facts.efi);
+    assert_eq!(facts.cpu.count, 2);
+    assert_eq!(facts.cpu.model.as_deref(), Some("QEMU Virtual CPU"));
+    assert_eq!(facts.memory.total_bytes, 2048000 * 1024);
+
+    let names: Vec<&str> = facts.disks.iter().map(|v| v.name.as_str()).collect();
+    assert_eq!(names, vec!["nvme0n1", "vda"]);
+    assert_eq!(facts.disks[0].model.as_deref(), Some("Samsung SSD 980"));
+    assert_eq!(facts.disks[0].serial.as_deref(), Some("S64DNX0R"));
+    assert_eq!(facts.disks[1].size_bytes, 20 * 1024 * 1024 * 1024);
+    assert_eq!(facts.disks[1].serial.as_deref(), Some("disk-1"));
+    assert!(facts.disks[1].rotational);
+
+    assert_eq!(facts.nics.len(), 2);
+    assert_eq!(facts.nics[1].name, "eth0");
+    assert_eq!(facts.nics[1].mac.as_deref(), Some("52:54:00:12:34:56"));
+    assert!(facts.nics[1].physical);
+    assert!(!facts.nics[0].physical);
+
+    assert_eq!(facts.dmi.vendor.as_deref(), Some("QEMU"));
+    assert_eq!(facts.dmi.serial, None);
+
+    assert_eq!(Facts::gather_from(&host, &host.join("nonexistent")).os, None);
+    std::fs::remove_dir_all(&host).unwrap();
+  }
+}
@@ -0,0 +1,170 @@
+//! Composition of configuration files through `include`.
+//!
+//! Included files are loaded first, in order, and the including file is merged on top of them. Objects are merged
+//! key by key, recipes are merged by `id` (a recipe with a new id is appended), and any other value replaces the one
+//! before it. A recipe that changes `use` replaces the earlier recipe instead of being merged into it.
+
+use std::path::{Path, PathBuf};
+
+use serde_json::Value;
+
+/// Read the document at `path` with all of its includes merged in. The result no longer has an `include` key.
+pub fn load_document(path: &Path) -> anyhow::Result<Value> { load_with_stack(path, &mut Vec::new()) }
+
+fn read_document(path: &Path) -> anyhow::Result<Value> {
+  let content = std::fs::read_to_string(path).map_err(|e| anyhow::anyhow!("Failed to read {}: {e}", path.display()))?;
+  parse_document(&content, &path.to_string_lossy())
+}
+
+/// Parse `content` as JSON or YAML, by the extension of `name`.
+pub fn parse_document(content: &str, name: &str) -> anyhow::Result<Value> {
+  let ext_name = Path::new(name).extension().and_then(|s| s.to_str()).unwrap_or("");
+  let value: Value = match ext_name {
+    "json" => serde_json::from_str(content).map_err(|e| anyhow::anyhow!("{name}: {e}"))?,
+    "yaml" | "yml" => serde_yml::from_str(content).map_err(|e| anyhow::anyhow!("{name}: {e}"))?,
+    _ => anyhow::bail!("Unsupported file format: {name}"),
+  };
+  Ok(match value {
+    Value::Null => Value::Object(Default::default()),
+    value => value,
+  })
+}
+
+fn load_with_stack(path: &Path, stack: &mut Vec<PathBuf>) -> anyhow::Result<Value> {
+  let canonical = path.canonicalize().map_err(|e| anyhow::anyhow!("Failed to resolve {}: {e}", path.display()))?;
+  if stack.contains(&canonical) {
+    anyhow::bail!("Include cycle at {}", path.display());
+  }
+
+  let mut document = read_document(path)?;
+  let includes = match document.as_object_mut().and_then(|v| v.remove("include")) {
+    None | Some(Value::Null) => Vec::new(),
+    Some(Value::Array(items)) => items,
+    Some(_) => anyhow::bail!("{}: `include` must be a list of paths", path.display()),
+  };
+  if includes.is_empty() {
+    return Ok(document);
+  }
+
+  stack.push(canonical);
+  let base_dir = path.parent().unwrap_or(Path::new("."));
+  let mut merged = Value::Object(Default::default());
+  for include in includes {
+    let Value::String(include) = include else {
+      anyhow::bail!("{}: `include` must be a list of paths", path.display());
+    };
+    log::debug!("Including {include} from {}", path.display());
+    merge(&mut merged, load_with_stack(&base_dir.join(&include), stack)?);
+  }
+  stack.pop();
+
+  merge(&mut merged, document);
+  Ok(merged)
+}
+
+/// Merge `overlay` into `base` in place.
+pub fn merge(base: &mut Value, overlay: Value) {
+  match (base, overlay) {
+    (Value::Object(base), Value::Object(overlay)) => {
+      for (key, value) in overlay {
+        match (key.as_str(), base.get_mut(&key)) {
+          ("recipe", Some(Value::Array(recipes))) => merge_recipes(recipes, value),
+          (_, Some(existing)) => merge(existing, value),
+          (_, None) => {
+            base.insert(key, value);
+          }
+        }
+      }
+    }
+    (base, overlay) => *base = overlay,
+  }
+}
+
+fn merge_recipes(base: &mut Vec<Value>, overlay: Value) {
+  let Value::Array(overlay) = overlay else {
+    return;
+  };
+  for recipe in overlay {
+    let existing = recipe.get("id").and_then(|id| base.iter_mut().find(|v| v.get("id") == Some(id)));
+    match existing {
+      Some(existing) if existing.get("use") == recipe.get("use") || recipe.get("use").is_none() => {
+        merge(existing, recipe)
+      }
+      Some(existing) => *existing = recipe,
+      None => base.push(recipe),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn merge_overlay() {
+    let mut base = serde_json::json!({
+      "global": { "distro_hint": "ubuntu" },
+      "vars": { "disk": "/dev/vdb", "mirror": "https://archive.ubuntu.com" },
+      "recipe": [
+        { "id": "deploy", "use": "system_deployer", "with": { "type": "tar", "disk": "/dev/vdb", "mount": "/mnt" } },
+        { "id": "packages", "use": "package_manager", "with": { "install": ["vim", "git"] } },
+      ],
+    });
+    merge(
+      &mut base,
+      serde_json::json!({
+        "vars": { "disk": "/dev/sda" },
+        "recipe": [
+          { "id": "deploy", "with": { "mount": "/target" } },
+          { "id": "packages", "use": "package_manager", "with": { "install": ["htop"] } },
+          { "id": "extra", "use": "package_manager", "with": { "remove": ["snapd"] } },
+        ],
+      }),
+    );
+    assert_eq!(
+      base,
+      serde_json::json!({
+        "global": { "distro_hint": "ubuntu" },
+        "vars": { "disk": "/dev/sda", "mirror": "https://archive.ubuntu.com" },
+        "recipe": [
+          { "id": "deploy", "use": "system_deployer", "with": { "type": "tar", "disk": "/dev/vdb", "mount": "/target" } },
+          { "id": "packages", "use": "package_manager", "with": { "install": ["htop"] } },
+          { "id": "extra", "use": "package_manager", "with": { "remove": ["snapd"] } },
+        ],
+      })
+    );
+  }
+
+  #[test]
+  fn load_includes() {
+    let dir = std::env::temp_dir().join(format!("infraplan-include-{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("lib")).unwrap();
+    std::fs::write(
+      dir.join("lib/packages.yaml"),
+      "recipe:\n  - id: packages\n    use: package_manager\n    with: { install: [vim] }\n",
+    )
+    .unwrap();
+    std::fs::write(
+      dir.join("base.yaml"),
+      "include: [lib/packages.yaml]\nglobal: { distro_hint: ubuntu }\n",
+    )
+    .unwrap();
+    std::fs::write(
+      dir.join("host.yaml"),
+      "include: [base.yaml]\nrecipe:\n  - id: packages\n    with: { install: [vim, htop] }\n",
+    )
+    .unwrap();
+    std::fs::write(dir.join("loop.yaml"), "include: [loop.yaml]\n").unwrap();
+
+    let document = load_document(&dir.join("host.yaml")).unwrap();
+    assert_eq!(
+      document,
+      serde_json::json!({
+        "global": { "distro_hint": "ubuntu" },
+        "recipe": [{ "id": "packages", "use": "package_manager", "with": { "install": ["vim", "htop"] } }],
+      })
+    );
+    assert!(load_document(&dir.join("loop.yaml")).is_err());
+    std::fs::remove_dir_all(&dir).unwrap();
+  }
+}
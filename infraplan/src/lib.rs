@@ -0,0 +1,25 @@
+//! Deploy and configure a machine from a single configuration.
+//!
+//! The `infraplan` command is a thin wrapper over this crate: load a `Config`, then run it with an `Executor`. Programs
+//! embedding infraplan can report events to their own `EventSink`, keep the `State` in their own `StateStore`, cancel
+//! the run with their own token and add plugins of their own.
+
+pub mod agent;
+pub mod control;
+pub mod diff;
+pub mod events;
+pub mod executor;
+pub mod exit;
+pub mod facts;
+pub mod include;
+pub mod notify;
+pub mod plugins;
+pub mod select;
+pub mod store;
+pub mod utils;
+pub mod validate;
+
+pub use events::{EventSink, Report};
+pub use executor::Executor;
+pub use plugins::{Config, Plugin, State};
+pub use store::{FileStore, StateStore};
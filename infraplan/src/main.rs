@@ -1,9 +1,11 @@
-use clap::Parser;
-
-use crate::utils::elevate_privileges;
+use std::{collections::HashMap, path::Path, process::ExitCode};
 
-pub mod plugins;
-pub mod utils;
+use clap::Parser;
+use infraplan::{
+  Executor, FileStore, agent, control, diff, events, exit, facts, plugins, select,
+  utils::{self, cancel, elevate_privileges, lock},
+  validate,
+};
 
 #[derive(Parser, Debug)]
 struct Cli {
@@ -22,6 +24,31 @@ enum Command {
   /// Recover states.
   Recover(RecoverArgs),
 
+  /// Print the actions the configuration would take, without applying it.
+  Plan(PlanArgs),
+
+  /// Check the configuration for errors without applying it.
+  Validate(ValidateArgs),
+
+  /// Report how this machine drifted from the configuration, by recipe. Exits with 5 if it did, 0 if not.
+  Check(CheckArgs),
+
+  /// Print the JSON Schema of the configuration format.
+  Schema,
+
+  /// Print the configuration with includes merged and variables resolved.
+  Render(RenderArgs),
+
+  /// Print the facts gathered about this machine as JSON.
+  Facts(FactsArgs),
+
+  /// Fetch the configuration periodically and apply it whenever it changes. The configuration must set `state_path`.
+  Agent(AgentArgs),
+
+  /// Inspect or edit a state file.
+  #[clap(subcommand)]
+  State(StateCommand),
+
   #[cfg(debug_assertions)]
   InternalTest(InternalTestArgs),
 }
@@ -30,19 +57,228 @@ enum Command {
 struct ApplyArgs {
   /// Path to the configuration file to apply.
   path: String,
+
+  #[clap(long, value_enum, default_value_t = OutputFormat::Text)]
+  output: OutputFormat,
+
+  /// Set a variable for `${name}` references, taking precedence over `vars` and the environment.
+  #[clap(long = "set", value_name = "KEY=VALUE", value_parser = parse_var)]
+  set: Vec<(String, String)>,
+
+  #[clap(flatten)]
+  select: SelectArgs,
+
+  /// Serve the control API on this address while running, e.g. `127.0.0.1:9300` or `unix:/run/infraplan/control.sock`.
+  #[clap(long, value_name = "ADDRESS")]
+  control: Option<String>,
+
+  /// Keep the progress of the last run in `state_path` for unchanged recipes, and run only the parts of them that
+  /// drifted, along with new and changed recipes.
+  #[clap(long, default_value = "false")]
+  only_drifted: bool,
+}
+
+/// Recipe selectors. Recipes that are not selected are skipped, and count as finished for the recipes after them.
+#[derive(clap::Args, Debug)]
+struct SelectArgs {
+  /// Run only these recipes.
+  #[clap(long, value_name = "ID", value_delimiter = ',')]
+  only: Vec<String>,
+
+  /// Do not run these recipes.
+  #[clap(long, value_name = "ID", value_delimiter = ',')]
+  skip: Vec<String>,
+
+  /// Start at this recipe, skipping the ones listed before it.
+  #[clap(long, value_name = "ID")]
+  from: Option<String>,
+
+  /// Stop after this recipe, skipping the ones listed after it.
+  #[clap(long, value_name = "ID")]
+  until: Option<String>,
+
+  /// Run only recipes with at least one of these tags.
+  #[clap(long, value_name = "TAG", value_delimiter = ',')]
+  tags: Vec<String>,
+
+  /// Do not run recipes with any of these tags.
+  #[clap(long, value_name = "TAG", value_delimiter = ',')]
+  skip_tags: Vec<String>,
+}
+
+impl SelectArgs {
+  fn selection(&self) -> select::Selection {
+    select::Selection {
+      only: self.only.clone(),
+      skip: self.skip.clone(),
+      from: self.from.clone(),
+      until: self.until.clone(),
+      tags: self.tags.clone(),
+      skip_tags: self.skip_tags.clone(),
+    }
+  }
 }
 
 #[derive(Parser, Debug)]
 struct RecoverArgs {
   /// Path to the state file.
   path: String,
+
+  #[clap(long, value_enum, default_value_t = OutputFormat::Text)]
+  output: OutputFormat,
+
+  /// Serve the control API on this address while running, e.g. `127.0.0.1:9300` or `unix:/run/infraplan/control.sock`.
+  #[clap(long, value_name = "ADDRESS")]
+  control: Option<String>,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum OutputFormat {
+  /// Logs, followed by a summary of the run.
+  Text,
+  /// One JSON event per line on stdout, ending with a `run_finish` event carrying the report.
+  Json,
+}
+
+impl OutputFormat {
+  fn sink(self) -> Box<dyn events::EventSink> {
+    match self {
+      OutputFormat::Text => Box::new(events::Summary),
+      OutputFormat::Json => Box::new(events::JsonLines),
+    }
+  }
+}
+
+#[derive(Parser, Debug)]
+struct PlanArgs {
+  /// Path to the configuration file to plan.
+  path: String,
+
+  /// Set a variable for `${name}` references, taking precedence over `vars` and the environment.
+  #[clap(long = "set", value_name = "KEY=VALUE", value_parser = parse_var)]
+  set: Vec<(String, String)>,
+
+  #[clap(flatten)]
+  select: SelectArgs,
+}
+
+#[derive(Parser, Debug)]
+struct ValidateArgs {
+  /// Path to the configuration file to validate.
+  path: String,
+
+  /// Set a variable for `${name}` references, taking precedence over `vars` and the environment.
+  #[clap(long = "set", value_name = "KEY=VALUE", value_parser = parse_var)]
+  set: Vec<(String, String)>,
+}
+
+#[derive(Parser, Debug)]
+struct CheckArgs {
+  /// Path to the configuration file to check against.
+  path: String,
+
+  /// Set a variable for `${name}` references, taking precedence over `vars` and the environment.
+  #[clap(long = "set", value_name = "KEY=VALUE", value_parser = parse_var)]
+  set: Vec<(String, String)>,
+}
+
+#[derive(Parser, Debug)]
+struct RenderArgs {
+  /// Path to the configuration file to render.
+  path: String,
+
+  /// Set a variable for `${name}` references, taking precedence over `vars` and the environment.
+  #[clap(long = "set", value_name = "KEY=VALUE", value_parser = parse_var)]
+  set: Vec<(String, String)>,
+
+  /// Print JSON instead of YAML.
+  #[clap(long, default_value = "false")]
+  json: bool,
+}
+
+#[derive(Parser, Debug)]
+struct FactsArgs {
+  /// Read the operating system from this root instead of `/`, e.g. a deployed system that is still mounted.
+  #[clap(long)]
+  root: Option<String>,
+}
+
+#[derive(Parser, Debug)]
+struct AgentArgs {
+  /// Path or `http(s)://` URL of the configuration.
+  #[clap(long)]
+  source: String,
+
+  /// How long to wait between checks, e.g. `15m`.
+  #[clap(long, default_value = "15m")]
+  interval: String,
+
+  #[clap(long, value_enum, default_value_t = OutputFormat::Text)]
+  output: OutputFormat,
+
+  /// Set a variable for `${name}` references, taking precedence over `vars` and the environment.
+  #[clap(long = "set", value_name = "KEY=VALUE", value_parser = parse_var)]
+  set: Vec<(String, String)>,
+
+  /// Serve the control API on this address while running, e.g. `127.0.0.1:9300` or `unix:/run/infraplan/control.sock`.
+  #[clap(long, value_name = "ADDRESS")]
+  control: Option<String>,
+}
+
+#[derive(Parser, Debug)]
+enum StateCommand {
+  /// Print the recipes of a state file and how far each of them got.
+  Show {
+    /// Path to the state file.
+    path: String,
+  },
+
+  /// Reset recipes so that they run again from the start.
+  Reset {
+    /// Path to the state file.
+    path: String,
+
+    #[clap(required = true, value_name = "ID")]
+    ids: Vec<String>,
+  },
+
+  /// Mark recipes as completed without running them.
+  MarkDone {
+    /// Path to the state file.
+    path: String,
+
+    #[clap(required = true, value_name = "ID")]
+    ids: Vec<String>,
+  },
+
+  /// Compare the configuration embedded in a state file with a configuration file. Exits with 5 if they differ, 0 if
+  /// they match.
+  Diff {
+    /// Path to the configuration file.
+    config: String,
+
+    /// Path to the state file. Defaults to the `state_path` of the configuration.
+    #[clap(long)]
+    state: Option<String>,
+
+    /// Set a variable for `${name}` references, taking precedence over `vars` and the environment.
+    #[clap(long = "set", value_name = "KEY=VALUE", value_parser = parse_var)]
+    set: Vec<(String, String)>,
+  },
 }
 
 #[derive(Parser, Debug)]
 struct InternalTestArgs {}
 
+fn parse_var(s: &str) -> anyhow::Result<(String, String)> {
+  let (key, value) = s.split_once('=').ok_or(anyhow::anyhow!("Expected KEY=VALUE, got `{s}`"))?;
+  Ok((key.to_string(), value.to_string()))
+}
+
+fn vars_map(set: &[(String, String)]) -> HashMap<String, String> { set.iter().cloned().collect() }
+
 #[tokio::main]
-async fn main() -> anyhow::Result<()> {
+async fn main() -> ExitCode {
   let cli = Cli::parse();
 
   env_logger::Builder::from_env(env_logger::Env::default().default_filter_or({
@@ -60,44 +296,362 @@ async fn main() -> anyhow::Result<()> {
 
   log::debug!("Parsed CLI arguments: {cli:?}");
 
-  elevate_privileges()?;
-  match cli.command {
+  match run(cli.command).await {
+    Ok(code) => ExitCode::from(code),
+    Err(e) => {
+      let code = exit::code_for_error(&e);
+      if code == exit::INTERRUPTED {
+        log::error!("Interrupted, state has been saved");
+      } else {
+        log::error!("{e:#}");
+      }
+      ExitCode::from(code)
+    }
+  }
+}
+
+async fn run(command: Command) -> anyhow::Result<u8> {
+  match command {
     Command::Apply(args) => {
-      args.run().await?;
+      elevate_privileges()?;
+      lock::lock_instance()?;
+      cancel::listen_for_signals()?;
+      return Ok(exit::code_for_report(&args.run().await?));
     }
     Command::Recover(args) => {
-      args.run().await?;
+      elevate_privileges()?;
+      lock::lock_instance()?;
+      cancel::listen_for_signals()?;
+      return Ok(exit::code_for_report(&args.run().await?));
+    }
+    Command::Plan(args) => {
+      args.run()?;
+    }
+    Command::Validate(args) => {
+      args.run()?;
+    }
+    Command::Check(args) => return args.run().await,
+    Command::Schema => {
+      println!("{}", plugins::Config::json_schema()?);
+    }
+    Command::Render(args) => {
+      args.run()?;
+    }
+    Command::Facts(args) => {
+      let facts = facts::Facts::gather_for(args.root.as_deref().unwrap_or("/"));
+      println!("{}", serde_json::to_string_pretty(&facts)?);
     }
+    Command::Agent(args) => {
+      let mut agent = agent::Agent {
+        source: args.source.clone(),
+        interval: utils::parse_duration(&args.interval).map_err(exit::config_error)?,
+        vars: vars_map(&args.set),
+        control: None,
+      };
+      elevate_privileges()?;
+      cancel::listen_for_signals()?;
+      if let Some(address) = &args.control {
+        let control = control::Control::new();
+        control.serve(address).await?;
+        agent.control = Some(control);
+      }
+      agent.run(args.output.sink().as_ref()).await?;
+    }
+    Command::State(command) => return command.run(),
     #[cfg(debug_assertions)]
     Command::InternalTest(args) => {
       args.run().await?;
     }
   }
-  Ok(())
+  Ok(exit::SUCCESS)
 }
 
 impl ApplyArgs {
-  async fn run(&self) -> anyhow::Result<()> {
+  async fn run(&self) -> anyhow::Result<events::Report> {
     log::info!("Applying configuration from path: {}", self.path);
-    match plugins::Config::from_path(&self.path) {
-      Ok(config) => {
-        let mut state = config.into_state();
-        state.invoke().await?;
-        log::info!("Configuration applied successfully.");
+    let mut config = plugins::Config::from_path(&self.path)?;
+    config.resolve_vars(&vars_map(&self.set))?;
+    let issues = config.validate();
+    for issue in &issues {
+      log::error!("{issue}");
+    }
+    let errors = issues.iter().filter(|v| v.is_error()).count();
+    if errors > 0 {
+      return Err(exit::config_error(anyhow::anyhow!(
+        "Configuration is invalid: {errors} error(s)"
+      )));
+    }
+
+    let mut state = config.into_state();
+    if self.only_drifted {
+      match state.config.state_path.clone().filter(|v| Path::new(v).exists()) {
+        Some(path) => {
+          let kept = state.carry_over(&plugins::State::from_path(&path)?);
+          log::info!(
+            "{} of {} recipe(s) are unchanged since the last run",
+            kept.len(),
+            state.recipes.len()
+          );
+          state.check_drift().await?;
+        }
+        None => log::warn!("No state of a previous run in `state_path`, running every recipe"),
       }
-      Err(e) => log::error!("Error reading configuration: {e}"),
     }
-    Ok(())
+    let executor = Executor::from_state(state).selection(self.select.selection());
+    let report = run_executor(executor, self.output, self.control.as_deref()).await?;
+    log::info!("Configuration applied successfully.");
+    Ok(report)
+  }
+}
+
+/// Run `executor`, serving the control API on `control` while it runs if it is set.
+async fn run_executor(
+  executor: Executor, output: OutputFormat, control: Option<&str>,
+) -> anyhow::Result<events::Report> {
+  let mut executor = executor.events(output.sink());
+  if let Some(address) = control {
+    let control = control::Control::single_run();
+    control.serve(address).await?;
+    executor = executor.control(control);
+  }
+  executor.run().await
+}
+
+impl StateCommand {
+  fn run(&self) -> anyhow::Result<u8> {
+    match self {
+      StateCommand::Show { path } => {
+        let state = plugins::State::from_path(path)?;
+        println!("{:<24} {:<22} STATUS", "RECIPE", "PLUGIN");
+        for recipe_id in &state.recipes {
+          let Some(recipe_state) = state.states.get(recipe_id) else {
+            println!("{recipe_id:<24} {:<22} missing", "");
+            continue;
+          };
+          println!(
+            "{recipe_id:<24} {:<22} {}",
+            recipe_state.config.name(),
+            describe_recipe_state(recipe_state)
+          );
+        }
+      }
+      StateCommand::Reset { path, ids } | StateCommand::MarkDone { path, ids } => {
+        // A run in progress would overwrite the edited state with its own.
+        elevate_privileges()?;
+        lock::lock_instance()?;
+        let mut state = plugins::State::from_path(path)?;
+        for id in ids {
+          let Some(recipe_state) = state.states.get_mut(id) else {
+            return Err(exit::config_error(anyhow::anyhow!("No recipe '{id}' in {path}")));
+          };
+          if matches!(self, StateCommand::Reset { .. }) {
+            recipe_state.reset();
+            log::info!("Reset recipe '{id}'");
+          } else {
+            recipe_state.mark_done();
+            log::info!("Marked recipe '{id}' as done");
+          }
+        }
+        state.save(path)?;
+      }
+      StateCommand::Diff { config, state, set } => {
+        let mut config = plugins::Config::from_path(config)?;
+        config.resolve_vars(&vars_map(set))?;
+        let Some(path) = state.as_ref().or(config.state_path.as_ref()) else {
+          return Err(exit::config_error(anyhow::anyhow!(
+            "No state file given and the configuration has no `state_path`"
+          )));
+        };
+        let state = plugins::State::from_path(path)?;
+        let differences = diff::diff(&state.config, &config)?;
+        for difference in &differences {
+          println!("{difference}");
+        }
+        if differences.is_empty() {
+          log::info!("The configuration matches {path}");
+          return Ok(exit::SUCCESS);
+        }
+        return Ok(exit::DRIFTED);
+      }
+    }
+    Ok(exit::SUCCESS)
+  }
+}
+
+/// A one-line summary of how far a recipe got, for `state show`.
+fn describe_recipe_state(recipe_state: &plugins::RecipeState) -> String {
+  let mut status = if recipe_state.is_completed() {
+    "completed".to_string()
+  } else if let Some(reason) = &recipe_state.skipped {
+    format!("skipped: {reason}")
+  } else if let Some(error) = recipe_state.attempts.last().and_then(|v| v.error.as_ref()) {
+    format!("failed: {error}")
+  } else {
+    "pending".to_string()
+  };
+  if let (plugins::PluginConfig::SystemReconfigurator(config), plugins::PluginState::SystemReconfigurator(done)) =
+    (&recipe_state.config, &recipe_state.state) &&
+    !recipe_state.is_completed()
+  {
+    let count = done.iter().filter(|v| **v).count();
+    status.push_str(&format!(" ({count}/{} items done)", config.with.len()));
+  }
+  if recipe_state.attempts.len() > 1 {
+    status.push_str(&format!(" after {} attempts", recipe_state.attempts.len()));
   }
+  status
 }
 
 impl RecoverArgs {
-  async fn run(&self) -> anyhow::Result<()> {
+  async fn run(&self) -> anyhow::Result<events::Report> {
     log::info!("Recovering states from path: {}", self.path);
+    let executor = Executor::resume(FileStore(self.path.clone()))?;
+    let report = run_executor(executor, self.output, self.control.as_deref()).await?;
+    log::info!("Configuration applied successfully.");
+    Ok(report)
+  }
+}
+
+impl PlanArgs {
+  fn run(&self) -> anyhow::Result<()> {
+    let mut config = plugins::Config::from_path(&self.path)?;
+    config.resolve_vars(&vars_map(&self.set))?;
+    let selection = self.select.selection();
+    selection.check(&config.recipe).map_err(exit::config_error)?;
+    let state = config.into_state();
+    let facts = serde_json::to_value(facts::Facts::gather())?;
+    for (i, recipe_id) in state.recipes.iter().enumerate() {
+      let Some(recipe_state) = state.states.get(recipe_id) else {
+        log::warn!("Recipe state for '{recipe_id}' not found");
+        continue;
+      };
+      println!(
+        "[{}/{}] {recipe_id} ({})",
+        i + 1,
+        state.recipes.len(),
+        recipe_state.display_name
+      );
+      if let Some(reason) = selection.skip_reason(&state.config.recipe, recipe_id) {
+        println!("  not selected, will be skipped: {reason}");
+        continue;
+      }
+      if let Some(when) = &recipe_state.when {
+        match recipe_state.condition(&facts) {
+          Ok(true) => println!("  when: {when} (currently true)"),
+          Ok(false) => {
+            println!("  when: {when} (currently false, will be skipped)");
+            continue;
+          }
+          Err(e) => println!("  ! {e}"),
+        }
+      }
+      match recipe_state.plan() {
+        Ok(actions) => actions.iter().for_each(|action| println!("  - {action}")),
+        Err(e) => println!("  ! {e}"),
+      }
+    }
+    Ok(())
+  }
+}
+
+impl ValidateArgs {
+  fn run(&self) -> anyhow::Result<()> {
+    let (config, ignored) = plugins::Config::load(&self.path)?;
+    let mut issues: Vec<_> = ignored
+      .into_iter()
+      .map(|field| validate::Issue {
+        severity: validate::Severity::Warning,
+        recipe: None,
+        message: format!("Unknown field `{field}`"),
+      })
+      .collect();
+    if let Err(e) = config.clone().resolve_vars(&vars_map(&self.set)) {
+      issues.push(validate::Issue {
+        severity: validate::Severity::Error,
+        recipe: None,
+        message: e.to_string(),
+      });
+    }
+    issues.extend(config.validate());
+    for issue in &issues {
+      println!("{issue}");
+    }
+
+    let errors = issues.iter().filter(|v| v.is_error()).count();
+    if errors > 0 {
+      return Err(exit::config_error(anyhow::anyhow!(
+        "Configuration is invalid: {errors} error(s)"
+      )));
+    }
+    println!("Configuration is valid.");
     Ok(())
   }
 }
 
+impl CheckArgs {
+  async fn run(&self) -> anyhow::Result<u8> {
+    let mut config = plugins::Config::from_path(&self.path)?;
+    config.resolve_vars(&vars_map(&self.set))?;
+    let mut state = config.into_state();
+    // Check every recipe as if it had been applied, except those a run would skip for their condition.
+    let facts = serde_json::to_value(facts::Facts::gather())?;
+    for recipe_state in state.states.values_mut() {
+      if recipe_state.condition(&facts).map_err(exit::config_error)? {
+        recipe_state.mark_done();
+      }
+    }
+
+    let drift = state.check_drift().await?;
+    let mut drifted = 0;
+    for recipe_id in &state.recipes {
+      let plugin = state.states.get(recipe_id).map(|v| v.config.name()).unwrap_or_default();
+      match drift.iter().find(|(id, _)| id == recipe_id).map(|(_, v)| v) {
+        None => println!("{recipe_id:<24} {plugin:<22} not checked, its condition is false"),
+        Some(None) => println!("{recipe_id:<24} {plugin:<22} cannot be checked"),
+        Some(Some(found)) if found.is_empty() => println!("{recipe_id:<24} {plugin:<22} in sync"),
+        Some(Some(found)) => {
+          drifted += 1;
+          println!("{recipe_id:<24} {plugin:<22} drifted");
+          found.iter().for_each(|v| println!("  - {v}"));
+        }
+      }
+    }
+    if drifted == 0 {
+      log::info!("No recipe drifted from {}", self.path);
+      return Ok(exit::SUCCESS);
+    }
+    log::info!("{drifted} recipe(s) drifted from {}", self.path);
+    Ok(exit::DRIFTED)
+  }
+}
+
+impl RenderArgs {
+  fn run(&self) -> anyhow::Result<()> {
+    let mut config = plugins::Config::from_path(&self.path)?;
+    config.resolve_vars(&vars_map(&self.set))?;
+    let mut value = serde_json::to_value(&config)?;
+    strip_nulls(&mut value);
+    if self.json {
+      println!("{}", serde_json::to_string_pretty(&value)?);
+    } else {
+      print!("{}", serde_yml::to_string(&value)?);
+    }
+    Ok(())
+  }
+}
+
+/// Drop unset optional fields so the rendered config reads like a hand-written one.
+fn strip_nulls(value: &mut serde_json::Value) {
+  match value {
+    serde_json::Value::Object(map) => {
+      map.retain(|_, v| !v.is_null());
+      map.values_mut().for_each(strip_nulls);
+    }
+    serde_json::Value::Array(items) => items.iter_mut().for_each(strip_nulls),
+    _ => {}
+  }
+}
+
 #[cfg(debug_assertions)]
 impl InternalTestArgs {
   async fn run(&self) -> anyhow::Result<()> {
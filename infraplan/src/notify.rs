@@ -0,0 +1,325 @@
+//! Webhooks that tell other systems how a run went, configured with `global.notify`.
+//!
+//! Every notification is a JSON `Notification` POSTed to each target interested in its event. Failed requests are
+//! retried, but a notification that cannot be delivered only logs a warning: it never fails the run.
+
+use std::{
+  collections::{BTreeMap, HashMap},
+  path::{Path, PathBuf},
+  sync::{Arc, Mutex},
+  time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use tokio::task::JoinHandle;
+
+use crate::{
+  events::{Event, Report, RunStatus},
+  facts::Facts,
+  plugins::State,
+  utils::http_client,
+};
+
+const DEFAULT_ATTEMPTS: u32 = 3;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize, schemars::JsonSchema)]
+pub struct Notify {
+  /// `http(s)://` URL the notifications are POSTed to.
+  pub url: String,
+  /// Headers sent with every request, e.g. `Authorization`.
+  pub headers: Option<HashMap<String, String>>,
+  /// Events to notify of. Defaults to `run_success`, `run_failure` and `reboot`.
+  pub events: Option<Vec<NotifyEvent>>,
+  /// Attempts per notification, including the first. Defaults to 3.
+  pub attempts: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize, schemars::JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum NotifyEvent {
+  /// The run finished and every recipe completed or was skipped.
+  RunSuccess,
+  /// The run failed or was interrupted.
+  RunFailure,
+  RecipeSuccess,
+  RecipeFailure,
+  /// A reboot recipe is about to boot into the deployed system. Sent once the notifications before it are delivered,
+  /// and waited for before rebooting.
+  Reboot,
+}
+
+impl Notify {
+  pub fn wants(&self, event: NotifyEvent) -> bool {
+    match &self.events {
+      Some(events) => events.contains(&event),
+      None => matches!(
+        event,
+        NotifyEvent::RunSuccess | NotifyEvent::RunFailure | NotifyEvent::Reboot
+      ),
+    }
+  }
+}
+
+/// The notify targets of a state, kept apart from it. Their headers often carry credentials, so `State::save` writes
+/// them to a file only its owner can read, see `Targets::path`, instead of into the state.
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Targets {
+  /// `global.notify`.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub global: Option<Vec<Notify>>,
+  /// `overrides.notify`, by recipe id.
+  #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+  pub overrides: BTreeMap<String, Vec<Notify>>,
+}
+
+impl Targets {
+  /// Where the targets of the state at `state_path` are saved.
+  pub fn path(state_path: &Path) -> PathBuf {
+    let mut path = state_path.as_os_str().to_owned();
+    path.push(".notify");
+    PathBuf::from(path)
+  }
+
+  pub fn is_empty(&self) -> bool { self.global.is_none() && self.overrides.is_empty() }
+}
+
+/// The JSON body of a notification.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Notification {
+  pub event: NotifyEvent,
+  /// Seconds since the Unix epoch.
+  pub timestamp: u64,
+  pub recipe: Option<String>,
+  /// `success`, `failed` or `interrupted` for runs, `completed` or `failed` for recipes and `rebooting` for reboots.
+  pub status: String,
+  pub error: Option<String>,
+  pub facts: Facts,
+  /// The report of the run, for run events.
+  pub report: Option<Report>,
+}
+
+impl Notification {
+  pub fn new(event: NotifyEvent, recipe: Option<&str>, status: &str, error: Option<&str>) -> Self {
+    Notification {
+      event,
+      timestamp: SystemTime::now().duration_since(UNIX_EPOCH).map(|v| v.as_secs()).unwrap_or(0),
+      recipe: recipe.map(str::to_string),
+      status: status.to_string(),
+      error: error.map(str::to_string),
+      facts: Facts::gather(),
+      report: None,
+    }
+  }
+
+  /// The notification for `event`, if it is one that can be notified of.
+  pub fn for_event(event: &Event) -> Option<Self> {
+    match event {
+      Event::RecipeFinish { recipe, .. } => {
+        Some(Self::new(NotifyEvent::RecipeSuccess, Some(recipe), "completed", None))
+      }
+      Event::RecipeFail { recipe, error, .. } => Some(Self::new(
+        NotifyEvent::RecipeFailure,
+        Some(recipe),
+        "failed",
+        Some(error),
+      )),
+      Event::RunFinish { report } => {
+        let (event, status) = match report.status {
+          RunStatus::Success => (NotifyEvent::RunSuccess, "success"),
+          RunStatus::Failed => (NotifyEvent::RunFailure, "failed"),
+          RunStatus::Interrupted => (NotifyEvent::RunFailure, "interrupted"),
+        };
+        Some(Notification {
+          report: Some(report.clone()),
+          ..Self::new(event, None, status, report.error.as_deref())
+        })
+      }
+      _ => None,
+    }
+  }
+}
+
+/// POST `notification` to every target that wants it, retrying each one with a growing backoff.
+pub async fn send(targets: &[Notify], notification: &Notification) {
+  let requests = targets.iter().filter(|v| v.wants(notification.event)).map(|target| async move {
+    let attempts = target.attempts.unwrap_or(DEFAULT_ATTEMPTS).max(1);
+    for attempt in 1..=attempts {
+      match post(target, notification).await {
+        Ok(()) => {
+          log::debug!("Sent {:?} notification to {}", notification.event, target.url);
+          return;
+        }
+        Err(e) if attempt < attempts => {
+          let backoff = Duration::from_secs(1 << (attempt - 1));
+          log::warn!(
+            "Failed to notify {} (attempt {attempt}/{attempts}), retrying in {backoff:?}: {e}",
+            target.url
+          );
+          tokio::time::sleep(backoff).await;
+        }
+        Err(e) => log::error!("Failed to notify {} after {attempts} attempt(s): {e}", target.url),
+      }
+    }
+  });
+  futures_util::future::join_all(requests).await;
+}
+
+async fn post(target: &Notify, notification: &Notification) -> anyhow::Result<()> {
+  let mut request = http_client().post(&target.url).timeout(REQUEST_TIMEOUT).header("content-type", "application/json");
+  for (name, value) in target.headers.iter().flatten() {
+    request = request.header(name, value);
+  }
+  request.body(serde_json::to_vec(notification)?).send().await?.error_for_status()?;
+  Ok(())
+}
+
+/// Sends the notifications of a run in the background, so that slow targets do not hold up the recipes. Clones share
+/// the notifications in flight.
+#[derive(Clone, Default)]
+pub struct Notifier {
+  /// Targets of the run's events.
+  targets: Vec<Notify>,
+  /// Targets of the events of each recipe, which are those of its globals: `overrides.notify`, or else
+  /// `global.notify`.
+  recipes: HashMap<String, Vec<Notify>>,
+  pending: Arc<Mutex<Vec<JoinHandle<()>>>>,
+}
+
+impl Notifier {
+  pub fn new(targets: Vec<Notify>, recipes: HashMap<String, Vec<Notify>>) -> Self {
+    Notifier {
+      targets,
+      recipes,
+      pending: Default::default(),
+    }
+  }
+
+  /// The notifier for a run of `state`.
+  pub fn for_state(state: &State) -> Self {
+    let recipes = state.states.iter().map(|(id, v)| (id.clone(), v.global.notify.clone().unwrap_or_default()));
+    Self::new(
+      state.config.global.as_ref().and_then(|v| v.notify.clone()).unwrap_or_default(),
+      recipes.collect(),
+    )
+  }
+
+  /// The targets of the events of `recipe`, or of the run's events without one.
+  fn targets_for(&self, recipe: Option<&str>) -> &[Notify] {
+    recipe.and_then(|v| self.recipes.get(v)).unwrap_or(&self.targets)
+  }
+
+  /// Start notifying of `event`, if a target wants it.
+  pub fn event(&self, event: &Event) {
+    let Some(notification) = Notification::for_event(event) else {
+      return;
+    };
+    let targets = self.targets_for(notification.recipe.as_deref());
+    if !targets.iter().any(|v| v.wants(notification.event)) {
+      return;
+    }
+    let targets = targets.to_vec();
+    self.pending.lock().unwrap().push(tokio::spawn(async move { send(&targets, &notification).await }));
+  }
+
+  /// Wait for the notifications started so far.
+  pub async fn finish(&self) {
+    let pending = std::mem::take(&mut *self.pending.lock().unwrap());
+    for handle in pending {
+      _ = handle.await;
+    }
+  }
+
+  /// Wait for the notifications started so far, then notify of the reboot `recipe` is about to do.
+  pub async fn reboot(&self, recipe: &str) {
+    self.finish().await;
+    let notification = Notification::new(NotifyEvent::Reboot, Some(recipe), "rebooting", None);
+    send(self.targets_for(Some(recipe)), &notification).await;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::utils::test_http::serve;
+
+  #[tokio::test]
+  async fn notify_with_retries() {
+    let (base, requests) = serve(vec![(500, ""), (200, "")]).await;
+    let target = Notify {
+      url: format!("{base}/hook"),
+      headers: Some(HashMap::from([(
+        "Authorization".to_string(),
+        "Bearer secret".to_string(),
+      )])),
+      events: Some(vec![NotifyEvent::RecipeFailure]),
+      attempts: Some(2),
+    };
+    assert!(!target.wants(NotifyEvent::RunSuccess));
+
+    let notifier = Notifier::new(vec![target], HashMap::new());
+    notifier.event(&Event::RecipeFinish {
+      recipe: "deploy".to_string(),
+      plugin: "system_deployer".to_string(),
+      duration_ms: 10,
+      attempts: 1,
+    });
+    notifier.event(&Event::RecipeFail {
+      recipe: "sysconf".to_string(),
+      plugin: "system_reconfigurator".to_string(),
+      duration_ms: 10,
+      attempts: 1,
+      error: "exit status 1".to_string(),
+    });
+    notifier.finish().await;
+
+    let requests = requests.lock().unwrap();
+    assert_eq!(requests.len(), 2);
+    assert!(requests.iter().all(|v| v.head.contains("authorization: Bearer secret")));
+    let notification: Notification = serde_json::from_str(&requests[1].body).unwrap();
+    assert_eq!(notification.event, NotifyEvent::RecipeFailure);
+    assert_eq!(notification.recipe.as_deref(), Some("sysconf"));
+    assert_eq!(notification.status, "failed");
+    assert_eq!(notification.error.as_deref(), Some("exit status 1"));
+  }
+
+  #[tokio::test]
+  async fn notify_reboot_after_pending() {
+    let (base, requests) = serve(vec![(500, ""), (200, ""), (200, "")]).await;
+    let target = Notify {
+      url: format!("{base}/hook"),
+      headers: None,
+      events: Some(vec![NotifyEvent::RecipeSuccess, NotifyEvent::Reboot]),
+      attempts: Some(2),
+    };
+    // Recipes notify their own targets; the run has none.
+    let notifier = Notifier::new(
+      vec![],
+      HashMap::from([("deploy".to_string(), vec![target.clone()]), ("kexec".to_string(), vec![target])]),
+    );
+    notifier.event(&Event::RecipeFinish {
+      recipe: "deploy".to_string(),
+      plugin: "system_deployer".to_string(),
+      duration_ms: 10,
+      attempts: 1,
+    });
+    let before_reboot = crate::plugins::reboot::BeforeReboot {
+      recipe: "kexec".to_string(),
+      handoff: None,
+      notifier: notifier.clone(),
+    };
+    before_reboot.scope(crate::plugins::reboot::before_reboot()).await.unwrap();
+
+    // The reboot is notified of after the retry of the notification before it.
+    let requests = requests.lock().unwrap();
+    let notifications: Vec<Notification> = requests.iter().map(|v| serde_json::from_str(&v.body).unwrap()).collect();
+    let events: Vec<_> = notifications.iter().map(|v| (v.event, v.recipe.as_deref())).collect();
+    assert_eq!(
+      events,
+      vec![
+        (NotifyEvent::RecipeSuccess, Some("deploy")),
+        (NotifyEvent::RecipeSuccess, Some("deploy")),
+        (NotifyEvent::Reboot, Some("kexec")),
+      ]
+    );
+  }
+}
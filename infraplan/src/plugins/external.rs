@@ -0,0 +1,321 @@
+//! Plugins that live outside of infraplan, as executables in a plugin directory.
+//!
+//! The executable is started with a JSON object on its standard input:
+//!
+//! ```json
+//! { "globals": { "distro_hint": "ubuntu" }, "config": { ... }, "state": null }
+//! ```
+//!
+//! `config` is the recipe's `with.config`, after variable substitution, and `state` is the last state the plugin
+//! reported, or `null` on the first run. `globals` only carries `distro_hint`: the `notify` targets, whose headers
+//! often hold credentials, are not shared with plugins. The plugin writes one JSON object per line to its standard
+//! output:
+//!
+//! ```json
+//! { "type": "log", "level": "info", "message": "Created 3 users" }
+//! { "type": "state", "state": { "created": ["alice", "bob", "carol"] } }
+//! ```
+//!
+//! The last `state` is persisted with the recipe and handed back on the next attempt. Exiting with 0 completes the
+//! recipe; any other exit code fails the attempt, which is then retried according to the recipe's `retry`. Lines that
+//! are not JSON are logged as they are.
+//!
+//! Programs embedding infraplan can also `register` plugins that run in the process. A registered plugin takes
+//! precedence over an executable with the same name, and gets `config` and `state` as its own types.
+
+use std::{
+  collections::BTreeMap,
+  path::Path,
+  sync::{Arc, Mutex},
+};
+
+use futures_util::future::LocalBoxFuture;
+use serde::{Serialize, de::DeserializeOwned};
+
+use crate::{
+  plugins::{Distro, Globals, Plugin},
+  utils::process::run_command_with_input,
+};
+
+const DEFAULT_PLUGIN_DIR: &str = "/usr/lib/infraplan/plugins";
+
+static REGISTERED: Mutex<BTreeMap<String, Arc<dyn Registered>>> = Mutex::new(BTreeMap::new());
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize, schemars::JsonSchema)]
+pub struct Config {
+  /// File name of the executable in `plugin_dir`.
+  pub plugin: String,
+  /// Directory the plugin is looked up in. Defaults to `/usr/lib/infraplan/plugins`.
+  pub plugin_dir: Option<String>,
+  /// Passed to the plugin as `config`.
+  #[serde(default)]
+  pub config: serde_json::Value,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct State {
+  pub done: bool,
+  /// The last state reported by the plugin.
+  pub state: serde_json::Value,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct Request<'a> {
+  globals: RequestGlobals<'a>,
+  config: &'a serde_json::Value,
+  state: &'a serde_json::Value,
+}
+
+/// The part of `Globals` plugins receive.
+#[derive(Debug, serde::Serialize)]
+struct RequestGlobals<'a> {
+  distro_hint: &'a Option<Distro>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Message {
+  Log {
+    #[serde(default = "default_level")]
+    level: String,
+    message: String,
+  },
+  State {
+    state: serde_json::Value,
+  },
+}
+
+fn default_level() -> String { "info".to_string() }
+
+pub struct Context(pub Globals);
+
+/// A `Plugin` with its config and state as JSON, so that plugins of any type can be registered.
+trait Registered: Send + Sync {
+  fn invoke<'a>(
+    &'a self, config: &'a serde_json::Value, state: &'a mut serde_json::Value,
+  ) -> LocalBoxFuture<'a, anyhow::Result<()>>;
+
+  fn plan(&self, config: &serde_json::Value, state: &serde_json::Value) -> anyhow::Result<Vec<String>>;
+
+  fn check<'a>(
+    &'a self, config: &'a serde_json::Value, state: &'a mut serde_json::Value,
+  ) -> LocalBoxFuture<'a, anyhow::Result<Option<Vec<String>>>>;
+}
+
+/// Adapts a `Plugin` to `Registered`.
+struct Typed<P>(P);
+
+impl<P> Registered for Typed<P>
+where
+  P: Plugin + Send + Sync,
+  P::Config: DeserializeOwned,
+  P::State: Serialize + DeserializeOwned + Default,
+{
+  fn invoke<'a>(
+    &'a self, config: &'a serde_json::Value, state: &'a mut serde_json::Value,
+  ) -> LocalBoxFuture<'a, anyhow::Result<()>> {
+    Box::pin(async move {
+      let config: P::Config = serde_json::from_value(config.clone())?;
+      let mut typed = typed_state::<P>(state)?;
+      let result = self.0.invoke(&config, &mut typed).await;
+      // Keep the progress of a failed attempt too, for the next one.
+      *state = serde_json::to_value(&typed)?;
+      result
+    })
+  }
+
+  fn plan(&self, config: &serde_json::Value, state: &serde_json::Value) -> anyhow::Result<Vec<String>> {
+    let config: P::Config = serde_json::from_value(config.clone())?;
+    self.0.plan(&config, &typed_state::<P>(state)?)
+  }
+
+  fn check<'a>(
+    &'a self, config: &'a serde_json::Value, state: &'a mut serde_json::Value,
+  ) -> LocalBoxFuture<'a, anyhow::Result<Option<Vec<String>>>> {
+    Box::pin(async move {
+      let config: P::Config = serde_json::from_value(config.clone())?;
+      let mut typed = typed_state::<P>(state)?;
+      let drift = self.0.check(&config, &mut typed).await?;
+      *state = serde_json::to_value(&typed)?;
+      Ok(drift)
+    })
+  }
+}
+
+/// The state `P` reported last, or its default on the first run.
+fn typed_state<P: Plugin>(state: &serde_json::Value) -> anyhow::Result<P::State>
+where P::State: DeserializeOwned + Default {
+  if state.is_null() {
+    return Ok(Default::default());
+  }
+  Ok(serde_json::from_value(state.clone())?)
+}
+
+/// Run `plugin` in the process for the external recipes with `plugin: <name>`, for the rest of the process.
+pub fn register<P>(name: &str, plugin: P)
+where
+  P: Plugin + Send + Sync + 'static,
+  P::Config: DeserializeOwned,
+  P::State: Serialize + DeserializeOwned + Default,
+{
+  log::debug!("Registered plugin {name}");
+  REGISTERED.lock().unwrap().insert(name.to_string(), Arc::new(Typed(plugin)));
+}
+
+pub fn is_registered(name: &str) -> bool { REGISTERED.lock().unwrap().contains_key(name) }
+
+fn registered(name: &str) -> Option<Arc<dyn Registered>> { REGISTERED.lock().unwrap().get(name).cloned() }
+
+impl Config {
+  pub fn path(&self) -> String {
+    let dir = self.plugin_dir.as_deref().unwrap_or(DEFAULT_PLUGIN_DIR);
+    Path::new(dir).join(&self.plugin).to_string_lossy().to_string()
+  }
+}
+
+impl Plugin for Context {
+  type Config = Config;
+  type State = State;
+
+  async fn invoke(&self, config: &Self::Config, state: &mut Self::State) -> anyhow::Result<()> {
+    if state.done {
+      log::info!("Plugin {} has already been run.", config.plugin);
+      return Ok(());
+    }
+
+    if let Some(plugin) = registered(&config.plugin) {
+      plugin.invoke(&config.config, &mut state.state).await?;
+      state.done = true;
+      return Ok(());
+    }
+    let path = config.path();
+    if !Path::new(&path).is_file() {
+      anyhow::bail!("Plugin {} not found at {path}", config.plugin);
+    }
+    let request = serde_json::to_string(&Request {
+      globals: RequestGlobals {
+        distro_hint: &self.0.distro_hint,
+      },
+      config: &config.config,
+      state: &state.state,
+    })?;
+    let (code, stdout, stderr) = run_command_with_input(&path, &[], request).await?;
+
+    for line in stdout.lines().filter(|v| !v.trim().is_empty()) {
+      match serde_json::from_str::<Message>(line) {
+        Ok(Message::Log { level, message }) => match level.as_str() {
+          "error" => log::error!("{}: {message}", config.plugin),
+          "warn" | "warning" => log::warn!("{}: {message}", config.plugin),
+          "debug" => log::debug!("{}: {message}", config.plugin),
+          "trace" => log::trace!("{}: {message}", config.plugin),
+          _ => log::info!("{}: {message}", config.plugin),
+        },
+        Ok(Message::State { state: new_state }) => state.state = new_state,
+        Err(_) => log::info!("{}: {line}", config.plugin),
+      }
+    }
+
+    if code != 0 {
+      anyhow::bail!("Plugin {} exited with {code}: {}", config.plugin, stderr.trim());
+    }
+    state.done = true;
+    Ok(())
+  }
+
+  fn plan(&self, config: &Self::Config, state: &Self::State) -> anyhow::Result<Vec<String>> {
+    if state.done {
+      return Ok(vec![format!(
+        "Plugin {} has already been run, nothing to do",
+        config.plugin
+      )]);
+    }
+    if let Some(plugin) = registered(&config.plugin) {
+      return plugin.plan(&config.config, &state.state);
+    }
+    Ok(vec![format!("Run plugin {} from {}", config.plugin, config.path())])
+  }
+
+  /// Only registered plugins can be checked for drift: the protocol of executables has no check.
+  async fn check(&self, config: &Self::Config, state: &mut Self::State) -> anyhow::Result<Option<Vec<String>>> {
+    let Some(plugin) = registered(&config.plugin) else {
+      return Ok(None);
+    };
+    let drift = plugin.check(&config.config, &mut state.state).await?;
+    if drift.as_ref().is_some_and(|v| !v.is_empty()) {
+      state.done = false;
+    }
+    Ok(drift)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{collections::HashMap, os::unix::fs::PermissionsExt};
+
+  use super::*;
+  use crate::{notify::Notify, plugins::Plugin};
+
+  #[tokio::test]
+  async fn run_external_plugin() {
+    let dir = std::env::temp_dir().join(format!("infraplan-external-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let script = dir.join("echo");
+    std::fs::write(
+      &script,
+      r#"#!/bin/sh
+request=$(cat)
+echo '{"type": "log", "level": "warn", "message": "hello"}'
+echo 'not json'
+printf '{"type": "state", "state": %s}\n' "$request"
+case "$request" in *'"fail":true'*) echo 'failed on purpose' >&2; exit 1;; esac
+"#,
+    )
+    .unwrap();
+    std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    let context = Context(Globals {
+      distro_hint: Some(Distro::Ubuntu),
+      notify: Some(vec![Notify {
+        url: "https://hooks.example.com/infraplan".to_string(),
+        headers: Some(HashMap::from([(
+          "Authorization".to_string(),
+          "Bearer secret".to_string(),
+        )])),
+        events: None,
+        attempts: None,
+      }]),
+    });
+    let mut config = Config {
+      plugin: "echo".to_string(),
+      plugin_dir: Some(dir.to_string_lossy().to_string()),
+      config: serde_json::json!({ "users": ["alice"] }),
+    };
+    let mut state = State::default();
+    context.invoke(&config, &mut state).await.unwrap();
+    assert!(state.done);
+    // The notify targets stay with infraplan.
+    assert_eq!(
+      state.state,
+      serde_json::json!({
+        "globals": { "distro_hint": "ubuntu" },
+        "config": { "users": ["alice"] },
+        "state": null,
+      })
+    );
+
+    // A failed attempt keeps the state it reported, and the next attempt receives it.
+    config.config = serde_json::json!({ "fail": true });
+    let mut state = State {
+      done: false,
+      state: serde_json::json!({ "step": 1 }),
+    };
+    let result = context.invoke(&config, &mut state).await;
+    assert!(result.unwrap_err().to_string().contains("failed on purpose"));
+    assert!(!state.done);
+    assert_eq!(state.state["state"], serde_json::json!({ "step": 1 }));
+
+    config.plugin = "missing".to_string();
+    assert!(context.invoke(&config, &mut State::default()).await.is_err());
+    std::fs::remove_dir_all(&dir).unwrap();
+  }
+}
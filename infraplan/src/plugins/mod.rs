@@ -1,25 +1,69 @@
 #![allow(async_fn_in_trait)]
 
-use std::{collections::HashMap, path::Path};
+use std::{
+  cell::LazyCell,
+  collections::{HashMap, HashSet},
+  path::Path,
+  time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
+use futures_util::{StreamExt, stream::FuturesUnordered};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+  control::Control,
+  events::{Event, EventSink, NoEvents, Report},
+  exit::{config_error, recipe_error},
+  facts::{Facts, OsRelease, read_os_release},
+  include,
+  notify::{self, Notifier},
+  select::Selection,
+  store::StateStore,
+  utils::{
+    cancel,
+    expr::{self, Expr},
+    lock, metrics, parse_duration,
+    template::interpolate_value,
+    write_file_atomic, write_file_atomic_with_mode,
+  },
+};
+
+/// How long a cancelled run waits for running recipes to notice before abandoning them.
+const CANCEL_GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+pub mod external;
 pub mod pkgmgr;
 pub mod reboot;
+pub mod shell;
 pub mod sys_deploy;
 pub mod sysconf;
 
-#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize, schemars::JsonSchema)]
 pub struct Config {
-  // pub state_path: Option<String>, // TODO: implement persistent states
+  /// Files merged in before this one, relative to it. They are resolved while loading, see `include`.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub include: Option<Vec<String>>,
+  pub state_path: Option<String>,
+  /// Where to write Prometheus metrics at the end of every run, for node_exporter's textfile collector, e.g.
+  /// `/var/lib/node_exporter/textfile_collector/infraplan.prom`.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub metrics_path: Option<String>,
+  /// Values for `${name}` references in recipe configs. See `Config::resolve_vars`.
+  pub vars: Option<HashMap<String, String>>,
   pub global: Option<Globals>,
   pub recipe: Vec<RecipeConfig>,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize, schemars::JsonSchema)]
 pub struct Globals {
+  /// Distro the recipes work on. Detected from `os-release` for each recipe when unset, see `Globals::distro_for`.
   pub distro_hint: Option<Distro>,
+  /// Webhooks notified of the run's progress, see `notify`. Saved apart from the state, see `notify::Targets`.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub notify: Option<Vec<notify::Notify>>,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize, schemars::JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub enum Distro {
   Ubuntu,
@@ -29,11 +73,23 @@ pub enum Distro {
   Alpine,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize, schemars::JsonSchema)]
 pub struct RecipeConfig {
   pub id: String,
   pub name: Option<String>,
   pub overrides: Option<Globals>,
+  /// Recipes that must finish before this one starts. Without it, a recipe waits for the one listed before it, so
+  /// recipes run in order; list only the recipes it needs, or `[]`, to let it run alongside others. Reboot recipes
+  /// always wait for every earlier recipe, and every later recipe waits for them.
+  pub depends_on: Option<Vec<String>>,
+  /// Condition over `global.*` and `facts.*`; the recipe is skipped when it is false. See `utils::expr`.
+  pub when: Option<String>,
+  /// Retry the recipe when it fails.
+  pub retry: Option<RetryPolicy>,
+  /// Cancel an attempt that takes longer than this, e.g. `10m`, killing the commands it started.
+  pub timeout: Option<String>,
+  /// Labels for selecting recipes with `--tags` and `--skip-tags`.
+  pub tags: Option<Vec<String>>,
 
   #[serde(flatten)]
   pub config: PluginConfig,
@@ -53,15 +109,45 @@ pub struct RecipeState {
   pub global: Globals,
   pub config: PluginConfig,
   pub state: PluginState,
+  #[serde(default)]
+  pub when: Option<String>,
+  /// Why the recipe was skipped in the last run, if it was.
+  #[serde(default)]
+  pub skipped: Option<String>,
+  #[serde(default)]
+  pub retry: Option<RetryPolicy>,
+  #[serde(default)]
+  pub timeout: Option<String>,
+  /// Attempts made in the last run, in order.
+  #[serde(default)]
+  pub attempts: Vec<Attempt>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize, schemars::JsonSchema)]
+pub struct RetryPolicy {
+  /// Total number of attempts, including the first one.
+  pub attempts: u32,
+  /// Delay before the second attempt, doubled after each further failure, e.g. `5s`. Defaults to no delay.
+  pub backoff: Option<String>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Attempt {
+  /// Seconds since the Unix epoch.
+  pub started_at: u64,
+  pub duration_ms: u64,
+  pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize, schemars::JsonSchema)]
 #[serde(rename_all = "snake_case", tag = "use", content = "with")]
 pub enum PluginConfig {
   SystemDeployer(<sys_deploy::Context as Plugin>::Config),
   PackageManager(<pkgmgr::Context as Plugin>::Config),
   Reboot(<reboot::Context as Plugin>::Config),
   SystemReconfigurator(<sysconf::Context as Plugin>::Config),
+  Shell(<shell::Context as Plugin>::Config),
+  External(<external::Context as Plugin>::Config),
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
@@ -71,6 +157,50 @@ pub enum PluginState {
   PackageManager(<pkgmgr::Context as Plugin>::State),
   Reboot(<reboot::Context as Plugin>::State),
   SystemReconfigurator(<sysconf::Context as Plugin>::State),
+  Shell(<shell::Context as Plugin>::State),
+  External(<external::Context as Plugin>::State),
+}
+
+impl Distro {
+  /// The distro an `os-release` describes, by its `ID` or else by the first of its `ID_LIKE` that is known.
+  pub fn from_os_release(os: &OsRelease) -> Option<Self> {
+    os.id.iter().chain(&os.id_like).find_map(|id| match id.as_str() {
+      "ubuntu" => Some(Distro::Ubuntu),
+      "debian" => Some(Distro::Debian),
+      "arch" | "archlinux" => Some(Distro::Arch),
+      "fedora" => Some(Distro::Fedora),
+      "alpine" => Some(Distro::Alpine),
+      _ => None,
+    })
+  }
+
+  /// Detect the distro installed in `root` from its `os-release`.
+  pub fn detect<P: AsRef<Path>>(root: P) -> Option<Self> {
+    read_os_release(root).as_ref().and_then(Self::from_os_release)
+  }
+}
+
+impl Globals {
+  /// The explicit `distro_hint`, or else the distro detected in `root`.
+  pub fn distro_for(&self, root: &str) -> Option<Distro> {
+    if let Some(distro) = &self.distro_hint {
+      return Some(distro.clone());
+    }
+    let detected = Distro::detect(root);
+    match &detected {
+      Some(distro) => log::info!("Detected distro {distro:?} in {root}"),
+      None => log::warn!("No `distro_hint` set and no known distro detected in {root}"),
+    }
+    detected
+  }
+
+  /// These globals with `distro_hint` filled in from `root` if it is unset.
+  pub fn with_distro_for(&self, root: &str) -> Self {
+    Globals {
+      distro_hint: self.distro_for(root),
+      ..self.clone()
+    }
+  }
 }
 
 impl Config {
@@ -83,14 +213,123 @@ impl Config {
   pub fn from_yaml(yaml: &str) -> anyhow::Result<Self> { serde_yml::from_str(yaml).map_err(|e| anyhow::anyhow!(e)) }
 
   pub fn from_path<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+    let (config, ignored) = Self::load(path)?;
+    for field in ignored {
+      log::warn!("Ignoring unknown configuration field: {field}");
+    }
+    Ok(config)
+  }
+
+  /// Load the configuration at `path` with its includes merged in, also returning the paths of fields that are not
+  /// part of the format.
+  pub fn load<P: AsRef<Path>>(path: P) -> anyhow::Result<(Self, Vec<String>)> {
     log::info!("Loading configuration from: {}", path.as_ref().display());
-    let content = std::fs::read_to_string(path.as_ref()).map_err(|e| anyhow::anyhow!(e))?;
-    let ext_name = path.as_ref().extension().and_then(|s| s.to_str()).unwrap_or("");
-    match ext_name {
-      "json" => Self::from_json(&content),
-      "yaml" | "yml" => Self::from_yaml(&content),
-      _ => anyhow::bail!("Unsupported file format: {}", path.as_ref().display()),
+    let raw = include::load_document(path.as_ref()).map_err(config_error)?;
+    Self::from_document(raw, &path.as_ref().to_string_lossy())
+  }
+
+  /// Parse a document that has its includes merged in already, also returning the paths of fields that are not part
+  /// of the format. `origin` names where it came from, for errors.
+  pub fn from_document(raw: serde_json::Value, origin: &str) -> anyhow::Result<(Self, Vec<String>)> {
+    let config: Self = serde_json::from_value(raw.clone())
+      .map_err(|e| config_error(anyhow::anyhow!("Invalid configuration in {origin}: {e}")))?;
+
+    let mut ignored = Vec::new();
+    collect_unknown_fields(&raw, &serde_json::to_value(&config)?, "", &mut ignored);
+    Ok((config, ignored))
+  }
+
+  /// Substitute `${name}` references in every string of the recipe configs, except in the fields that hold shell code
+  /// (see `PluginConfig::shell_fields`), where `${...}` is left to the shell. A name is looked up in `set` first, then
+  /// in `vars`, then in the environment; `${facts.path}` refers to the facts of the running host instead. Must run
+  /// before `into_state` so the state holds the concrete values.
+  pub fn resolve_vars(&mut self, set: &HashMap<String, String>) -> anyhow::Result<()> {
+    let vars = self.vars.clone().unwrap_or_default();
+    let facts = LazyCell::new(|| serde_json::to_value(Facts::gather()).unwrap_or_default());
+    let lookup = |name: &str| {
+      if let Some(path) = name.strip_prefix("facts.") {
+        let path: Vec<String> = path.split('.').map(str::to_string).collect();
+        return match expr::lookup(&facts, &path) {
+          serde_json::Value::Null => None,
+          serde_json::Value::String(v) => Some(v),
+          v => Some(v.to_string()),
+        };
+      }
+      set.get(name).cloned().or_else(|| vars.get(name).cloned()).or_else(|| std::env::var(name).ok())
+    };
+    for recipe in &mut self.recipe {
+      let mut value = serde_json::to_value(&recipe.config)?;
+      let with = value.get_mut("with").and_then(|v| v.as_object_mut());
+      let shell_code: Vec<_> = match with {
+        Some(with) => recipe.config.shell_fields().iter().filter_map(|&f| Some((f, with.remove(f)?))).collect(),
+        None => Vec::new(),
+      };
+      interpolate_value(&mut value, &lookup)
+        .map_err(|e| config_error(anyhow::anyhow!("Recipe '{}': {e}", recipe.id)))?;
+      if let Some(with) = value.get_mut("with").and_then(|v| v.as_object_mut()) {
+        with.extend(shell_code.into_iter().map(|(f, v)| (f.to_string(), v)));
+      }
+      recipe.config = serde_json::from_value(value)?;
+    }
+    Ok(())
+  }
+
+  pub fn json_schema() -> anyhow::Result<String> {
+    serde_json::to_string_pretty(&schemars::schema_for!(Config)).map_err(|e| anyhow::anyhow!(e))
+  }
+
+  /// Resolve the recipes each recipe waits for. A recipe without `depends_on` waits for the one listed before it. A
+  /// reboot recipe is a barrier: it waits for every earlier recipe, and every later recipe waits for it.
+  pub fn dependencies(&self) -> anyhow::Result<HashMap<String, Vec<String>>> {
+    let ids: HashSet<&str> = self.recipe.iter().map(|r| r.id.as_str()).collect();
+    if ids.len() != self.recipe.len() {
+      anyhow::bail!("Recipe ids must be unique");
+    }
+    let mut dependencies = HashMap::new();
+    let mut barrier: Option<&str> = None;
+    for (i, recipe) in self.recipe.iter().enumerate() {
+      let mut deps = if matches!(recipe.config, PluginConfig::Reboot(_)) {
+        self.recipe[..i].iter().map(|r| r.id.clone()).collect()
+      } else {
+        match &recipe.depends_on {
+          Some(deps) => deps.clone(),
+          None => self.recipe[..i].last().map(|r| vec![r.id.clone()]).unwrap_or_default(),
+        }
+      };
+      if let Some(barrier) = barrier.filter(|b| !deps.iter().any(|d| d == b)) {
+        deps.push(barrier.to_string());
+      }
+      if matches!(recipe.config, PluginConfig::Reboot(_)) {
+        barrier = Some(recipe.id.as_str());
+      }
+
+      for dep in &deps {
+        if !ids.contains(dep.as_str()) {
+          anyhow::bail!("Recipe '{}' depends on unknown recipe '{dep}'", recipe.id);
+        }
+        if dep == &recipe.id {
+          anyhow::bail!("Recipe '{}' depends on itself", recipe.id);
+        }
+      }
+      dependencies.insert(recipe.id.clone(), deps);
+    }
+
+    let mut resolved: HashSet<&str> = HashSet::new();
+    while resolved.len() < dependencies.len() {
+      let ready: Vec<&str> = dependencies
+        .iter()
+        .filter(|(id, deps)| !resolved.contains(id.as_str()) && deps.iter().all(|d| resolved.contains(d.as_str())))
+        .map(|(id, _)| id.as_str())
+        .collect();
+      if ready.is_empty() {
+        let mut cycle: Vec<&str> =
+          dependencies.keys().map(|id| id.as_str()).filter(|id| !resolved.contains(id)).collect();
+        cycle.sort();
+        anyhow::bail!("Dependency cycle between recipes: {}", cycle.join(", "));
+      }
+      resolved.extend(ready);
     }
+    Ok(dependencies)
   }
 
   pub fn into_state(self) -> State {
@@ -109,31 +348,56 @@ impl Config {
   }
 }
 
+/// Collect the paths of fields present in `raw` but not in `known`, the re-serialized form of what was parsed.
+fn collect_unknown_fields(raw: &serde_json::Value, known: &serde_json::Value, path: &str, out: &mut Vec<String>) {
+  match (raw, known) {
+    (serde_json::Value::Object(raw), serde_json::Value::Object(known)) => {
+      for (key, value) in raw {
+        let path = if path.is_empty() {
+          key.clone()
+        } else {
+          format!("{path}.{key}")
+        };
+        match known.get(key) {
+          Some(known) => collect_unknown_fields(value, known, &path, out),
+          None => out.push(path),
+        }
+      }
+    }
+    (serde_json::Value::Array(raw), serde_json::Value::Array(known)) => {
+      for (i, (value, known)) in raw.iter().zip(known).enumerate() {
+        collect_unknown_fields(value, known, &format!("{path}[{i}]"), out);
+      }
+    }
+    _ => {}
+  }
+}
+
 impl RecipeConfig {
   pub fn into_state(&self, global: &Option<Globals>) -> RecipeState {
     let global = match (global, &self.overrides) {
       (Some(global), Some(overrides)) => Globals {
         distro_hint: overrides.distro_hint.as_ref().or(global.distro_hint.as_ref()).cloned(),
+        notify: overrides.notify.as_ref().or(global.notify.as_ref()).cloned(),
       },
       (Some(global), None) => global.clone(),
       (None, Some(overrides)) => overrides.clone(),
-      (None, None) => Globals { distro_hint: None },
+      (None, None) => Globals {
+        distro_hint: None,
+        notify: None,
+      },
     };
     RecipeState {
       id: self.id.clone(),
       display_name: self.name.clone().unwrap_or_else(|| self.id.clone()),
       global,
       config: self.config.clone(),
-      state: match &self.config {
-        PluginConfig::SystemDeployer(_) => {
-          PluginState::SystemDeployer(<sys_deploy::Context as Plugin>::State::default())
-        }
-        PluginConfig::PackageManager(_) => PluginState::PackageManager(<pkgmgr::Context as Plugin>::State::default()),
-        PluginConfig::Reboot(_) => PluginState::Reboot(<reboot::Context as Plugin>::State::default()),
-        PluginConfig::SystemReconfigurator(_) => {
-          PluginState::SystemReconfigurator(<sysconf::Context as Plugin>::State::default())
-        }
-      },
+      when: self.when.clone(),
+      skipped: None,
+      retry: self.retry.clone(),
+      timeout: self.timeout.clone(),
+      attempts: Vec::new(),
+      state: self.config.default_state(),
     }
   }
 }
@@ -143,6 +407,88 @@ pub trait Plugin {
   type State;
 
   async fn invoke(&self, config: &Self::Config, state: &mut Self::State) -> anyhow::Result<()>;
+
+  /// Describe the actions `invoke` would take, without touching the machine.
+  fn plan(&self, config: &Self::Config, state: &Self::State) -> anyhow::Result<Vec<String>>;
+
+  /// Compare the machine with `config` and describe how it drifted, e.g. a deleted user. The parts of `state` that
+  /// drifted are marked as not done, so that the next `invoke` applies them again. `None` if the plugin cannot tell.
+  async fn check(&self, _config: &Self::Config, _state: &mut Self::State) -> anyhow::Result<Option<Vec<String>>> {
+    Ok(None)
+  }
+}
+
+impl PluginConfig {
+  /// The root the recipe works on, which the distro is detected from when there is no `distro_hint`. `None` for a
+  /// system deployer, which detects the deployed distro itself once the root is extracted.
+  pub fn root(&self) -> Option<&str> {
+    match self {
+      PluginConfig::SystemDeployer(_) => None,
+      PluginConfig::SystemReconfigurator(sysconf::Config { chroot: Some(root), .. }) |
+      PluginConfig::Shell(shell::Config { chroot: Some(root), .. }) => Some(root),
+      _ => Some("/"),
+    }
+  }
+
+  /// `global` with the distro detected for this recipe filled in, see `root`.
+  fn globals_for(&self, global: &Globals) -> Globals {
+    match self.root() {
+      Some(root) => global.with_distro_for(root),
+      None => global.clone(),
+    }
+  }
+
+  /// The state of a recipe that has not run yet.
+  pub fn default_state(&self) -> PluginState {
+    match self {
+      PluginConfig::SystemDeployer(_) => PluginState::SystemDeployer(<sys_deploy::Context as Plugin>::State::default()),
+      PluginConfig::PackageManager(_) => PluginState::PackageManager(<pkgmgr::Context as Plugin>::State::default()),
+      PluginConfig::Reboot(_) => PluginState::Reboot(<reboot::Context as Plugin>::State::default()),
+      PluginConfig::SystemReconfigurator(_) => {
+        PluginState::SystemReconfigurator(<sysconf::Context as Plugin>::State::default())
+      }
+      PluginConfig::Shell(_) => PluginState::Shell(<shell::Context as Plugin>::State::default()),
+      PluginConfig::External(_) => PluginState::External(<external::Context as Plugin>::State::default()),
+    }
+  }
+
+  /// The state of a recipe that has run to completion.
+  pub fn completed_state(&self) -> PluginState {
+    match self {
+      PluginConfig::SystemDeployer(_) => PluginState::SystemDeployer(true),
+      PluginConfig::PackageManager(_) => PluginState::PackageManager(true),
+      PluginConfig::Reboot(_) => PluginState::Reboot(true),
+      PluginConfig::SystemReconfigurator(config) => PluginState::SystemReconfigurator(vec![true; config.with.len()]),
+      PluginConfig::Shell(_) => PluginState::Shell(shell::State {
+        done: true,
+        ..Default::default()
+      }),
+      PluginConfig::External(_) => PluginState::External(external::State {
+        done: true,
+        ..Default::default()
+      }),
+    }
+  }
+
+  /// The name used for the plugin in `use`.
+  pub fn name(&self) -> &'static str {
+    match self {
+      PluginConfig::SystemDeployer(_) => "system_deployer",
+      PluginConfig::PackageManager(_) => "package_manager",
+      PluginConfig::Reboot(_) => "reboot",
+      PluginConfig::SystemReconfigurator(_) => "system_reconfigurator",
+      PluginConfig::Shell(_) => "shell",
+      PluginConfig::External(_) => "external",
+    }
+  }
+
+  /// Fields that hold shell code, which `Config::resolve_vars` leaves as they are.
+  pub fn shell_fields(&self) -> &'static [&'static str] {
+    match self {
+      PluginConfig::Shell(_) => shell::CODE_FIELDS,
+      _ => &[],
+    }
+  }
 }
 
 impl Plugin for Globals {
@@ -203,55 +549,662 @@ impl Plugin for Globals {
         };
         sysconf::Context(self.clone()).invoke(config, state_i).await
       }
+      PluginConfig::Shell(config) => {
+        let state_i = match state {
+          PluginState::Shell(s) => s,
+          _ => {
+            *state = PluginState::Shell(<shell::Context as Plugin>::State::default());
+            match state {
+              PluginState::Shell(s) => s,
+              _ => unreachable!("State should have been set to Shell"),
+            }
+          }
+        };
+        shell::Context(self.clone()).invoke(config, state_i).await
+      }
+      PluginConfig::External(config) => {
+        let state_i = match state {
+          PluginState::External(s) => s,
+          _ => {
+            *state = PluginState::External(<external::Context as Plugin>::State::default());
+            match state {
+              PluginState::External(s) => s,
+              _ => unreachable!("State should have been set to External"),
+            }
+          }
+        };
+        external::Context(self.clone()).invoke(config, state_i).await
+      }
+    }
+  }
+
+  fn plan(&self, config: &Self::Config, state: &Self::State) -> anyhow::Result<Vec<String>> {
+    match config {
+      PluginConfig::SystemDeployer(config) => {
+        let state = match state {
+          PluginState::SystemDeployer(s) => *s,
+          _ => Default::default(),
+        };
+        sys_deploy::Context(self.clone()).plan(config, &state)
+      }
+      PluginConfig::PackageManager(config) => {
+        let state = match state {
+          PluginState::PackageManager(s) => *s,
+          _ => Default::default(),
+        };
+        pkgmgr::Context(self.clone()).plan(config, &state)
+      }
+      PluginConfig::Reboot(config) => {
+        let state = match state {
+          PluginState::Reboot(s) => *s,
+          _ => Default::default(),
+        };
+        reboot::Context(self.clone()).plan(config, &state)
+      }
+      PluginConfig::SystemReconfigurator(config) => {
+        let state = match state {
+          PluginState::SystemReconfigurator(s) => s.clone(),
+          _ => Default::default(),
+        };
+        sysconf::Context(self.clone()).plan(config, &state)
+      }
+      PluginConfig::Shell(config) => {
+        let state = match state {
+          PluginState::Shell(s) => s.clone(),
+          _ => Default::default(),
+        };
+        shell::Context(self.clone()).plan(config, &state)
+      }
+      PluginConfig::External(config) => {
+        let state = match state {
+          PluginState::External(s) => s.clone(),
+          _ => Default::default(),
+        };
+        external::Context(self.clone()).plan(config, &state)
+      }
+    }
+  }
+
+  async fn check(&self, config: &Self::Config, state: &mut Self::State) -> anyhow::Result<Option<Vec<String>>> {
+    match (config, state) {
+      (PluginConfig::PackageManager(config), PluginState::PackageManager(state)) => {
+        pkgmgr::Context(self.clone()).check(config, state).await
+      }
+      (PluginConfig::SystemReconfigurator(config), PluginState::SystemReconfigurator(state)) => {
+        sysconf::Context(self.clone()).check(config, state).await
+      }
+      (PluginConfig::External(config), PluginState::External(state)) => {
+        external::Context(self.clone()).check(config, state).await
+      }
+      _ => Ok(None),
     }
   }
 }
 
 impl RecipeState {
   pub async fn invoke(&mut self) -> anyhow::Result<()> {
-    self.global.invoke(&self.config, &mut self.state).await.map_err(|e| anyhow::anyhow!(e))
+    let global = self.config.globals_for(&self.global);
+    global.invoke(&self.config, &mut self.state).await.map_err(|e| anyhow::anyhow!(e))
+  }
+
+  /// Check the machine for drift from the recipe, see `Plugin::check`.
+  pub async fn check(&mut self) -> anyhow::Result<Option<Vec<String>>> {
+    let global = self.config.globals_for(&self.global);
+    global.check(&self.config, &mut self.state).await
+  }
+
+  /// Invoke the recipe under its retry and timeout policies, recording every attempt in `attempts`. The recipe stops
+  /// when `token` is cancelled.
+  pub async fn run(&mut self, token: &CancellationToken) -> anyhow::Result<()> {
+    let attempts = self.retry.as_ref().map_or(1, |v| v.attempts.max(1));
+    let mut backoff = match self.retry.as_ref().and_then(|v| v.backoff.as_deref()) {
+      Some(backoff) => parse_duration(backoff)?,
+      None => Duration::ZERO,
+    };
+    let timeout = match self.timeout.as_deref() {
+      Some(timeout) => Some((timeout.to_string(), parse_duration(timeout)?)),
+      None => None,
+    };
+
+    self.attempts.clear();
+    let mut attempt = 1;
+    loop {
+      let started_at = SystemTime::now();
+      let result = match &timeout {
+        Some((text, duration)) => tokio::time::timeout(*duration, cancel::scope(token.clone(), self.invoke()))
+          .await
+          .unwrap_or_else(|_| Err(anyhow::anyhow!("Timed out after {text}"))),
+        None => cancel::scope(token.clone(), self.invoke()).await,
+      };
+      self.attempts.push(Attempt {
+        started_at: started_at.duration_since(UNIX_EPOCH).map(|v| v.as_secs()).unwrap_or(0),
+        duration_ms: started_at.elapsed().map(|v| v.as_millis() as u64).unwrap_or(0),
+        error: result.as_ref().err().map(|e| e.to_string()),
+      });
+
+      match result {
+        Ok(()) => return Ok(()),
+        Err(e) if attempt >= attempts || token.is_cancelled() => return Err(e),
+        Err(e) => {
+          log::warn!(
+            "Recipe '{}' failed on attempt {attempt}/{attempts}: {e}, retrying in {backoff:?}",
+            self.id
+          );
+          tokio::select! {
+            _ = tokio::time::sleep(backoff) => {}
+            _ = token.cancelled() => return Err(e),
+          }
+          backoff *= 2;
+        }
+      }
+      attempt += 1;
+    }
+  }
+
+  pub fn plan(&self) -> anyhow::Result<Vec<String>> {
+    self.config.globals_for(&self.global).plan(&self.config, &self.state)
+  }
+
+  /// Evaluate the `when` condition against the recipe's globals and `facts`. Recipes without one always run.
+  pub fn condition(&self, facts: &serde_json::Value) -> anyhow::Result<bool> {
+    let Some(when) = &self.when else {
+      return Ok(true);
+    };
+    let context = serde_json::json!({ "global": self.global, "facts": facts });
+    when
+      .parse::<Expr>()
+      .map_err(|e| anyhow::anyhow!("Invalid condition `{when}`: {e}"))?
+      .is_true(&context)
+  }
+
+  /// Forget what the recipe has done, so that it runs again from the start.
+  pub fn reset(&mut self) {
+    self.state = self.config.default_state();
+    self.skipped = None;
+    self.attempts.clear();
+  }
+
+  /// Record the recipe as completed without running it.
+  pub fn mark_done(&mut self) {
+    self.state = self.config.completed_state();
+    self.skipped = None;
+  }
+
+  pub fn is_completed(&self) -> bool {
+    match (&self.config, &self.state) {
+      (PluginConfig::SystemDeployer(_), PluginState::SystemDeployer(done)) |
+      (PluginConfig::PackageManager(_), PluginState::PackageManager(done)) |
+      (PluginConfig::Reboot(_), PluginState::Reboot(done)) => *done,
+      (PluginConfig::SystemReconfigurator(config), PluginState::SystemReconfigurator(done)) => {
+        done.len() == config.with.len() && done.iter().all(|v| *v)
+      }
+      (PluginConfig::Shell(_), PluginState::Shell(state)) => state.done,
+      (PluginConfig::External(_), PluginState::External(state)) => state.done,
+      _ => false,
+    }
   }
 }
 
 impl State {
-  pub async fn invoke(&mut self) -> anyhow::Result<()> {
+  /// Load the state saved at `path`, with the notify targets saved next to it, see `save`.
+  pub fn from_path<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+    log::info!("Loading state from: {}", path.as_ref().display());
+    let content = std::fs::read_to_string(path.as_ref()).map_err(|e| config_error(anyhow::anyhow!(e)))?;
+    let mut state: State = serde_json::from_str(&content).map_err(|e| config_error(anyhow::anyhow!(e)))?;
+    let targets_path = notify::Targets::path(path.as_ref());
+    if targets_path.exists() {
+      let content = std::fs::read_to_string(&targets_path).map_err(|e| config_error(anyhow::anyhow!(e)))?;
+      let targets = serde_json::from_str(&content).map_err(|e| {
+        config_error(anyhow::anyhow!(
+          "Invalid notify targets in {}: {e}",
+          targets_path.display()
+        ))
+      })?;
+      state.restore_notify(targets);
+    }
+    Ok(state)
+  }
+
+  /// Save the state to `path`. Its notify targets are saved apart, to a file only the owner can read, see
+  /// `notify::Targets`.
+  pub fn save<P: AsRef<Path>>(&self, path: P) -> anyhow::Result<()> {
+    let mut state = self.clone();
+    let targets = state.take_notify();
+    let targets_path = notify::Targets::path(path.as_ref());
+    if !targets.is_empty() {
+      write_file_atomic_with_mode(&targets_path, serde_json::to_string_pretty(&targets)?, 0o600)?;
+    } else if targets_path.exists() {
+      std::fs::remove_file(&targets_path)?;
+    }
+    write_file_atomic(path.as_ref(), serde_json::to_string_pretty(&state)?)?;
+    log::debug!("State saved to: {}", path.as_ref().display());
+    Ok(())
+  }
+
+  /// Remove the notify targets from the state, returning them. See `notify::Targets`.
+  pub fn take_notify(&mut self) -> notify::Targets {
+    let mut targets = notify::Targets {
+      global: self.config.global.as_mut().and_then(|v| v.notify.take()),
+      ..Default::default()
+    };
+    for recipe in &mut self.config.recipe {
+      if let Some(notify) = recipe.overrides.as_mut().and_then(|v| v.notify.take()) {
+        targets.overrides.insert(recipe.id.clone(), notify);
+      }
+    }
+    for recipe_state in self.states.values_mut() {
+      recipe_state.global.notify = None;
+    }
+    targets
+  }
+
+  /// Put back the notify targets `take_notify` removed.
+  pub fn restore_notify(&mut self, mut targets: notify::Targets) {
+    if let Some(notify) = targets.global.take() {
+      self.config.global.get_or_insert_with(Globals::default).notify = Some(notify);
+    }
+    for recipe in &mut self.config.recipe {
+      if let Some(notify) = targets.overrides.remove(&recipe.id) {
+        recipe.overrides.get_or_insert_with(Globals::default).notify = Some(notify);
+      }
+      if let Some(recipe_state) = self.states.get_mut(&recipe.id) {
+        recipe_state.global.notify = recipe.into_state(&self.config.global).global.notify;
+      }
+    }
+  }
+
+  /// Save the state to `config.state_path`, if configured.
+  pub fn persist(&self) -> anyhow::Result<()> {
+    match &self.config.state_path {
+      Some(path) => self.save(path),
+      None => Ok(()),
+    }
+  }
+
+  /// Save the state to `store`, or to `config.state_path` without one, and publish it to `control` if the run has one.
+  fn checkpoint(&self, control: Option<&Control>, store: Option<&dyn StateStore>) -> anyhow::Result<()> {
+    if let Some(control) = control {
+      control.publish_state(self);
+    }
+    match store {
+      Some(store) => store.save(self),
+      None => self.persist(),
+    }
+  }
+
+  /// Keep the progress recorded in `previous` for every recipe whose configuration did not change, so that only new
+  /// and changed recipes run. Returns the ids of the recipes that kept their progress.
+  pub fn carry_over(&mut self, previous: &State) -> Vec<String> {
+    let mut kept = Vec::new();
+    for recipe_id in &self.recipes {
+      let (Some(recipe_state), Some(old)) = (self.states.get_mut(recipe_id), previous.states.get(recipe_id)) else {
+        continue;
+      };
+      // Where notifications go does not change what a recipe does.
+      if old.config == recipe_state.config && old.global.distro_hint == recipe_state.global.distro_hint {
+        recipe_state.state = old.state.clone();
+        kept.push(recipe_id.clone());
+      }
+    }
+    kept
+  }
+
+  /// Check the completed recipes for drift, see `RecipeState::check`, returning what drifted by recipe. Recipes that
+  /// drifted are no longer completed, so that the next invocation runs the parts of them that drifted.
+  pub async fn check_drift(&mut self) -> anyhow::Result<Vec<(String, Option<Vec<String>>)>> {
+    let mut drift = Vec::new();
+    for recipe_id in &self.recipes {
+      let Some(recipe_state) = self.states.get_mut(recipe_id).filter(|v| v.is_completed()) else {
+        continue;
+      };
+      let found = recipe_state
+        .check()
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to check recipe '{recipe_id}' for drift: {e}"))?;
+      if let Some(found) = found.as_ref().filter(|v| !v.is_empty()) {
+        log::info!("Recipe '{recipe_id}' drifted: {}", found.join("; "));
+      }
+      drift.push((recipe_id.clone(), found));
+    }
+    Ok(drift)
+  }
+
+  /// Evaluate the recipe's condition and mark it skipped when the condition is false. Returns why it was skipped.
+  fn skip_unless_condition(&mut self, recipe_id: &str, facts: &serde_json::Value) -> anyhow::Result<Option<String>> {
+    let Some(recipe_state) = self.states.get_mut(recipe_id) else {
+      return Ok(Some("Recipe state not found".to_string()));
+    };
+    if recipe_state.condition(facts)? {
+      recipe_state.skipped = None;
+      return Ok(None);
+    }
+
+    let reason = format!(
+      "Condition `{}` is false",
+      recipe_state.when.as_deref().unwrap_or_default()
+    );
+    log::info!("Skipping recipe '{recipe_id}': {reason}");
+    recipe_state.skipped = Some(reason.clone());
+    Ok(Some(reason))
+  }
+
+  /// The copy of the state, with the reboot recipe marked as done, to move into the system the recipe boots once it
+  /// has loaded the new kernel. `None` unless the recipe has `move_state`.
+  fn handoff(&self, recipe_id: &str) -> Option<reboot::Handoff> {
+    let recipe_state = self.states.get(recipe_id)?;
+    let PluginConfig::Reboot(config) = &recipe_state.config else {
+      return None;
+    };
+    let (new_root, state_path) = config.move_state()?;
+
+    let mut state = self.clone();
+    state.config.state_path = Some(state_path.to_string());
+    if let Some(next) = state.states.get_mut(recipe_id) {
+      next.state = PluginState::Reboot(true);
+    }
+    Some(reboot::Handoff {
+      new_root: new_root.to_string(),
+      state_path: state_path.to_string(),
+      state,
+      distro: recipe_state.global.distro_for(new_root),
+    })
+  }
+
+  pub async fn invoke(&mut self) -> anyhow::Result<Report> { self.invoke_with(&NoEvents).await }
+
+  pub async fn invoke_with(&mut self, events: &dyn EventSink) -> anyhow::Result<Report> {
+    self.invoke_selected(&Selection::default(), events).await
+  }
+
+  /// Run every recipe that is not completed yet, starting each one as soon as the recipes it depends on have
+  /// finished. Once a recipe fails no new recipes are started, and the first error is returned after the running ones
+  /// have finished. Recipes that `selection` leaves out are skipped, and count as finished for their dependents.
+  /// Progress is reported to `events`, ending with a `RunFinish` event that carries the report.
+  pub async fn invoke_selected(&mut self, selection: &Selection, events: &dyn EventSink) -> anyhow::Result<Report> {
+    self.invoke_inner(selection, events, None, None, cancel::run_token()).await
+  }
+
+  /// Like `invoke_selected`, with the run observed and steered through `control`: it sees the state and the events,
+  /// and can pause the run or skip recipes before they start.
+  pub async fn invoke_controlled(
+    &mut self, selection: &Selection, events: &dyn EventSink, control: &Control,
+  ) -> anyhow::Result<Report> {
+    self.invoke_inner(selection, events, Some(control), None, cancel::run_token()).await
+  }
+
+  /// The run behind the `invoke_*` methods and `Executor`, saving the state to `store` if it is set. The run stops
+  /// when `token` is cancelled.
+  pub(crate) async fn invoke_inner(
+    &mut self, selection: &Selection, events: &dyn EventSink, control: Option<&Control>,
+    store: Option<&dyn StateStore>, token: CancellationToken,
+  ) -> anyhow::Result<Report> {
+    let token = match control {
+      Some(control) => control.start_run(&token),
+      None => token,
+    };
+    let started = Instant::now();
+    let mut report = Report::new(self.recipes.iter().map(|id| {
+      let plugin = self.states.get(id).map(|v| v.config.name()).unwrap_or_default();
+      (id.clone(), plugin.to_string())
+    }));
+    let notifier = Notifier::for_state(self);
+    let mut emit = |event: Event| {
+      report.record(&event);
+      if let Some(control) = control {
+        control.broadcast(&event);
+      }
+      notifier.event(&event);
+      events.emit(&event);
+    };
+    emit(Event::RunStart {
+      recipes: self.recipes.clone(),
+    });
+
+    let result = self.schedule(selection, control, store, &notifier, &token, &mut emit).await;
+    report.finish(started.elapsed().as_millis() as u64, &result, token.is_cancelled());
+    if let Some(path) = &self.config.metrics_path &&
+      let Err(e) = metrics::write_textfile(path, &report)
+    {
+      log::warn!("Failed to write metrics to {path}: {e}");
+    }
+    let finish = Event::RunFinish { report: report.clone() };
+    if let Some(control) = control {
+      control.broadcast(&finish);
+    }
+    notifier.event(&finish);
+    events.emit(&finish);
+    notifier.finish().await;
+    result.map(|_| report)
+  }
+
+  async fn schedule(
+    &mut self, selection: &Selection, control: Option<&Control>, store: Option<&dyn StateStore>, notifier: &Notifier,
+    token: &CancellationToken, emit: &mut impl FnMut(Event),
+  ) -> anyhow::Result<()> {
+    selection.check(&self.config.recipe).map_err(config_error)?;
+    let dependencies = self.config.dependencies().map_err(config_error)?;
+
+    let mut done: HashSet<String> = HashSet::new();
+    let mut pending: Vec<String> = Vec::new();
     for recipe_id in &self.recipes {
-      log::info!("Invoking recipe: {recipe_id}");
-      if let Some(recipe_state) = self.states.get_mut(recipe_id) {
-        recipe_state.invoke().await?;
+      match self.states.get_mut(recipe_id) {
+        Some(recipe_state) if recipe_state.is_completed() => {
+          log::info!("Recipe '{recipe_id}' is already completed, skipping");
+          emit(Event::RecipeSkip {
+            recipe: recipe_id.clone(),
+            plugin: recipe_state.config.name().to_string(),
+            reason: "Already completed".to_string(),
+          });
+          done.insert(recipe_id.clone());
+        }
+        Some(recipe_state) => match selection.skip_reason(&self.config.recipe, recipe_id) {
+          Some(reason) => {
+            log::info!("Skipping recipe '{recipe_id}': {reason}");
+            recipe_state.skipped = Some(reason.clone());
+            emit(Event::RecipeSkip {
+              recipe: recipe_id.clone(),
+              plugin: recipe_state.config.name().to_string(),
+              reason,
+            });
+            done.insert(recipe_id.clone());
+          }
+          None => pending.push(recipe_id.clone()),
+        },
+        None => log::warn!("Recipe state for '{recipe_id}' not found"),
+      }
+    }
+    self.checkpoint(control, store)?;
+
+    let facts = serde_json::to_value(Facts::gather())?;
+    let mut running = FuturesUnordered::new();
+    let mut error: Option<anyhow::Error> = None;
+    loop {
+      // Skipping a recipe can make its dependents ready, so keep scheduling until nothing new becomes ready.
+      while error.is_none() && !token.is_cancelled() && !control.is_some_and(|v| v.is_paused()) {
+        let (ready, waiting): (Vec<String>, Vec<String>) = pending.into_iter().partition(|id| {
+          dependencies
+            .get(id)
+            .is_none_or(|deps| deps.iter().all(|d| done.contains(d) || !self.states.contains_key(d)))
+        });
+        pending = waiting;
+        if ready.is_empty() {
+          break;
+        }
+        for recipe_id in ready {
+          let plugin = self.states.get(&recipe_id).map(|v| v.config.name()).unwrap_or_default().to_string();
+          if control.is_some_and(|v| v.take_skip(&recipe_id)) {
+            let reason = "Skipped through the control API".to_string();
+            log::info!("Skipping recipe '{recipe_id}': {reason}");
+            if let Some(recipe_state) = self.states.get_mut(&recipe_id) {
+              recipe_state.skipped = Some(reason.clone());
+            }
+            if let Err(e) = self.checkpoint(control, store) {
+              log::error!("Failed to persist state after skipping recipe '{recipe_id}': {e}");
+              error.get_or_insert(e);
+              break;
+            }
+            emit(Event::RecipeSkip {
+              recipe: recipe_id.clone(),
+              plugin,
+              reason,
+            });
+            done.insert(recipe_id);
+            continue;
+          }
+          match self.skip_unless_condition(&recipe_id, &facts) {
+            Ok(None) => {}
+            Ok(Some(reason)) => {
+              if let Err(e) = self.checkpoint(control, store) {
+                log::error!("Failed to persist state after skipping recipe '{recipe_id}': {e}");
+                error.get_or_insert(e);
+                break;
+              }
+              emit(Event::RecipeSkip {
+                recipe: recipe_id.clone(),
+                plugin,
+                reason,
+              });
+              done.insert(recipe_id);
+              continue;
+            }
+            Err(e) => {
+              log::error!("Failed to evaluate condition of recipe '{recipe_id}': {e}");
+              error.get_or_insert(config_error(e));
+              break;
+            }
+          }
+          log::info!("Invoking recipe: {recipe_id}");
+          lock::set_running(&recipe_id, true);
+          emit(Event::RecipeStart {
+            recipe: recipe_id.clone(),
+            plugin,
+          });
+          let mut recipe_state = self.states[&recipe_id].clone();
+          let before_reboot = matches!(recipe_state.config, PluginConfig::Reboot(_)).then(|| reboot::BeforeReboot {
+            recipe: recipe_id.clone(),
+            handoff: self.handoff(&recipe_id),
+            notifier: notifier.clone(),
+          });
+          let token = token.clone();
+          running.push(async move {
+            let started = Instant::now();
+            let result = match before_reboot {
+              Some(before_reboot) => before_reboot.scope(recipe_state.run(&token)).await,
+              None => recipe_state.run(&token).await,
+            };
+            (recipe_state, result, started.elapsed().as_millis() as u64)
+          });
+        }
+      }
+
+      if let Some(control) = control &&
+        control.is_paused() &&
+        running.is_empty() &&
+        !pending.is_empty() &&
+        error.is_none() &&
+        !token.is_cancelled()
+      {
+        log::info!("Run paused, waiting to be resumed");
+        emit(Event::RunPause);
+        tokio::select! {
+          _ = control.resumed() => emit(Event::RunResume),
+          _ = token.cancelled() => {}
+        }
+        continue;
+      }
+
+      let next = if token.is_cancelled() {
+        tokio::time::timeout(CANCEL_GRACE_PERIOD, running.next()).await.unwrap_or_else(|_| {
+          log::warn!("Recipes did not stop within {CANCEL_GRACE_PERIOD:?}, abandoning them");
+          None
+        })
       } else {
-        log::warn!("Recipe state for '{recipe_id}' not found");
+        running.next().await
+      };
+      let Some((recipe_state, result, duration_ms)) = next else {
+        break;
+      };
+      let recipe_id = recipe_state.id.clone();
+      let plugin = recipe_state.config.name().to_string();
+      let attempts = recipe_state.attempts.len();
+      lock::set_running(&recipe_id, false);
+      self.states.insert(recipe_id.clone(), recipe_state);
+      if let Err(e) = self.checkpoint(control, store) {
+        log::error!("Failed to persist state after recipe '{recipe_id}': {e}");
+        error.get_or_insert(e);
+      }
+      match result {
+        Ok(()) => {
+          emit(Event::RecipeFinish {
+            recipe: recipe_id.clone(),
+            plugin,
+            duration_ms,
+            attempts,
+          });
+          done.insert(recipe_id);
+        }
+        Err(e) => {
+          log::error!("Recipe '{recipe_id}' failed: {e}");
+          emit(Event::RecipeFail {
+            recipe: recipe_id,
+            plugin,
+            duration_ms,
+            attempts,
+            error: e.to_string(),
+          });
+          error.get_or_insert(recipe_error(e));
+        }
       }
     }
+
+    if token.is_cancelled() {
+      // Dropping the abandoned recipes kills their commands, then cleans up the mounts under them.
+      drop(running);
+      self.checkpoint(control, store)?;
+      return Err(cancel::Interrupted.into());
+    }
+    if let Some(e) = error {
+      return Err(e);
+    }
     Ok(())
   }
 }
 
 #[cfg(test)]
 mod tests {
-  use std::{path::PathBuf, str::FromStr};
+  use std::{path::PathBuf, str::FromStr, sync::Mutex};
 
   use super::*;
+  use crate::events::RecipeStatus;
 
   #[test]
   fn serialize() {
     let config = Config {
-      // state_path: Some("/infraplan-state.json".to_string()),
+      include: None,
+      state_path: Some("/infraplan-state.json".to_string()),
+      metrics_path: None,
+      vars: None,
       global: Some(Globals {
         distro_hint: Some(Distro::Ubuntu),
+        notify: None,
       }),
       recipe: vec![
         RecipeConfig {
           id: "system_deploy".to_string(),
           name: Some("Deploy ubuntu".to_string()),
           overrides: None,
+          depends_on: None,
+          when: None,
+          retry: None,
+          timeout: None,
+          tags: None,
           config: PluginConfig::SystemDeployer(sys_deploy::Config::Tar(sys_deploy::tar::Config {
             url: "https://example.local/ubuntu.tar.zstd".to_string(),
             compression: Some(sys_deploy::tar::Compression::Zstd),
             common: sys_deploy::CommonConfig {
               disk: "/dev/sda".to_string(),
               mount: "/mnt".to_string(),
-              distro: Distro::Ubuntu,
+              distro: Some(Distro::Ubuntu),
             },
           })),
         },
@@ -259,6 +1212,11 @@ mod tests {
           id: "system_reconfig".to_string(),
           name: Some("Reconfigure system".to_string()),
           overrides: None,
+          depends_on: None,
+          when: None,
+          retry: None,
+          timeout: None,
+          tags: None,
           config: PluginConfig::SystemReconfigurator(sysconf::Config {
             chroot: Some("/mnt".to_string()),
             with: vec![
@@ -287,18 +1245,28 @@ mod tests {
           id: "reboot".to_string(),
           name: Some("Reboot system".to_string()),
           overrides: None,
+          depends_on: None,
+          when: None,
+          retry: None,
+          timeout: None,
+          tags: None,
           config: PluginConfig::Reboot(reboot::Config::Kexec(reboot::kexec::Config {
             linux: Some("/mnt/boot/vmlinuz".to_string()),
             initrd: Some("/mnt/boot/initrd.img".to_string()),
             root: "/dev/sda3".to_string(),
             append: Some("ro quiet splash".to_string()),
-            // move_state: Some("/mnt/infraplan-state.json".to_string()),
+            move_state: Some("/var/lib/infraplan/state.json".to_string()),
           })),
         },
         RecipeConfig {
           id: "install_packages".to_string(),
           name: Some("Install packages".to_string()),
           overrides: None,
+          depends_on: None,
+          when: None,
+          retry: None,
+          timeout: None,
+          tags: None,
           config: PluginConfig::PackageManager(pkgmgr::Config {
             install: Some(vec![
               "vim".to_string(),
@@ -332,6 +1300,480 @@ mod tests {
     assert_eq!(state, deserialized_state);
   }
 
+  #[test]
+  fn persist_state() {
+    let path = std::env::temp_dir().join(format!("infraplan-state-{}.json", std::process::id()));
+    let mut state = Config::from_path("../examples/deploy_ubuntu.yaml").unwrap().into_state();
+    state.config.state_path = Some(path.to_string_lossy().to_string());
+    assert!(state.states.values().all(|s| !s.is_completed()));
+
+    if let Some(PluginState::SystemDeployer(done)) = state.states.get_mut("system_deploy").map(|s| &mut s.state) {
+      *done = true;
+    }
+    assert!(state.states["system_deploy"].is_completed());
+    state.persist().unwrap();
+
+    let restored = State::from_path(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(state, restored);
+    assert!(restored.states["system_deploy"].is_completed());
+    assert!(!restored.states["system_reconfigure"].is_completed());
+  }
+
+  #[test]
+  fn save_notify_targets_apart() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let path = std::env::temp_dir().join(format!("infraplan-notify-{}.json", std::process::id()));
+    let state = Config::from_yaml(
+      r#"
+global:
+  notify: [{ url: "https://inventory.example/hooks", headers: { Authorization: Bearer secret } }]
+recipe:
+  - { id: a, use: shell, with: { script: "true" } }
+  - id: b
+    overrides: { notify: [{ url: "https://other.example/hooks" }] }
+    use: shell
+    with: { script: "true" }
+"#,
+    )
+    .unwrap()
+    .into_state();
+    state.save(&path).unwrap();
+
+    let saved = std::fs::read_to_string(&path).unwrap();
+    assert!(!saved.contains("secret") && !saved.contains("example"), "{saved}");
+    let targets_path = notify::Targets::path(&path);
+    let mode = std::fs::metadata(&targets_path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o077, 0);
+    let restored = State::from_path(&path).unwrap();
+    assert_eq!(restored, state);
+    let mut without = state.clone();
+    without.take_notify();
+    assert!(without.states["b"].global.notify.is_none());
+
+    // Saving a state without targets removes the ones saved before.
+    without.save(&path).unwrap();
+    assert!(!targets_path.exists());
+    std::fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn plan_recipes() {
+    let state = Config::from_path("../examples/deploy_ubuntu.yaml").unwrap().into_state();
+    for recipe_id in &state.recipes {
+      let actions = state.states[recipe_id].plan().unwrap();
+      assert!(
+        !actions.is_empty(),
+        "Recipe '{recipe_id}' should plan at least one action"
+      );
+    }
+
+    let actions = state.states["install_packages"].plan().unwrap();
+    assert_eq!(actions[0], "apt-get update");
+    assert!(actions.iter().any(|v| v.starts_with("apt-get install") && v.ends_with("htop docker.io")));
+  }
+
+  #[test]
+  fn resolve_dependencies() {
+    let config = Config::from_yaml(
+      r#"
+recipe:
+  - id: a
+    use: system_reconfigurator
+    with: { with: [] }
+  - id: b
+    use: system_reconfigurator
+    with: { with: [] }
+  - id: c
+    depends_on: [a]
+    use: system_reconfigurator
+    with: { with: [] }
+  - id: reboot
+    use: reboot
+    with: { type: kexec, root: /mnt }
+  - id: d
+    depends_on: []
+    use: system_reconfigurator
+    with: { with: [] }
+"#,
+    )
+    .unwrap();
+    let deps = config.dependencies().unwrap();
+    assert_eq!(deps["a"], Vec::<String>::new());
+    assert_eq!(deps["b"], vec!["a"]);
+    assert_eq!(deps["c"], vec!["a"]);
+    assert_eq!(deps["reboot"], vec!["a", "b", "c"]);
+    assert_eq!(deps["d"], vec!["reboot"]);
+
+    let mut cyclic = config.clone();
+    cyclic.recipe[0].depends_on = Some(vec!["c".to_string()]);
+    let err = cyclic.dependencies().unwrap_err();
+    assert_eq!(err.to_string(), "Dependency cycle between recipes: a, b, c, d, reboot");
+
+    let mut unknown = config.clone();
+    unknown.recipe[1].depends_on = Some(vec!["x".to_string()]);
+    assert!(unknown.dependencies().is_err());
+  }
+
+  #[tokio::test]
+  async fn invoke_in_dependency_order() {
+    let dir = std::env::temp_dir().join(format!("infraplan-order-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    // Each recipe records when it starts and ends, in nanoseconds.
+    let script = |id: &str| {
+      let path = dir.join(id);
+      format!(
+        "date +%s%N > {0}.start; sleep 0.5; date +%s%N > {0}.end",
+        path.display()
+      )
+    };
+    let mut state = Config::from_yaml(&format!(
+      r#"
+recipe:
+  - id: a
+    use: shell
+    with: {{ script: "{}" }}
+  - id: b
+    depends_on: []
+    use: shell
+    with: {{ script: "{}" }}
+  - id: c
+    depends_on: [a, b]
+    use: shell
+    with: {{ script: "{}" }}
+"#,
+      script("a"),
+      script("b"),
+      script("c")
+    ))
+    .unwrap()
+    .into_state();
+    let report = state.invoke().await.unwrap();
+    assert!(state.states.values().all(|s| s.is_completed()));
+    assert!(report.recipes.iter().all(|v| v.status == RecipeStatus::Completed));
+
+    let time = |name: &str| -> u128 { std::fs::read_to_string(dir.join(name)).unwrap().trim().parse().unwrap() };
+    assert!(time("c.start") >= time("a.end"));
+    assert!(time("c.start") >= time("b.end"));
+    assert!(time("a.start") < time("b.end") && time("b.start") < time("a.end"));
+    std::fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[tokio::test]
+  async fn skip_recipes_by_condition() {
+    let mut state = Config::from_yaml(
+      r#"
+global:
+  distro_hint: ubuntu
+recipe:
+  - id: a
+    when: global.distro_hint == "alpine"
+    use: system_reconfigurator
+    with: { with: [{ use: netplan, with: [] }] }
+  - id: b
+    when: global.distro_hint in ["debian", "ubuntu"]
+    use: system_reconfigurator
+    with: { with: [{ use: netplan, with: [] }] }
+"#,
+    )
+    .unwrap()
+    .into_state();
+    let report = state.invoke().await.unwrap();
+    assert_eq!(report.recipes[0].status, RecipeStatus::Skipped);
+    assert_eq!(report.recipes[1].status, RecipeStatus::Completed);
+    assert!(report.changed());
+    assert_eq!(
+      state.states["a"].skipped.as_deref(),
+      Some(r#"Condition `global.distro_hint == "alpine"` is false"#)
+    );
+    assert_eq!(state.states["b"].skipped, None);
+  }
+
+  #[tokio::test]
+  async fn retry_failed_recipe() {
+    let mut state = Config::from_yaml(
+      r#"
+recipe:
+  - id: users
+    retry: { attempts: 3, backoff: 10ms }
+    timeout: 10s
+    use: system_reconfigurator
+    with:
+      chroot: /nonexistent/infraplan-root
+      with: [{ use: user, with: [{ name: test }] }]
+"#,
+    )
+    .unwrap()
+    .into_state();
+    assert!(state.invoke().await.is_err());
+    let attempts = &state.states["users"].attempts;
+    assert_eq!(attempts.len(), 3);
+    assert!(attempts.iter().all(|v| v.error.is_some()));
+  }
+
+  #[tokio::test]
+  async fn clean_up_timed_out_chroot() {
+    // Preparing the chroot mounts /proc, /sys and /dev into it, and the root is built from the host with `ldd`.
+    if !nix::unistd::geteuid().is_root() || std::process::Command::new("ldd").arg("--version").output().is_err() {
+      eprintln!("Skipping clean_up_timed_out_chroot: needs root and ldd");
+      return;
+    }
+    let root = std::env::temp_dir().join(format!("infraplan-chroot-{}", std::process::id()));
+    // A root with just enough of the host to run `sh -c "sleep 30"`.
+    for binary in ["/bin/sh", "/bin/sleep"] {
+      let libraries = std::process::Command::new("ldd").arg(binary).output().unwrap();
+      let libraries = String::from_utf8(libraries.stdout).unwrap();
+      let paths = libraries.split_whitespace().filter(|v| v.starts_with('/')).map(str::to_string);
+      for path in paths.chain([binary.to_string()]) {
+        let target = root.join(path.trim_start_matches('/'));
+        std::fs::create_dir_all(target.parent().unwrap()).unwrap();
+        std::fs::copy(&path, &target).unwrap();
+      }
+    }
+    let mut state = Config::from_yaml(&format!(
+      r#"
+recipe:
+  - id: wait
+    timeout: 1s
+    use: shell
+    with: {{ script: "sleep 30", chroot: "{}" }}
+"#,
+      root.display()
+    ))
+    .unwrap()
+    .into_state();
+    assert!(state.invoke().await.is_err());
+    let error = state.states["wait"].attempts[0].error.clone().unwrap();
+    assert!(error.contains("Timed out after 1s"), "{error}");
+
+    let mounts = std::fs::read_to_string("/proc/self/mountinfo").unwrap();
+    let root = root.to_str().unwrap();
+    assert!(!mounts.contains(root), "Mounts left under {root}:\n{mounts}");
+    std::fs::remove_dir_all(root).unwrap();
+  }
+
+  struct Collect(Mutex<Vec<Event>>);
+
+  impl EventSink for Collect {
+    fn emit(&self, event: &Event) { self.0.lock().unwrap().push(event.clone()); }
+  }
+
+  #[tokio::test]
+  async fn emit_events() {
+    let mut state = Config::from_yaml(
+      r#"
+recipe:
+  - id: users
+    retry: { attempts: 2 }
+    use: system_reconfigurator
+    with:
+      chroot: /nonexistent/infraplan-root
+      with: [{ use: user, with: [{ name: test }] }]
+  - id: after
+    use: system_reconfigurator
+    with: { with: [{ use: netplan, with: [] }] }
+"#,
+    )
+    .unwrap()
+    .into_state();
+    let sink = Collect(Mutex::new(Vec::new()));
+    assert!(state.invoke_with(&sink).await.is_err());
+
+    let events = sink.0.into_inner().unwrap();
+    assert!(matches!(events[0], Event::RunStart { .. }));
+    assert!(
+      matches!(&events[1], Event::RecipeStart { recipe, plugin } if recipe == "users" && plugin == "system_reconfigurator")
+    );
+    assert!(matches!(&events[2], Event::RecipeFail { recipe, attempts: 2, .. } if recipe == "users"));
+    let Event::RunFinish { report } = &events[3] else {
+      panic!("Expected the run to finish, got {:?}", events[3]);
+    };
+    assert_eq!(report.status, crate::events::RunStatus::Failed);
+    assert_eq!(report.recipes[1].status, RecipeStatus::Pending);
+  }
+
+  #[test]
+  fn resolve_vars() {
+    let mut config = Config::from_path("../examples/deploy_ubuntu.yaml").unwrap();
+    config.resolve_vars(&HashMap::from([("disk".to_string(), "/dev/sda".to_string())])).unwrap();
+    let value = serde_json::to_value(&config.recipe).unwrap().to_string();
+    assert!(!value.contains("${"));
+    assert!(value.contains(r#""disk":"/dev/sda""#));
+    assert!(value.contains(r#""base_url":"https://archive.ubuntu.com/ubuntu""#));
+
+    // `vars` take precedence over the environment.
+    let mut config = Config::from_yaml(
+      r#"
+vars:
+  PATH: /mnt
+recipe:
+  - id: reboot
+    use: reboot
+    with:
+      type: kexec
+      root: ${PATH}
+"#,
+    )
+    .unwrap();
+    config.resolve_vars(&HashMap::new()).unwrap();
+    let value = serde_json::to_value(&config.recipe).unwrap().to_string();
+    assert!(value.contains(r#""root":"/mnt""#));
+
+    // Shell code keeps its `${...}` for the shell, other shell fields are interpolated.
+    let mut config = Config::from_yaml(
+      r#"
+vars:
+  disk: /dev/vdb
+recipe:
+  - id: names
+    use: shell
+    with:
+      script: 'for f in /sys/class/net/*; do echo "${f##*/} ${HOME}"; done'
+      commands: [[echo, "${VAR}"]]
+      unless: 'test -n "${VAR}"'
+      env: { DISK: "${disk}" }
+"#,
+    )
+    .unwrap();
+    config.resolve_vars(&HashMap::new()).unwrap();
+    let PluginConfig::Shell(shell) = &config.recipe[0].config else {
+      panic!("Unexpected config {:?}", config.recipe[0].config);
+    };
+    assert_eq!(
+      shell.script.as_deref(),
+      Some(r#"for f in /sys/class/net/*; do echo "${f##*/} ${HOME}"; done"#)
+    );
+    assert_eq!(
+      shell.commands,
+      Some(vec![vec!["echo".to_string(), "${VAR}".to_string()]])
+    );
+    assert_eq!(shell.unless.as_deref(), Some(r#"test -n "${VAR}""#));
+    assert_eq!(shell.env.as_ref().unwrap()["DISK"], "/dev/vdb");
+
+    let mut config = Config::from_yaml(
+      r#"
+recipe:
+  - id: reboot
+    use: reboot
+    with:
+      type: kexec
+      root: ${undefined_infraplan_var}
+"#,
+    )
+    .unwrap();
+    assert!(config.resolve_vars(&HashMap::new()).is_err());
+  }
+
+  #[test]
+  fn reset_and_mark_done() {
+    let config = Config::from_yaml(
+      r#"
+recipe:
+  - id: sysconf
+    use: system_reconfigurator
+    with: { with: [{ use: netplan, with: [] }, { use: netplan, with: [] }] }
+  - { id: shell, use: shell, with: { script: "true" } }
+"#,
+    )
+    .unwrap();
+    let mut state = config.into_state();
+    for recipe_state in state.states.values_mut() {
+      assert!(!recipe_state.is_completed());
+      recipe_state.mark_done();
+      assert!(recipe_state.is_completed());
+      recipe_state.reset();
+      assert!(!recipe_state.is_completed());
+      assert_eq!(recipe_state.state, recipe_state.config.default_state());
+    }
+  }
+
+  #[tokio::test]
+  async fn rerun_drifted_items() {
+    let root = std::env::temp_dir().join(format!("infraplan-drift-{}", std::process::id()));
+    let lists = root.join("etc/apt/sources.list.d");
+    std::fs::create_dir_all(&lists).unwrap();
+    let config = Config::from_yaml(&format!(
+      r#"
+recipe:
+  - id: repos
+    use: system_reconfigurator
+    with:
+      chroot: {}
+      with:
+        - {{ use: apt_repo, with: [{{ name: a, base_url: "http://a", distro: stable, components: [main] }}] }}
+        - {{ use: apt_repo, with: [{{ name: b, base_url: "http://b", distro: stable, components: [main] }}] }}
+"#,
+      root.display()
+    ))
+    .unwrap();
+    let mut state = config.into_state();
+    state.states.get_mut("repos").unwrap().mark_done();
+    // Edited by hand, but not to be overwritten, so it did not drift.
+    std::fs::write(lists.join("a.list"), "deb http://mirror stable main\n").unwrap();
+
+    let drift = state.check_drift().await.unwrap();
+    let missing = format!("{} is missing", lists.join("b.list").display());
+    assert_eq!(drift, vec![("repos".to_string(), Some(vec![missing]))]);
+    assert_eq!(
+      state.states["repos"].state,
+      PluginState::SystemReconfigurator(vec![true, false])
+    );
+
+    state.invoke().await.unwrap();
+    assert_eq!(
+      std::fs::read_to_string(lists.join("a.list")).unwrap(),
+      "deb http://mirror stable main\n"
+    );
+    assert_eq!(
+      std::fs::read_to_string(lists.join("b.list")).unwrap(),
+      "deb http://b stable main\n"
+    );
+    assert_eq!(
+      state.check_drift().await.unwrap(),
+      vec![("repos".to_string(), Some(vec![]))]
+    );
+    std::fs::remove_dir_all(root).unwrap();
+  }
+
+  #[test]
+  fn detect_distro() {
+    let os = |content: &str| crate::facts::parse_os_release(content);
+    assert_eq!(
+      Distro::from_os_release(&os("ID=ubuntu\nID_LIKE=debian\n")),
+      Some(Distro::Ubuntu)
+    );
+    assert_eq!(
+      Distro::from_os_release(&os("ID=linuxmint\nID_LIKE=\"ubuntu debian\"\n")),
+      Some(Distro::Ubuntu)
+    );
+    assert_eq!(
+      Distro::from_os_release(&os("ID=\"rocky\"\nID_LIKE=\"rhel centos fedora\"\n")),
+      Some(Distro::Fedora)
+    );
+    assert_eq!(
+      Distro::from_os_release(&os("ID=manjaro\nID_LIKE=arch\n")),
+      Some(Distro::Arch)
+    );
+    assert_eq!(Distro::from_os_release(&os("ID=nixos\n")), None);
+
+    let root = std::env::temp_dir().join(format!("infraplan-distro-{}", std::process::id()));
+    std::fs::create_dir_all(root.join("etc")).unwrap();
+    std::fs::write(root.join("etc/os-release"), "ID=alpine\n").unwrap();
+    let root = root.to_string_lossy().to_string();
+    let detect = Globals {
+      distro_hint: None,
+      notify: None,
+    };
+    assert_eq!(detect.distro_for(&root), Some(Distro::Alpine));
+    let explicit = Globals {
+      distro_hint: Some(Distro::Debian),
+      ..detect
+    };
+    assert_eq!(explicit.distro_for(&root), Some(Distro::Debian));
+    std::fs::remove_dir_all(&root).unwrap();
+  }
+
   #[test]
   fn deserialize_yaml() {
     const EXAMPLES_PATH: &str = "../examples";
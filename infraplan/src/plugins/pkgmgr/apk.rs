@@ -1,10 +1,15 @@
 use crate::utils::process::run_command;
 
-const EXE_APK: &str = "apk";
+pub const EXE_APK: &str = "apk";
+pub const ARGS_UPDATE: &[&str] = &["update"];
+pub const ARGS_UPGRADE: &[&str] = &["upgrade", "--no-progress"];
+pub const ARGS_INSTALL: &[&str] = &["add", "--no-progress"];
+pub const ARGS_REMOVE: &[&str] = &["del", "--no-progress"];
+pub const ARGS_QUERY: &[&str] = &["info"];
 
 pub async fn apk_update() -> anyhow::Result<()> {
   log::info!("Updating package lists...");
-  let (code, _, _) = run_command(EXE_APK, &["update"]).await?;
+  let (code, _, _) = run_command(EXE_APK, ARGS_UPDATE).await?;
 
   if code != 0 {
     log::error!("Failed to update package lists with exit code: {code}");
@@ -16,7 +21,7 @@ pub async fn apk_update() -> anyhow::Result<()> {
 
 pub async fn apk_upgrade() -> anyhow::Result<()> {
   log::info!("Upgrading installed packages...");
-  let (code, _, _) = run_command(EXE_APK, &["upgrade", "--no-progress"]).await?;
+  let (code, _, _) = run_command(EXE_APK, ARGS_UPGRADE).await?;
 
   if code != 0 {
     log::error!("Failed to upgrade packages with exit code: {code}");
@@ -33,10 +38,7 @@ pub async fn apk_install(packages: &[String]) -> anyhow::Result<()> {
   }
 
   log::info!("Installing packages: {}", packages.join(", "));
-  let args = ["add", "--no-progress"]
-    .into_iter()
-    .chain(packages.iter().map(|v| v.as_str()))
-    .collect::<Vec<&str>>();
+  let args = ARGS_INSTALL.iter().copied().chain(packages.iter().map(|v| v.as_str())).collect::<Vec<&str>>();
 
   let (code, _, _) = run_command(EXE_APK, &args).await?;
 
@@ -55,10 +57,7 @@ pub async fn apk_remove(packages: &[String]) -> anyhow::Result<()> {
   }
 
   log::info!("Removing packages: {}", packages.join(", "));
-  let args = ["del", "--no-progress"]
-    .into_iter()
-    .chain(packages.iter().map(|v| v.as_str()))
-    .collect::<Vec<&str>>();
+  let args = ARGS_REMOVE.iter().copied().chain(packages.iter().map(|v| v.as_str())).collect::<Vec<&str>>();
 
   let (code, _, _) = run_command(EXE_APK, &args).await?;
 
@@ -1,10 +1,17 @@
 use crate::utils::process::run_command;
 
-const EXE_APT: &str = "apt-get";
+pub const EXE_APT: &str = "apt-get";
+pub const ARGS_UPDATE: &[&str] = &["update"];
+pub const ARGS_UPGRADE: &[&str] = &["upgrade", "-y"];
+pub const ARGS_INSTALL: &[&str] =
+  &["install", "-y", "--no-install-recommends", "--no-install-suggests", "--allow-downgrades"];
+pub const ARGS_REMOVE: &[&str] = &["autoremove", "-y", "--purge"];
+pub const EXE_DPKG_QUERY: &str = "dpkg-query";
+pub const ARGS_QUERY: &[&str] = &["-W", "-f", "${db:Status-Status} ${Package}\n"];
 
 pub async fn apt_update() -> anyhow::Result<()> {
   log::info!("Updating package lists...");
-  let (code, _, _) = run_command(EXE_APT, &["update"]).await?;
+  let (code, _, _) = run_command(EXE_APT, ARGS_UPDATE).await?;
 
   if code != 0 {
     log::error!("Failed to update package lists with exit code: {code}");
@@ -16,7 +23,7 @@ pub async fn apt_update() -> anyhow::Result<()> {
 
 pub async fn apt_upgrade() -> anyhow::Result<()> {
   log::info!("Upgrading installed packages...");
-  let (code, _, _) = run_command(EXE_APT, &["upgrade", "-y"]).await?;
+  let (code, _, _) = run_command(EXE_APT, ARGS_UPGRADE).await?;
 
   if code != 0 {
     log::error!("Failed to upgrade packages with exit code: {code}");
@@ -35,10 +42,7 @@ pub async fn apt_install(packages: &[String]) -> anyhow::Result<()> {
   log::info!("Installing packages: {}", packages.join(", "));
   let (code, _, _) = run_command(
     EXE_APT,
-    &["install", "-y", "--no-install-recommends", "--no-install-suggests", "--allow-downgrades"]
-      .into_iter()
-      .chain(packages.iter().map(|v| v.as_str()))
-      .collect::<Vec<&str>>(),
+    ARGS_INSTALL.iter().copied().chain(packages.iter().map(|v| v.as_str())).collect::<Vec<&str>>(),
   )
   .await?;
 
@@ -59,10 +63,7 @@ pub async fn apt_remove(packages: &[String]) -> anyhow::Result<()> {
   log::info!("Removing packages: {}", packages.join(", "));
   let (code, _, _) = run_command(
     EXE_APT,
-    &["autoremove", "-y", "--purge"]
-      .into_iter()
-      .chain(packages.iter().map(|v| v.as_str()))
-      .collect::<Vec<&str>>(),
+    ARGS_REMOVE.iter().copied().chain(packages.iter().map(|v| v.as_str())).collect::<Vec<&str>>(),
   )
   .await?;
 
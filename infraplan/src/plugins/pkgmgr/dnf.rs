@@ -1,10 +1,15 @@
 use crate::utils::process::run_command;
 
-const EXE_DNF: &str = "dnf";
+pub const EXE_DNF: &str = "dnf";
+pub const ARGS_UPGRADE: &[&str] = &["upgrade", "-y"];
+pub const ARGS_INSTALL: &[&str] = &["install", "-y"];
+pub const ARGS_REMOVE: &[&str] = &["remove", "-y"];
+pub const EXE_RPM: &str = "rpm";
+pub const ARGS_QUERY: &[&str] = &["-qa", "--qf", "%{NAME}\n"];
 
 pub async fn dnf_upgrade() -> anyhow::Result<()> {
   log::info!("Upgrading installed packages...");
-  let (code, _, _) = run_command(EXE_DNF, &["upgrade", "-y"]).await?;
+  let (code, _, _) = run_command(EXE_DNF, ARGS_UPGRADE).await?;
 
   if code != 0 {
     log::error!("Failed to upgrade packages with exit code: {code}");
@@ -21,7 +26,7 @@ pub async fn dnf_install(packages: &[String]) -> anyhow::Result<()> {
   }
 
   log::info!("Installing packages: {}", packages.join(", "));
-  let args: Vec<&str> = ["install", "-y"].into_iter().chain(packages.iter().map(|v| v.as_str())).collect();
+  let args: Vec<&str> = ARGS_INSTALL.iter().copied().chain(packages.iter().map(|v| v.as_str())).collect();
 
   let (code, _, _) = run_command(EXE_DNF, &args).await?;
 
@@ -40,7 +45,7 @@ pub async fn dnf_remove(packages: &[String]) -> anyhow::Result<()> {
   }
 
   log::info!("Removing packages: {}", packages.join(", "));
-  let args: Vec<&str> = ["remove", "-y"].into_iter().chain(packages.iter().map(|v| v.as_str())).collect();
+  let args: Vec<&str> = ARGS_REMOVE.iter().copied().chain(packages.iter().map(|v| v.as_str())).collect();
 
   let (code, _, _) = run_command(EXE_DNF, &args).await?;
 
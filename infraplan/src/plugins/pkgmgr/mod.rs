@@ -1,17 +1,109 @@
-use crate::plugins::Distro;
+use std::collections::HashSet;
+
+use crate::{plugins::Distro, utils::process::run_command};
 
 pub mod apk;
 pub mod apt;
 pub mod dnf;
 pub mod pacman;
 
-#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize, schemars::JsonSchema)]
 pub struct Config {
   pub install: Option<Vec<String>>,
   pub remove: Option<Vec<String>>,
   pub update: Option<bool>,
 }
 
+struct Commands {
+  exe: &'static str,
+  update: Option<&'static [&'static str]>,
+  /// Run when `update` is set. Arch and Alpine only refresh the package database again.
+  upgrade: &'static [&'static str],
+  install: &'static [&'static str],
+  remove: &'static [&'static str],
+  /// Lists the installed packages, see `parse_installed`.
+  query_exe: &'static str,
+  query: &'static [&'static str],
+}
+
+fn commands(distro: &Option<Distro>) -> Option<Commands> {
+  match distro {
+    Some(Distro::Debian) | Some(Distro::Ubuntu) => Some(Commands {
+      exe: apt::EXE_APT,
+      update: Some(apt::ARGS_UPDATE),
+      upgrade: apt::ARGS_UPGRADE,
+      install: apt::ARGS_INSTALL,
+      remove: apt::ARGS_REMOVE,
+      query_exe: apt::EXE_DPKG_QUERY,
+      query: apt::ARGS_QUERY,
+    }),
+    Some(Distro::Fedora) => Some(Commands {
+      exe: dnf::EXE_DNF,
+      update: None,
+      upgrade: dnf::ARGS_UPGRADE,
+      install: dnf::ARGS_INSTALL,
+      remove: dnf::ARGS_REMOVE,
+      query_exe: dnf::EXE_RPM,
+      query: dnf::ARGS_QUERY,
+    }),
+    Some(Distro::Arch) => Some(Commands {
+      exe: pacman::EXE_PACMAN,
+      update: Some(pacman::ARGS_UPDATE),
+      upgrade: pacman::ARGS_UPDATE,
+      install: pacman::ARGS_INSTALL,
+      remove: pacman::ARGS_REMOVE,
+      query_exe: pacman::EXE_PACMAN,
+      query: pacman::ARGS_QUERY,
+    }),
+    Some(Distro::Alpine) => Some(Commands {
+      exe: apk::EXE_APK,
+      update: Some(apk::ARGS_UPDATE),
+      upgrade: apk::ARGS_UPDATE,
+      install: apk::ARGS_INSTALL,
+      remove: apk::ARGS_REMOVE,
+      query_exe: apk::EXE_APK,
+      query: apk::ARGS_QUERY,
+    }),
+    None => None,
+  }
+}
+
+/// Names of the installed packages, from the output of a `query` command. `dpkg-query` prints the status of each
+/// package before its name, and lists packages of which only the configuration is left too.
+fn parse_installed(output: &str) -> HashSet<String> {
+  output
+    .lines()
+    .filter_map(|line| match line.split_whitespace().collect::<Vec<_>>().as_slice() {
+      [name] | ["installed", name] => Some(name.to_string()),
+      _ => None,
+    })
+    .collect()
+}
+
+/// The package name of `spec` in the syntax of the distro's package manager, without a version, release, repository or
+/// architecture: `curl=7.88.1-10` or `curl/bookworm-backports` with apt, `curl-7.88.1` or `curl.x86_64` with dnf,
+/// `extra/curl` with pacman and `curl=8.5.0-r0` or `curl@edge` with apk are all `curl`.
+fn package_name<'a>(distro: &Option<Distro>, spec: &'a str) -> &'a str {
+  let before = |spec: &'a str, separators: &[char]| spec.split(separators).next().unwrap_or(spec);
+  match distro {
+    Some(Distro::Debian) | Some(Distro::Ubuntu) => before(spec, &['=', '/', ':']),
+    Some(Distro::Fedora) => {
+      let spec = [".x86_64", ".aarch64", ".i686", ".noarch"]
+        .iter()
+        .find_map(|arch| spec.strip_suffix(arch))
+        .unwrap_or(spec);
+      // Package names contain dashes too; the version starts at the first one followed by a digit.
+      match spec.match_indices('-').find(|(i, _)| spec[i + 1..].starts_with(|c: char| c.is_ascii_digit())) {
+        Some((i, _)) => &spec[..i],
+        None => spec,
+      }
+    }
+    Some(Distro::Arch) => before(spec.rsplit('/').next().unwrap_or(spec), &['=', '<', '>']),
+    Some(Distro::Alpine) => before(spec, &['=', '<', '>', '~', '@']),
+    None => spec,
+  }
+}
+
 pub struct Context(pub crate::plugins::Globals);
 
 impl crate::plugins::Plugin for Context {
@@ -81,11 +173,107 @@ impl crate::plugins::Plugin for Context {
         }
       }
       None => {
-        anyhow::bail!("No distro hint provided for package manager plugin.");
+        anyhow::bail!("No distro hint provided for package manager plugin, and none could be detected.");
       }
     }
 
     *state = true;
     Ok(())
   }
+
+  fn plan(&self, config: &Self::Config, state: &Self::State) -> anyhow::Result<Vec<String>> {
+    if *state {
+      return Ok(vec!["Package manager is already invoked, nothing to do".to_string()]);
+    }
+    let Some(commands) = commands(&self.0.distro_hint) else {
+      anyhow::bail!("No distro hint provided for package manager plugin, and none could be detected.");
+    };
+
+    let mut actions = Vec::new();
+    if let Some(update) = commands.update {
+      actions.push(format!("{} {}", commands.exe, update.join(" ")));
+    }
+    if config.update.unwrap_or(true) {
+      actions.push(format!("{} {}", commands.exe, commands.upgrade.join(" ")));
+    }
+    if let Some(install) = config.install.as_ref().filter(|v| !v.is_empty()) {
+      actions.push(format!(
+        "{} {} {}",
+        commands.exe,
+        commands.install.join(" "),
+        install.join(" ")
+      ));
+    }
+    if let Some(remove) = config.remove.as_ref().filter(|v| !v.is_empty()) {
+      actions.push(format!(
+        "{} {} {}",
+        commands.exe,
+        commands.remove.join(" "),
+        remove.join(" ")
+      ));
+    }
+    Ok(actions)
+  }
+
+  async fn check(&self, config: &Self::Config, state: &mut Self::State) -> anyhow::Result<Option<Vec<String>>> {
+    let Some(commands) = commands(&self.0.distro_hint) else {
+      anyhow::bail!("No distro hint provided for package manager plugin, and none could be detected.");
+    };
+    let (code, stdout, stderr) = run_command(commands.query_exe, commands.query).await?;
+    if code != 0 {
+      anyhow::bail!("Failed to list installed packages: {}", stderr.trim());
+    }
+    let installed = parse_installed(&stdout);
+
+    let mut drift = Vec::new();
+    let distro = &self.0.distro_hint;
+    for spec in config.install.iter().flatten() {
+      let name = package_name(distro, spec);
+      if !installed.contains(name) {
+        drift.push(format!("Package {name} is not installed"));
+      }
+    }
+    for spec in config.remove.iter().flatten() {
+      let name = package_name(distro, spec);
+      if installed.contains(name) {
+        drift.push(format!("Package {name} is installed"));
+      }
+    }
+    if !drift.is_empty() {
+      *state = false;
+    }
+    Ok(Some(drift))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parse_installed_packages() {
+    let dpkg = "installed curl\nconfig-files vim\nnot-installed nano\n";
+    assert_eq!(parse_installed(dpkg), HashSet::from(["curl".to_string()]));
+    assert_eq!(
+      parse_installed("bash\ncoreutils\n"),
+      HashSet::from(["bash".to_string(), "coreutils".to_string()])
+    );
+  }
+
+  #[test]
+  fn package_names() {
+    let name = |distro: Distro, spec| package_name(&Some(distro), spec);
+    assert_eq!(name(Distro::Debian, "curl=7.88.1-10"), "curl");
+    assert_eq!(
+      name(Distro::Ubuntu, "linux-image-amd64/bookworm-backports"),
+      "linux-image-amd64"
+    );
+    assert_eq!(name(Distro::Debian, "libc6:i386"), "libc6");
+    assert_eq!(name(Distro::Fedora, "curl-7.88.1"), "curl");
+    assert_eq!(name(Distro::Fedora, "python3-libs.x86_64"), "python3-libs");
+    assert_eq!(name(Distro::Arch, "extra/vim"), "vim");
+    assert_eq!(name(Distro::Arch, "linux-firmware"), "linux-firmware");
+    assert_eq!(name(Distro::Alpine, "curl=8.5.0-r0"), "curl");
+    assert_eq!(name(Distro::Alpine, "py3-pip@edge"), "py3-pip");
+  }
 }
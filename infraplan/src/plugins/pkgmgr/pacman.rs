@@ -1,10 +1,15 @@
 use crate::utils::process::run_command;
 
-const EXE_PACMAN: &str = "pacman";
+pub const EXE_PACMAN: &str = "pacman";
+pub const ARGS_UPDATE: &[&str] = &["-Sy"];
+pub const ARGS_UPGRADE: &[&str] = &["-Su", "--noconfirm"];
+pub const ARGS_INSTALL: &[&str] = &["-S", "--noconfirm"];
+pub const ARGS_REMOVE: &[&str] = &["-Rns", "--noconfirm"];
+pub const ARGS_QUERY: &[&str] = &["-Qq"];
 
 pub async fn pacman_update() -> anyhow::Result<()> {
   log::info!("Updating package database...");
-  let (code, _, _) = run_command(EXE_PACMAN, &["-Sy"]).await?;
+  let (code, _, _) = run_command(EXE_PACMAN, ARGS_UPDATE).await?;
 
   if code != 0 {
     log::error!("Failed to update package database with exit code: {code}");
@@ -16,7 +21,7 @@ pub async fn pacman_update() -> anyhow::Result<()> {
 
 pub async fn pacman_upgrade() -> anyhow::Result<()> {
   log::info!("Upgrading installed packages...");
-  let (code, _, _) = run_command(EXE_PACMAN, &["-Su", "--noconfirm"]).await?;
+  let (code, _, _) = run_command(EXE_PACMAN, ARGS_UPGRADE).await?;
 
   if code != 0 {
     log::error!("Failed to upgrade packages with exit code: {code}");
@@ -33,7 +38,7 @@ pub async fn pacman_install(packages: &[String]) -> anyhow::Result<()> {
   }
 
   log::info!("Installing packages: {}", packages.join(", "));
-  let args: Vec<&str> = ["-S", "--noconfirm"].into_iter().chain(packages.iter().map(|v| v.as_str())).collect();
+  let args: Vec<&str> = ARGS_INSTALL.iter().copied().chain(packages.iter().map(|v| v.as_str())).collect();
 
   let (code, _, _) = run_command(EXE_PACMAN, &args).await?;
 
@@ -52,7 +57,7 @@ pub async fn pacman_remove(packages: &[String]) -> anyhow::Result<()> {
   }
 
   log::info!("Removing packages: {}", packages.join(", "));
-  let args: Vec<&str> = ["-Rns", "--noconfirm"].into_iter().chain(packages.iter().map(|v| v.as_str())).collect();
+  let args: Vec<&str> = ARGS_REMOVE.iter().copied().chain(packages.iter().map(|v| v.as_str())).collect();
 
   let (code, _, _) = run_command(EXE_PACMAN, &args).await?;
 
@@ -0,0 +1,174 @@
+use std::{os::unix::fs::PermissionsExt, path::Path};
+
+use crate::{
+  plugins::{Distro, State},
+  utils::join_path_string,
+};
+
+const INSTALLED_EXE: &str = "/usr/local/sbin/infraplan";
+const SERVICE_NAME: &str = "infraplan-recover";
+
+/// Write `state` into `new_root` at `state_path` and install a first-boot service that runs `infraplan recover` on it.
+pub fn install_state(new_root: &str, state_path: &str, state: &State, distro: &Option<Distro>) -> anyhow::Result<()> {
+  let target = join_path_string(new_root, state_path.trim_start_matches('/'));
+  log::info!("Moving state to {target}");
+  state.save(&target)?;
+
+  let exe = std::env::current_exe()?;
+  let exe_target = join_path_string(new_root, INSTALLED_EXE.trim_start_matches('/'));
+  log::info!("Copying {} to {exe_target}", exe.display());
+  create_parent_dir(&exe_target)?;
+  std::fs::copy(&exe, &exe_target)?;
+  std::fs::set_permissions(&exe_target, std::fs::Permissions::from_mode(0o755))?;
+
+  let use_openrc = match distro {
+    Some(Distro::Alpine) => true,
+    Some(_) => false,
+    None => Path::new(&join_path_string(new_root, "sbin/openrc-run")).exists(),
+  };
+  if use_openrc {
+    install_openrc_service(new_root, state_path)
+  } else {
+    install_systemd_service(new_root, state_path)
+  }
+}
+
+fn create_parent_dir(path: &str) -> anyhow::Result<()> {
+  let parent = Path::new(path).parent().ok_or(anyhow::anyhow!("Failed to get parent directory"))?;
+  std::fs::create_dir_all(parent)?;
+  Ok(())
+}
+
+fn enable_service(link: &str, target: &str) -> anyhow::Result<()> {
+  create_parent_dir(link)?;
+  if std::fs::symlink_metadata(link).is_ok() {
+    std::fs::remove_file(link)?;
+  }
+  std::os::unix::fs::symlink(target, link)?;
+  Ok(())
+}
+
+fn install_systemd_service(new_root: &str, state_path: &str) -> anyhow::Result<()> {
+  let unit = format!(
+    r#"# Generated by InfraPlan
+[Unit]
+Description=Continue infraplan after reboot
+Wants=network-online.target
+After=network-online.target
+ConditionPathExists={state_path}
+
+[Service]
+Type=oneshot
+RemainAfterExit=yes
+ExecStart={INSTALLED_EXE} recover {state_path}
+ExecStartPost=systemctl disable {SERVICE_NAME}.service
+
+[Install]
+WantedBy=multi-user.target
+"#
+  );
+  let unit_path = format!("/etc/systemd/system/{SERVICE_NAME}.service");
+  let unit_target = join_path_string(new_root, unit_path.trim_start_matches('/'));
+  create_parent_dir(&unit_target)?;
+  std::fs::write(&unit_target, unit)?;
+  log::info!("Wrote systemd unit to {unit_target}");
+
+  enable_service(
+    &join_path_string(
+      new_root,
+      &format!("etc/systemd/system/multi-user.target.wants/{SERVICE_NAME}.service"),
+    ),
+    &unit_path,
+  )
+}
+
+fn install_openrc_service(new_root: &str, state_path: &str) -> anyhow::Result<()> {
+  let script = format!(
+    r#"#!/sbin/openrc-run
+# Generated by InfraPlan
+description="Continue infraplan after reboot"
+
+depend() {{
+  need net
+}}
+
+start() {{
+  ebegin "Continuing infraplan"
+  {INSTALLED_EXE} recover {state_path} && rc-update del {SERVICE_NAME} default
+  eend $?
+}}
+"#
+  );
+  let script_path = format!("/etc/init.d/{SERVICE_NAME}");
+  let script_target = join_path_string(new_root, script_path.trim_start_matches('/'));
+  create_parent_dir(&script_target)?;
+  std::fs::write(&script_target, script)?;
+  std::fs::set_permissions(&script_target, std::fs::Permissions::from_mode(0o755))?;
+  log::info!("Wrote OpenRC service to {script_target}");
+
+  enable_service(
+    &join_path_string(new_root, &format!("etc/runlevels/default/{SERVICE_NAME}")),
+    &script_path,
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::plugins::Config;
+
+  #[test]
+  fn install_state_into_root() {
+    let state = Config::from_yaml(
+      r#"
+recipe:
+  - { id: a, use: shell, with: { script: "true" } }
+"#,
+    )
+    .unwrap()
+    .into_state();
+    let base = std::env::temp_dir().join(format!("infraplan-handoff-{}", std::process::id()));
+
+    let root = base.join("systemd");
+    let new_root = root.to_string_lossy();
+    install_state(
+      &new_root,
+      "/var/lib/infraplan/state.json",
+      &state,
+      &Some(Distro::Debian),
+    )
+    .unwrap();
+    assert_eq!(
+      State::from_path(root.join("var/lib/infraplan/state.json")).unwrap(),
+      state
+    );
+    let exe = root.join(INSTALLED_EXE.trim_start_matches('/'));
+    assert_eq!(std::fs::metadata(&exe).unwrap().permissions().mode() & 0o777, 0o755);
+    let unit = std::fs::read_to_string(root.join("etc/systemd/system/infraplan-recover.service")).unwrap();
+    assert!(unit.contains("ExecStart=/usr/local/sbin/infraplan recover /var/lib/infraplan/state.json"));
+    let wants = root.join("etc/systemd/system/multi-user.target.wants/infraplan-recover.service");
+    assert_eq!(
+      std::fs::read_link(wants).unwrap(),
+      Path::new("/etc/systemd/system/infraplan-recover.service")
+    );
+    assert!(!root.join("etc/init.d").exists());
+
+    // Without a distro, OpenRC is detected from the new root.
+    let root = base.join("openrc");
+    let new_root = root.to_string_lossy();
+    std::fs::create_dir_all(root.join("sbin")).unwrap();
+    std::fs::write(root.join("sbin/openrc-run"), "").unwrap();
+    install_state(&new_root, "/var/lib/infraplan/state.json", &state, &None).unwrap();
+    assert!(root.join("var/lib/infraplan/state.json").exists());
+    let script = root.join("etc/init.d/infraplan-recover");
+    assert!(std::fs::read_to_string(&script).unwrap().contains("recover /var/lib/infraplan/state.json"));
+    assert_eq!(std::fs::metadata(&script).unwrap().permissions().mode() & 0o777, 0o755);
+    assert_eq!(
+      std::fs::read_link(root.join("etc/runlevels/default/infraplan-recover")).unwrap(),
+      Path::new("/etc/init.d/infraplan-recover")
+    );
+    assert!(!root.join("etc/systemd").exists());
+
+    std::fs::remove_dir_all(&base).unwrap();
+  }
+}
@@ -6,15 +6,18 @@ use std::{
   str::FromStr,
 };
 
-use crate::utils::fstab::get_fstab_entries_by_path;
+use crate::{
+  notify::NotifyEvent,
+  utils::{fstab::get_fstab_entries_by_path, join_path_string},
+};
 
-#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize, schemars::JsonSchema)]
 pub struct Config {
   pub linux: Option<String>,
   pub initrd: Option<String>,
   pub root: String,
   pub append: Option<String>,
-  // pub move_state: Option<String>, // TODO: persistent states is not implemented yet
+  pub move_state: Option<String>,
 }
 
 pub struct Context(pub crate::plugins::Globals);
@@ -81,11 +84,57 @@ impl crate::plugins::Plugin for Context {
     log::info!("Loading kernel and initramfs for kexec");
     kexec_file_load(&kernel, &initramfs, kernel_params)?;
 
+    super::before_reboot().await?;
+
+    nix::unistd::sync();
     log::error!("Rebooting system using kexec");
     kexec_reboot()?;
 
     Ok(())
   }
+
+  fn plan(&self, config: &Self::Config, state: &Self::State) -> anyhow::Result<Vec<String>> {
+    if *state {
+      return Ok(vec!["Kexec already invoked, nothing to do".to_string()]);
+    }
+
+    let mut actions = Vec::new();
+    match (&config.linux, &config.initrd) {
+      (Some(linux), Some(initrd)) => actions.push(format!("Load kernel {linux} with initramfs {initrd}")),
+      (None, None) => match find_kernel(&config.root) {
+        Ok(Some((kernel, initramfs))) => actions.push(format!(
+          "Load kernel {} with initramfs {}",
+          kernel.display(),
+          initramfs.display()
+        )),
+        _ => actions.push(format!(
+          "Load kernel and initramfs found in {}/boot at run time",
+          config.root
+        )),
+      },
+      (Some(_), None) => anyhow::bail!("No initramfs specified"),
+      (None, Some(_)) => anyhow::bail!("No kernel specified"),
+    }
+    let root_params =
+      find_kernel_params_root(&config.root).unwrap_or_else(|_| format!("<root from {}/etc/fstab>", config.root));
+    let append = match &config.append {
+      Some(args) => args.clone(),
+      None => find_kernel_params_grub(&config.root)
+        .unwrap_or_else(|_| format!("<kernel parameters from {}/etc/default/grub>", config.root)),
+    };
+    actions.push(format!("Kernel command line: {root_params} {append}"));
+    if let Some(state_path) = &config.move_state {
+      actions.push(format!(
+        "Move state to {} and install a first-boot service running `infraplan recover {state_path}`",
+        join_path_string(&config.root, state_path.trim_start_matches('/'))
+      ));
+    }
+    for target in self.0.notify.iter().flatten().filter(|v| v.wants(NotifyEvent::Reboot)) {
+      actions.push(format!("Notify {} of the reboot", target.url));
+    }
+    actions.push("Reboot into the loaded kernel with kexec".to_string());
+    Ok(actions)
+  }
 }
 
 /// See also: https://docs.kernel.org/admin-guide/kernel-parameters.html
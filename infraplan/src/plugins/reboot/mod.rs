@@ -1,11 +1,66 @@
+use crate::{
+  notify::Notifier,
+  plugins::{Distro, State},
+};
+
+pub mod handoff;
 pub mod kexec;
 
-#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+tokio::task_local! {
+  static BEFORE_REBOOT: BeforeReboot;
+}
+
+/// What the run does once a reboot recipe has loaded the new kernel, right before rebooting into it. Nothing runs
+/// after the reboot, so this is the last chance to save the state and tell anyone.
+#[derive(Clone)]
+pub struct BeforeReboot {
+  pub recipe: String,
+  pub handoff: Option<Handoff>,
+  pub notifier: Notifier,
+}
+
+/// A copy of the state to move into the system the recipe boots, see `handoff::install_state`.
+#[derive(Clone)]
+pub struct Handoff {
+  pub new_root: String,
+  /// Path of the state inside `new_root`.
+  pub state_path: String,
+  pub state: State,
+  pub distro: Option<Distro>,
+}
+
+impl BeforeReboot {
+  /// Run `future`, the invocation of the reboot recipe, with this to do before it reboots, see `before_reboot`.
+  pub async fn scope<F: Future>(self, future: F) -> F::Output { BEFORE_REBOOT.scope(self, future).await }
+}
+
+/// Do what the run needs done before rebooting, see `BeforeReboot`. Outside of a run there is nothing to do.
+pub async fn before_reboot() -> anyhow::Result<()> {
+  let Ok(before) = BEFORE_REBOOT.try_with(Clone::clone) else {
+    return Ok(());
+  };
+  if let Some(handoff) = &before.handoff {
+    handoff::install_state(&handoff.new_root, &handoff.state_path, &handoff.state, &handoff.distro)?;
+  }
+  before.notifier.reboot(&before.recipe).await;
+  Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize, schemars::JsonSchema)]
 #[serde(rename_all = "snake_case", tag = "type")]
 pub enum Config {
   Kexec(kexec::Config),
 }
 
+impl Config {
+  /// The new root and the path (inside it) the state should be moved to before rebooting.
+  pub fn move_state(&self) -> Option<(&str, &str)> {
+    match self {
+      Config::Kexec(inner) => inner.move_state.as_deref().map(|path| (inner.root.as_str(), path)),
+    }
+  }
+}
+
 pub struct Context(pub crate::plugins::Globals);
 
 impl crate::plugins::Plugin for Context {
@@ -17,4 +72,10 @@ impl crate::plugins::Plugin for Context {
       Config::Kexec(inner) => kexec::Context(self.0.clone()).invoke(inner, state).await,
     }
   }
+
+  fn plan(&self, config: &Self::Config, state: &Self::State) -> anyhow::Result<Vec<String>> {
+    match config {
+      Config::Kexec(inner) => kexec::Context(self.0.clone()).plan(inner, state),
+    }
+  }
 }
@@ -0,0 +1,246 @@
+use std::{collections::HashMap, ffi::OsStr, fmt, path::Path};
+
+use crate::utils::{
+  cancel::{Cleanup, CleanupGuard},
+  chroot::prepare_chroot,
+  join_path_string,
+  process::run_command_with,
+};
+
+const DEFAULT_SHELL: &str = "/bin/sh";
+/// Keep at most this many bytes of each captured stream in the state, from the end of the output.
+const MAX_OUTPUT_LEN: usize = 64 * 1024;
+/// Fields of `Config` that hold shell code, left to the shell instead of interpolated, see
+/// `plugins::Config::resolve_vars`.
+pub const CODE_FIELDS: &[&str] = &["script", "commands", "unless"];
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize, schemars::JsonSchema)]
+pub struct Config {
+  /// Inline script, run with `shell -c` before any `commands`. At least one of `script` and `commands` must be set.
+  ///
+  /// `script`, `commands` and `unless` are run as written: `${...}` in them is the shell's, not an infraplan variable.
+  /// Pass variables through `env` instead, e.g. `env: { DISK: "${disk}" }` and `"$DISK"` in the script.
+  pub script: Option<String>,
+  /// Commands run in order without a shell, each given as the program followed by its arguments.
+  pub commands: Option<Vec<Vec<String>>>,
+  /// Shell used for `script` and `unless`. Defaults to `/bin/sh`.
+  pub shell: Option<String>,
+  pub env: Option<HashMap<String, String>>,
+  /// Written to the standard input of the script, or of every command.
+  pub stdin: Option<String>,
+  /// Run inside this root, with `/proc`, `/sys`, `/dev` and friends mounted for the duration of the recipe.
+  pub chroot: Option<String>,
+  /// Exit codes that count as success. Defaults to `[0]`.
+  pub expected_exit_codes: Option<Vec<i32>>,
+  /// Skip the recipe when this path exists, inside `chroot` if set.
+  pub creates: Option<String>,
+  /// Skip the recipe when this shell command exits with 0, inside `chroot` if set.
+  pub unless: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct State {
+  pub done: bool,
+  /// Why the commands were not run, when a guard matched.
+  pub skipped: Option<String>,
+  pub outputs: Vec<Output>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Output {
+  pub command: String,
+  pub exit_code: i32,
+  pub stdout: String,
+  pub stderr: String,
+}
+
+pub struct Context(pub crate::plugins::Globals);
+
+impl crate::plugins::Plugin for Context {
+  type Config = Config;
+  type State = State;
+
+  async fn invoke(&self, config: &Self::Config, state: &mut Self::State) -> anyhow::Result<()> {
+    if state.done {
+      log::info!("Shell commands have already been run.");
+      return Ok(());
+    }
+    state.outputs.clear();
+    state.skipped = None;
+
+    if let Some(creates) = &config.creates {
+      let path = match &config.chroot {
+        Some(root) => join_path_string(root, creates.trim_start_matches('/')),
+        None => creates.clone(),
+      };
+      if Path::new(&path).exists() {
+        log::info!("{path} exists, skipping shell commands");
+        state.skipped = Some(format!("`{creates}` exists"));
+        state.done = true;
+        return Ok(());
+      }
+    }
+
+    // Cleans up the chroot when dropped, also when the attempt times out.
+    let _chroot = match &config.chroot {
+      Some(root) => {
+        prepare_chroot(root)?;
+        Some(CleanupGuard::new(Cleanup::Chroot(root.clone())))
+      }
+      None => None,
+    };
+    self.run(config, state).await
+  }
+
+  fn plan(&self, config: &Self::Config, state: &Self::State) -> anyhow::Result<Vec<String>> {
+    if state.done {
+      return Ok(vec!["Shell commands have already been run, nothing to do".to_string()]);
+    }
+
+    let mut actions = Vec::new();
+    if let Some(creates) = &config.creates {
+      actions.push(format!("Skip if {creates} exists"));
+    }
+    if let Some(root) = &config.chroot {
+      actions.push(format!("Prepare chroot at {root}"));
+    }
+    if let Some(unless) = &config.unless {
+      actions.push(format!("Skip if `{unless}` succeeds"));
+    }
+    let prefix = config.chroot.as_ref().map(|root| format!("chroot {root} ")).unwrap_or_default();
+    if let Some(script) = &config.script {
+      let lines = script.lines().filter(|v| !v.trim().is_empty()).count();
+      actions.push(format!("{prefix}{} -c <script, {lines} line(s)>", shell(config)));
+    }
+    for command in config.commands.iter().flatten() {
+      actions.push(format!("{prefix}{}", command.join(" ")));
+    }
+    if let Some(root) = &config.chroot {
+      actions.push(format!("Clean up chroot at {root}"));
+    }
+    Ok(actions)
+  }
+}
+
+fn shell(config: &Config) -> &str { config.shell.as_deref().unwrap_or(DEFAULT_SHELL) }
+
+/// Keep the end of `output`, which is where errors usually are.
+fn truncate_output(output: String) -> String {
+  if output.len() <= MAX_OUTPUT_LEN {
+    return output;
+  }
+  let mut start = output.len() - MAX_OUTPUT_LEN;
+  while !output.is_char_boundary(start) {
+    start += 1;
+  }
+  format!("[truncated]\n{}", &output[start..])
+}
+
+impl Context {
+  async fn run(&self, config: &Config, state: &mut State) -> anyhow::Result<()> {
+    if let Some(unless) = &config.unless {
+      let (code, _, _) = self.exec(config, shell(config), &["-c", unless], None).await?;
+      if code == 0 {
+        log::info!("`{unless}` succeeded, skipping shell commands");
+        state.skipped = Some(format!("`{unless}` succeeded"));
+        state.done = true;
+        return Ok(());
+      }
+    }
+
+    let mut commands: Vec<(String, Vec<String>)> = Vec::new();
+    if let Some(script) = &config.script {
+      commands.push((
+        format!("{} -c <script>", shell(config)),
+        vec![shell(config).to_string(), "-c".to_string(), script.clone()],
+      ));
+    }
+    commands.extend(config.commands.iter().flatten().map(|v| (v.join(" "), v.clone())));
+
+    let expected = config.expected_exit_codes.clone().unwrap_or_else(|| vec![0]);
+    for (display, command) in commands {
+      let Some((program, args)) = command.split_first() else {
+        anyhow::bail!("Empty command");
+      };
+      let (exit_code, stdout, stderr) = self.exec(config, program, args, config.stdin.as_deref()).await?;
+      state.outputs.push(Output {
+        command: display.clone(),
+        exit_code,
+        stdout: truncate_output(stdout),
+        stderr: truncate_output(stderr.clone()),
+      });
+      if !expected.contains(&exit_code) {
+        anyhow::bail!(
+          "Command `{display}` exited with {exit_code}, expected one of {expected:?}: {}",
+          stderr.trim()
+        );
+      }
+    }
+    state.done = true;
+    Ok(())
+  }
+
+  async fn exec<A: AsRef<OsStr> + fmt::Debug>(
+    &self, config: &Config, program: &str, args: &[A], stdin: Option<&str>,
+  ) -> anyhow::Result<(i32, String, String)> {
+    run_command_with(program, Some(args), config.env.clone(), stdin, config.chroot.clone()).await
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::plugins::{Globals, Plugin};
+
+  fn config(yaml: &str) -> Config { serde_yml::from_str(yaml).unwrap() }
+
+  #[tokio::test]
+  async fn run_shell() {
+    let context = Context(Globals {
+      distro_hint: None,
+      notify: None,
+    });
+    let mut state = State::default();
+    context
+      .invoke(
+        &config(
+          r#"
+script: |
+  echo "$GREETING, $(cat)"
+  echo oops >&2
+  exit 3
+commands:
+  - [echo, done]
+env: { GREETING: Hello }
+stdin: World
+expected_exit_codes: [0, 3]
+"#,
+        ),
+        &mut state,
+      )
+      .await
+      .unwrap();
+    assert!(state.done);
+    assert_eq!(state.outputs.len(), 2);
+    assert_eq!(state.outputs[0].exit_code, 3);
+    assert_eq!(state.outputs[0].stdout, "Hello, World\n");
+    assert_eq!(state.outputs[0].stderr, "oops\n");
+    assert_eq!(state.outputs[1].command, "echo done");
+
+    let mut state = State::default();
+    let result = context.invoke(&config("commands: [[sh, -c, 'exit 1']]"), &mut state).await;
+    assert!(result.is_err());
+    assert!(!state.done);
+    assert_eq!(state.outputs[0].exit_code, 1);
+
+    let mut state = State::default();
+    let guarded = config("unless: 'true'\ncommands: [[sh, -c, 'exit 1']]");
+    context.invoke(&guarded, &mut state).await.unwrap();
+    assert!(state.done);
+    assert!(state.outputs.is_empty());
+
+    let mut state = State::default();
+    context.invoke(&config("creates: /\ncommands: [[false]]"), &mut state).await.unwrap();
+    assert_eq!(state.skipped.as_deref(), Some("`/` exists"));
+  }
+}
@@ -3,14 +3,16 @@ use crate::plugins::Distro;
 pub mod tar;
 mod utils;
 
-#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize, schemars::JsonSchema)]
 pub struct CommonConfig {
   pub disk: String,
   pub mount: String,
-  pub distro: Distro,
+  /// Distro in the tarball, which selects the post-installation steps. Defaults to `distro_hint`, or else is
+  /// detected from the extracted root.
+  pub distro: Option<Distro>,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize, schemars::JsonSchema)]
 #[serde(rename_all = "snake_case", tag = "type")]
 pub enum Config {
   Tar(tar::Config),
@@ -32,4 +34,13 @@ impl crate::plugins::Plugin for Context {
     }
     Ok(())
   }
+
+  fn plan(&self, config: &Self::Config, state: &Self::State) -> anyhow::Result<Vec<String>> {
+    if *state {
+      return Ok(vec!["System is already deployed, nothing to do".to_string()]);
+    }
+    match config {
+      Config::Tar(inner) => tar::Context(self.0.clone()).plan(inner, state),
+    }
+  }
 }
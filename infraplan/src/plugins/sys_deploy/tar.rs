@@ -9,12 +9,19 @@ use futures_util::{Stream, StreamExt};
 use tokio::io::{AsyncBufRead, AsyncRead};
 use tokio_tar::ArchiveBuilder;
 
-use crate::plugins::{
-  Distro,
-  sys_deploy::utils::{postinst, prepare_disk, write_fstab},
+use crate::{
+  plugins::{
+    Distro,
+    sys_deploy::utils::{plan_postinst, plan_prepare_disk, postinst, prepare_disk, write_fstab},
+  },
+  utils::{
+    cancel::{self, Cleanup, CleanupGuard},
+    http_client, join_path_string,
+    metrics::{self, CountingReader},
+  },
 };
 
-#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize, schemars::JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub enum Compression {
   Zstd,
@@ -24,7 +31,7 @@ pub enum Compression {
   Lzma,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize, schemars::JsonSchema)]
 pub struct Config {
   pub url: String,
   pub compression: Option<Compression>,
@@ -42,17 +49,10 @@ impl crate::plugins::Plugin for Context {
   async fn invoke(&self, config: &Self::Config, state: &mut Self::State) -> anyhow::Result<()> {
     log::info!("System Deployer with config: {config:?}; context: {self:?}");
 
-    let (use_mdev, use_udev) = match self.0.distro_hint.as_ref() {
-      Some(Distro::Alpine) => (true, false), // Alpine uses mdev
-      Some(Distro::Arch) | Some(Distro::Debian) | Some(Distro::Fedora) | Some(Distro::Ubuntu) => (false, true), // Arch, Debian, Fedora, and Ubuntu use udev
-      _ => {
-        log::warn!(
-          "Unknown distro hint: {:?}, defaulting to no mdev or udev",
-          self.0.distro_hint
-        );
-        (false, false)
-      } // Unknown or unspecified distro, default to no mdev or udev
-    };
+    let (use_mdev, use_udev) = self.device_managers();
+    // The target stays mounted for the recipes that follow, unless the deployment fails, times out or is cancelled
+    // halfway.
+    let guard = CleanupGuard::new(Cleanup::Unmount(config.common.mount.clone()));
     prepare_disk(
       config.common.disk.as_str(),
       use_mdev,
@@ -61,11 +61,60 @@ impl crate::plugins::Plugin for Context {
     )
     .await?;
     extract_tarball(config.url.as_str(), config.common.mount.as_str(), &config.compression).await?;
+    cancel::check()?;
     write_fstab(config.common.disk.as_str(), config.common.mount.as_str()).await?;
-    postinst(config.common.mount.as_str(), &self.0.distro_hint).await?;
+    let distro = config.common.distro.clone().or_else(|| self.0.distro_for(&config.common.mount));
+    postinst(config.common.mount.as_str(), &distro).await?;
+    guard.dismiss();
     *state = true;
     Ok(())
   }
+
+  fn plan(&self, config: &Self::Config, _state: &Self::State) -> anyhow::Result<Vec<String>> {
+    let (use_mdev, use_udev) = self.device_managers();
+    let mut actions = plan_prepare_disk(
+      config.common.disk.as_str(),
+      use_mdev,
+      use_udev,
+      config.common.mount.as_str(),
+    );
+    let compression = match &config.compression {
+      Some(compression) => format!("{compression:?}").to_lowercase(),
+      None => "no".to_string(),
+    };
+    actions.push(format!(
+      "Stream tarball from {} with {compression} compression into {}",
+      config.url, config.common.mount
+    ));
+    actions.push(format!(
+      "Write fstab for {} to {}",
+      config.common.disk,
+      join_path_string(config.common.mount.as_str(), "etc/fstab")
+    ));
+    match config.common.distro.as_ref().or(self.0.distro_hint.as_ref()) {
+      Some(distro) => actions.extend(plan_postinst(config.common.mount.as_str(), &Some(distro.clone()))),
+      None => actions.push(format!(
+        "Detect the distro from {} and run its post-installation steps",
+        join_path_string(config.common.mount.as_str(), "etc/os-release")
+      )),
+    }
+    Ok(actions)
+  }
+}
+
+impl Context {
+  /// Whether the host uses mdev or udev to populate `/dev`, as `(use_mdev, use_udev)`.
+  fn device_managers(&self) -> (bool, bool) {
+    let distro = self.0.distro_for("/");
+    match distro.as_ref() {
+      Some(Distro::Alpine) => (true, false), // Alpine uses mdev
+      Some(Distro::Arch) | Some(Distro::Debian) | Some(Distro::Fedora) | Some(Distro::Ubuntu) => (false, true), // Arch, Debian, Fedora, and Ubuntu use udev
+      _ => {
+        log::warn!("Unknown distro: {distro:?}, defaulting to no mdev or udev");
+        (false, false)
+      } // Unknown or unspecified distro, default to no mdev or udev
+    }
+  }
 }
 
 struct HttpStream {
@@ -75,7 +124,7 @@ struct HttpStream {
 
 impl HttpStream {
   async fn fetch(url: &str) -> anyhow::Result<Self> {
-    let client = reqwest::Client::new();
+    let client = http_client();
     log::info!("Fetching stream from URL: {url}");
     let stream = client.get(url).send().await?.bytes_stream();
     Ok(HttpStream {
@@ -116,7 +165,7 @@ impl AsyncRead for HttpStream {
 
 enum MaybeRemoteStream {
   Local(tokio::fs::File),
-  Remote(HttpStream),
+  Remote(CountingReader<HttpStream>),
 }
 
 impl AsyncRead for MaybeRemoteStream {
@@ -181,10 +230,13 @@ impl<S: AsyncBufRead + Unpin> AsyncRead for MaybeCompressedStream<S> {
 }
 
 pub(crate) async fn extract_tarball(url: &str, dest: &str, compression: &Option<Compression>) -> anyhow::Result<()> {
-  let backing_stream = if url.starts_with("http://") || url.starts_with("https://") {
+  let remote = url.starts_with("http://") || url.starts_with("https://");
+  let backing_stream = if remote {
     log::info!("Downloading tarball from {url} to {dest}");
     let http_stream = HttpStream::fetch(url).await?;
-    MaybeRemoteStream::Remote(http_stream)
+    MaybeRemoteStream::Remote(
+      CountingReader::new(http_stream, &metrics::DOWNLOADED_BYTES).timed(&metrics::DOWNLOAD_NANOS),
+    )
   } else {
     log::info!("Opening local tarball file: {url}");
     let file = tokio::fs::File::open(url).await?;
@@ -213,7 +265,7 @@ pub(crate) async fn extract_tarball(url: &str, dest: &str, compression: &Option<
     }
     None => MaybeCompressedStream::Plain(tokio::io::BufReader::new(backing_stream)),
   };
-  let mut archive = ArchiveBuilder::new(compressed_stream)
+  let mut archive = ArchiveBuilder::new(CountingReader::new(compressed_stream, &metrics::EXTRACTED_BYTES))
     .set_allow_external_symlinks(true)
     .set_ignore_zeros(false)
     .set_overwrite(true)
@@ -222,6 +274,13 @@ pub(crate) async fn extract_tarball(url: &str, dest: &str, compression: &Option<
     .set_preserve_ownerships(true)
     .set_unpack_xattrs(true)
     .build();
-  archive.unpack(dest).await?;
+  let result = tokio::select! {
+    result = archive.unpack(dest) => result,
+    _ = cancel::cancelled() => {
+      log::warn!("Interrupted while extracting tarball to {dest}");
+      return Err(cancel::Interrupted.into());
+    }
+  };
+  result?;
   Ok(())
 }
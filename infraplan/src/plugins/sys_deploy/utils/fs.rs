@@ -1,6 +1,7 @@
 use crate::utils::{
+  cancel,
   fstab::{find_mountpoint_by_device, is_mountpoint},
-  join_path_string,
+  join_path_string, lock,
   parted_exe::{EXE_PARTED, get_parted_outputs},
   process::run_command,
   syscall::{FsType, mount, unmount_all},
@@ -12,9 +13,8 @@ const EXE_UDEVADM: &str = "udevadm";
 const EXE_MKFS_VFAT: &str = "mkfs.vfat";
 const EXE_MKFS_EXT4: &str = "mkfs.ext4";
 
-pub async fn create_partition_table(disk: &str) -> anyhow::Result<()> {
-  log::debug!("Creating partition table on disk {disk}");
-  let args = vec![
+pub fn create_partition_table_args(disk: &str) -> Vec<&str> {
+  vec![
     disk,       // block device to format
     "--script", // run in script mode
     "--fix",    // fix alignment issues
@@ -24,7 +24,12 @@ pub async fn create_partition_table(disk: &str) -> anyhow::Result<()> {
     "mkpart", "primary", "ext4", "512MiB", "2048MiB", // create a primary partition for boot
     "mkpart", "primary", "ext4", "2048MiB", "100%", // create a primary partition for root
     "set", "1", "esp", "on", // set the first partition as ESP
-  ];
+  ]
+}
+
+pub async fn create_partition_table(disk: &str) -> anyhow::Result<()> {
+  log::debug!("Creating partition table on disk {disk}");
+  let args = create_partition_table_args(disk);
   let (code, _, stderr) = run_command(EXE_PARTED, &args).await?;
   if code != 0 {
     anyhow::bail!("Failed to format disk {disk} with parted: {}", stderr);
@@ -89,6 +94,36 @@ pub async fn format_root_part(part: &str) -> anyhow::Result<()> {
   format_ext4(part, "root", Some(vec!["^orphan_file"])).await
 }
 
+/// Describe what `prepare_disk` would do to `disk`.
+pub fn plan_prepare_disk(disk: &str, use_mdev: bool, use_udev: bool, target: &str) -> Vec<String> {
+  let mut actions = vec![
+    format!("Lock {disk} against other runs"),
+    format!("Unmount everything on {target} and on partitions of {disk}"),
+    format!("{EXE_PARTED} {}", create_partition_table_args(disk).join(" ")),
+    format!("Create partition {disk} #1: EFI system partition, 1MiB - 512MiB, vfat"),
+    format!("Create partition {disk} #2: boot, 512MiB - 2048MiB, ext4"),
+    format!("Create partition {disk} #3: root, 2048MiB - 100%, ext4"),
+    format!("{EXE_PARTPROBE} {disk}"),
+  ];
+  if use_mdev {
+    actions.push(format!("{EXE_MDEV} -s"));
+  }
+  if use_udev {
+    actions.push(format!("{EXE_UDEVADM} trigger --type=all --settle"));
+  }
+  actions.push(format!("{EXE_MKFS_VFAT} -F 32 -n EFI <partition #1>"));
+  actions.push(format!(
+    "{EXE_MKFS_EXT4} -L boot -O ^metadata_csum_seed -O ^orphan_file <partition #2>"
+  ));
+  actions.push(format!("{EXE_MKFS_EXT4} -L root -O ^orphan_file <partition #3>"));
+  actions.push(format!(
+    "Mount partition #3 on {target}, #2 on {}, #1 on {}",
+    join_path_string(target, "boot"),
+    join_path_string(target, "boot/efi")
+  ));
+  actions
+}
+
 pub async fn block_for_disk_ready(disk: &str) -> anyhow::Result<()> {
   log::debug!("Blocking for disk {disk} to be ready");
   loop {
@@ -98,9 +133,9 @@ pub async fn block_for_disk_ready(disk: &str) -> anyhow::Result<()> {
     }
     tokio::select! {
       _ = tokio::time::sleep(tokio::time::Duration::from_millis(1000)) => {}
-      _ = tokio::signal::ctrl_c() => {
+      _ = cancel::cancelled() => {
         log::warn!("Interrupted while waiting for disk {disk} to be ready");
-        return Err(anyhow::anyhow!("Interrupted while waiting for disk {disk} to be ready"));
+        return Err(cancel::Interrupted.into());
       }
     }
   }
@@ -108,6 +143,7 @@ pub async fn block_for_disk_ready(disk: &str) -> anyhow::Result<()> {
 }
 
 pub async fn prepare_disk(disk: &str, use_mdev: bool, use_udev: bool, target: &str) -> anyhow::Result<()> {
+  let _lock = lock::lock_disk(disk)?;
   if is_mountpoint(target)? {
     unmount_all(target)?;
   }
@@ -1,13 +1,15 @@
 use crate::{
   plugins::sys_deploy::Distro,
   utils::{
-    chroot::{cleanup_chroot, prepare_chroot},
+    cancel::{Cleanup, CleanupGuard},
+    chroot::prepare_chroot,
     process::run_command_with_chroot,
   },
 };
 
 pub async fn postinst(mountpoint: &str, distro: &Option<Distro>) -> anyhow::Result<()> {
   prepare_chroot(mountpoint)?;
+  let _chroot = CleanupGuard::new(Cleanup::Chroot(mountpoint.to_string()));
   match distro {
     Some(Distro::Ubuntu) => postinst_ubuntu(mountpoint).await?,
     _ => {
@@ -15,7 +17,6 @@ pub async fn postinst(mountpoint: &str, distro: &Option<Distro>) -> anyhow::Resu
       log::warn!("No post-installation steps defined for distro: {distro:?}");
     }
   }
-  cleanup_chroot(mountpoint)?;
   Ok(())
 }
 
@@ -23,9 +24,27 @@ const EXE_UPDATE_INITRAMFS: &str = "update-initramfs";
 const EXE_GRUB_INSTALL: &str = "grub-install";
 const EXE_UPDATE_GRUB: &str = "update-grub";
 
+const POSTINST_UBUNTU: &[(&str, &[&str])] = &[
+  (EXE_UPDATE_INITRAMFS, &["-c", "-k", "all"]),
+  (EXE_GRUB_INSTALL, &["--efi-directory=/boot/efi", "--recheck"]),
+  (EXE_UPDATE_GRUB, &[]),
+];
+
+/// Describe what `postinst` would run inside `mountpoint`.
+pub fn plan_postinst(mountpoint: &str, distro: &Option<Distro>) -> Vec<String> {
+  let commands = match distro {
+    Some(Distro::Ubuntu) => POSTINST_UBUNTU,
+    _ => return vec![format!("No post-installation steps defined for distro: {distro:?}")],
+  };
+  commands
+    .iter()
+    .map(|(command, args)| format!("chroot {mountpoint} {command} {}", args.join(" ")).trim_end().to_string())
+    .collect()
+}
+
 async fn postinst_ubuntu(new_root: &str) -> anyhow::Result<()> {
-  run_command_with_chroot(EXE_UPDATE_INITRAMFS, &["-c", "-k", "all"], new_root).await?;
-  run_command_with_chroot(EXE_GRUB_INSTALL, &["--efi-directory=/boot/efi", "--recheck"], new_root).await?;
-  run_command_with_chroot(EXE_UPDATE_GRUB, &[], new_root).await?;
+  for (command, args) in POSTINST_UBUNTU {
+    run_command_with_chroot(command, args, new_root).await?;
+  }
   Ok(())
 }
@@ -1,6 +1,6 @@
 use std::{path::PathBuf, str::FromStr};
 
-#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize, schemars::JsonSchema)]
 pub struct ConfigItem {
   pub overwrite: Option<bool>,
   pub name: Option<String>,
@@ -27,25 +27,73 @@ impl crate::plugins::Plugin for Context {
     }
 
     log::info!("Configuring APT repositories...");
-    let config_dir = PathBuf::from_str(self.chroot.as_deref().unwrap_or("/"))?.join("etc/apt");
-
     for item in config {
-      let repo_file = match &item.name {
-        Some(name) => format!("sources.list.d/{name}.list"),
-        None => "sources.list".to_string(),
-      };
-      let file_path = config_dir.join(repo_file);
+      let file_path = self.repo_file(item)?;
       if file_path.exists() && !item.overwrite.unwrap_or(false) {
         log::info!("Skipping existing file {}", file_path.display());
         continue;
       }
-
-      let content = format!("deb {} {} {}\n", item.base_url, item.distro, item.components.join(" "));
-      std::fs::write(&file_path, content)?;
+      std::fs::write(&file_path, repo_content(item))?;
     }
 
     *state = true;
     log::info!("APT repositories configured successfully");
     Ok(())
   }
+
+  fn plan(&self, config: &Self::Config, state: &Self::State) -> anyhow::Result<Vec<String>> {
+    if *state {
+      return Ok(vec![
+        "APT repositories are already configured, nothing to do".to_string(),
+      ]);
+    }
+
+    let mut actions = Vec::new();
+    for item in config {
+      let file_path = self.repo_file(item)?;
+      if file_path.exists() && !item.overwrite.unwrap_or(false) {
+        actions.push(format!("Skip existing file {}", file_path.display()));
+      } else {
+        actions.push(format!(
+          "Write {}: {}",
+          file_path.display(),
+          repo_content(item).trim_end()
+        ));
+      }
+    }
+    Ok(actions)
+  }
+
+  /// Missing repository files drifted, and so do files whose content differs if they are to be overwritten; other
+  /// files are left alone by `invoke`, so their content does not count.
+  async fn check(&self, config: &Self::Config, state: &mut Self::State) -> anyhow::Result<Option<Vec<String>>> {
+    let mut drift = Vec::new();
+    for item in config {
+      let file_path = self.repo_file(item)?;
+      if !file_path.exists() {
+        drift.push(format!("{} is missing", file_path.display()));
+      } else if item.overwrite.unwrap_or(false) && std::fs::read_to_string(&file_path)? != repo_content(item) {
+        drift.push(format!("{} differs from the configuration", file_path.display()));
+      }
+    }
+    if !drift.is_empty() {
+      *state = false;
+    }
+    Ok(Some(drift))
+  }
+}
+
+impl Context {
+  fn repo_file(&self, item: &ConfigItem) -> anyhow::Result<PathBuf> {
+    let config_dir = PathBuf::from_str(self.chroot.as_deref().unwrap_or("/"))?.join("etc/apt");
+    let repo_file = match &item.name {
+      Some(name) => format!("sources.list.d/{name}.list"),
+      None => "sources.list".to_string(),
+    };
+    Ok(config_dir.join(repo_file))
+  }
+}
+
+fn repo_content(item: &ConfigItem) -> String {
+  format!("deb {} {} {}\n", item.base_url, item.distro, item.components.join(" "))
 }
@@ -1,8 +1,10 @@
+use crate::utils::cancel;
+
 pub mod apt_repo;
 pub mod netplan;
 pub mod user;
 
-#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize, schemars::JsonSchema)]
 #[serde(rename_all = "snake_case", tag = "use", content = "with")]
 pub enum ConfigItem {
   Netplan(netplan::Config),
@@ -10,7 +12,7 @@ pub enum ConfigItem {
   AptRepo(apt_repo::Config),
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize, schemars::JsonSchema)]
 pub struct Config {
   pub chroot: Option<String>,
   pub with: Vec<ConfigItem>,
@@ -22,17 +24,17 @@ impl crate::plugins::Plugin for Context {
   type State = Vec<bool>;
 
   async fn invoke(&self, configs: &Self::Config, state: &mut Self::State) -> anyhow::Result<()> {
-    let mut new_state = Vec::with_capacity(configs.with.len());
-    for item in &configs.with {
-      let mut state_i = state.pop().unwrap_or(false);
+    state.resize(configs.with.len(), false);
+    for (item, state_i) in configs.with.iter().zip(state.iter_mut()) {
+      cancel::check()?;
       match item {
-        ConfigItem::Netplan(config) => netplan::Context(self.0.clone()).invoke(config, &mut state_i).await?,
+        ConfigItem::Netplan(config) => netplan::Context(self.0.clone()).invoke(config, state_i).await?,
         ConfigItem::User(config) => {
           user::Context {
             globals: self.0.clone(),
             chroot: configs.chroot.clone(),
           }
-          .invoke(config, &mut state_i)
+          .invoke(config, state_i)
           .await?
         }
         ConfigItem::AptRepo(config) => {
@@ -40,13 +42,81 @@ impl crate::plugins::Plugin for Context {
             globals: self.0.clone(),
             chroot: configs.chroot.clone(),
           }
-          .invoke(config, &mut state_i)
+          .invoke(config, state_i)
           .await?
         }
       }
-      new_state.push(state_i);
     }
-    *state = new_state;
     Ok(())
   }
+
+  fn plan(&self, configs: &Self::Config, state: &Self::State) -> anyhow::Result<Vec<String>> {
+    let mut actions = Vec::new();
+    for (i, item) in configs.with.iter().enumerate() {
+      let state_i = state.get(i).copied().unwrap_or(false);
+      let item_actions = match item {
+        ConfigItem::Netplan(config) => netplan::Context(self.0.clone()).plan(config, &state_i)?,
+        ConfigItem::User(config) => user::Context {
+          globals: self.0.clone(),
+          chroot: configs.chroot.clone(),
+        }
+        .plan(config, &state_i)?,
+        ConfigItem::AptRepo(config) => apt_repo::Context {
+          globals: self.0.clone(),
+          chroot: configs.chroot.clone(),
+        }
+        .plan(config, &state_i)?,
+      };
+      actions.extend(item_actions);
+    }
+    Ok(actions)
+  }
+
+  /// The drift of the items. When none drifted but some cannot be checked, such as netplan, the recipe cannot be told
+  /// in sync either.
+  async fn check(&self, configs: &Self::Config, state: &mut Self::State) -> anyhow::Result<Option<Vec<String>>> {
+    state.resize(configs.with.len(), false);
+    let mut drift = Vec::new();
+    let mut unchecked = false;
+    for (item, state_i) in configs.with.iter().zip(state.iter_mut()) {
+      let item_drift = match item {
+        ConfigItem::Netplan(config) => netplan::Context(self.0.clone()).check(config, state_i).await?,
+        ConfigItem::User(config) => {
+          user::Context {
+            globals: self.0.clone(),
+            chroot: configs.chroot.clone(),
+          }
+          .check(config, state_i)
+          .await?
+        }
+        ConfigItem::AptRepo(config) => {
+          apt_repo::Context {
+            globals: self.0.clone(),
+            chroot: configs.chroot.clone(),
+          }
+          .check(config, state_i)
+          .await?
+        }
+      };
+      match item_drift {
+        Some(item_drift) => drift.extend(item_drift),
+        None => unchecked = true,
+      }
+    }
+    Ok((!unchecked || !drift.is_empty()).then_some(drift))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::plugins::{Globals, Plugin};
+
+  #[tokio::test]
+  async fn check_netplan() {
+    let config: Config = serde_yml::from_str("with: [{ use: netplan, with: [] }]").unwrap();
+    let mut state = Vec::new();
+    let drift = Context(Globals::default()).check(&config, &mut state).await.unwrap();
+    assert_eq!(drift, None);
+  }
 }
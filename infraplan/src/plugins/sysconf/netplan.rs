@@ -1,4 +1,4 @@
-#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize, schemars::JsonSchema)]
 pub struct ConfigItem {
   // #[serde(rename = "type")]
   // pub type_: String,
@@ -19,4 +19,10 @@ impl crate::plugins::Plugin for Context {
     // TODO: implement logic here
     Ok(())
   }
+
+  fn plan(&self, _config: &Self::Config, _state: &Self::State) -> anyhow::Result<Vec<String>> {
+    Ok(vec![
+      "Netplan configuration is not implemented yet, nothing to do".to_string(),
+    ])
+  }
 }
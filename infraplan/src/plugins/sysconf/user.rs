@@ -1,6 +1,11 @@
+use std::{
+  collections::{HashMap, HashSet},
+  path::Path,
+};
+
 use crate::utils::process::{run_command, run_command_with_chroot, run_command_with_input};
 
-#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize, schemars::JsonSchema)]
 pub struct ConfigItem {
   pub name: String,
   pub password: Option<String>,
@@ -14,8 +19,57 @@ pub struct Context {
 }
 
 const EXE_USERADD: &str = "useradd";
+const EXE_USERMOD: &str = "usermod";
 const EXE_CHPASSWD: &str = "chpasswd";
 
+/// The users of a system and the supplementary groups they are in, from `/etc/passwd` and `/etc/group`.
+#[derive(Debug, Default)]
+struct Accounts {
+  users: HashSet<String>,
+  /// Members by group.
+  groups: HashMap<String, HashSet<String>>,
+}
+
+impl Accounts {
+  /// Read the accounts of the system at `root`. Missing files are read as empty.
+  fn read(root: &str) -> anyhow::Result<Self> {
+    let read = |name: &str| {
+      let path = Path::new(root).join("etc").join(name);
+      match std::fs::read_to_string(&path) {
+        Ok(content) => Ok(content),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(String::new()),
+        Err(e) => Err(anyhow::anyhow!("Failed to read {}: {e}", path.display())),
+      }
+    };
+    let users = read("passwd")?
+      .lines()
+      .filter_map(|v| v.split(':').next())
+      .filter(|v| !v.is_empty())
+      .map(str::to_string)
+      .collect();
+    let groups = read("group")?
+      .lines()
+      .filter_map(|line| {
+        let fields: Vec<&str> = line.split(':').collect();
+        let members = fields.get(3)?.split(',').filter(|v| !v.is_empty()).map(str::to_string).collect();
+        Some((fields[0].to_string(), members))
+      })
+      .collect();
+    Ok(Accounts { users, groups })
+  }
+
+  /// The groups of `item` its user is not a member of.
+  fn missing_groups<'a>(&self, item: &'a ConfigItem) -> Vec<&'a str> {
+    item
+      .groups
+      .iter()
+      .flatten()
+      .filter(|group| !self.groups.get(group.as_str()).is_some_and(|v| v.contains(&item.name)))
+      .map(String::as_str)
+      .collect()
+  }
+}
+
 impl crate::plugins::Plugin for Context {
   type Config = Config;
   type State = bool;
@@ -28,37 +82,26 @@ impl crate::plugins::Plugin for Context {
 
     log::info!("Applying user configuration...");
 
+    let accounts = Accounts::read(self.root())?;
     for item in config {
       if item.name == "root" {
         log::debug!("Skipping root user configuration");
         continue;
       }
-      log::info!("Configuring user: {}", item.name);
-      let mut useradd_args: Vec<&str> = vec![
-        item.name.as_str(),
-        "-m", // Create home directory
-        "-s",
-        "/bin/bash", // Default shell
-      ];
-
-      if let Some(groups) = &item.groups {
-        for group in groups {
-          useradd_args.push("-G"); // Add supplementary groups
-          useradd_args.push(group.as_str());
+      if accounts.users.contains(&item.name) {
+        // Users are only created once, but a user left out of its groups is added to them again.
+        let missing = accounts.missing_groups(item);
+        if missing.is_empty() {
+          log::info!("User {} already exists", item.name);
+          continue;
         }
+        log::info!("Adding user {} to groups: {}", item.name, missing.join(", "));
+        let groups = missing.join(",");
+        self.run(EXE_USERMOD, &["-a", "-G", groups.as_str(), item.name.as_str()]).await?;
+        continue;
       }
-
-      if let Some(new_root) = &self.chroot {
-        // useradd_args.push("--root"); // Chroot before add user
-        // useradd_args.push(new_root.as_str());
-        // run_command_with_root(EXE_USERADD, &useradd_args, &new_root).await?;
-
-        // In some distros (e.g. Fedora), the useradd command has compatibility issues with the distro in new root.
-        // Simply tricks on linker may not works
-        run_command_with_chroot(EXE_USERADD, &useradd_args, new_root).await?;
-      } else {
-        run_command(EXE_USERADD, &useradd_args).await?;
-      }
+      log::info!("Configuring user: {}", item.name);
+      self.run(EXE_USERADD, &useradd_args(item)).await?;
     }
 
     let mut reset_passwd = Vec::new();
@@ -80,4 +123,136 @@ impl crate::plugins::Plugin for Context {
     log::info!("User configuration applied successfully.");
     Ok(())
   }
+
+  fn plan(&self, config: &Self::Config, state: &Self::State) -> anyhow::Result<Vec<String>> {
+    if *state {
+      return Ok(vec!["User configuration is already applied, nothing to do".to_string()]);
+    }
+
+    let chroot = match &self.chroot {
+      Some(new_root) => format!("chroot {new_root} "),
+      None => String::new(),
+    };
+    let mut actions: Vec<String> = config
+      .iter()
+      .filter(|item| item.name != "root")
+      .map(|item| format!("{chroot}{EXE_USERADD} {}", useradd_args(item).join(" ")))
+      .collect();
+    let users: Vec<&str> =
+      config.iter().filter(|item| item.password.is_some()).map(|item| item.name.as_str()).collect();
+    if !users.is_empty() {
+      let root = match &self.chroot {
+        Some(new_root) => format!(" --root {new_root}"),
+        None => String::new(),
+      };
+      actions.push(format!("{EXE_CHPASSWD}{root}: set passwords for {}", users.join(", ")));
+    }
+    Ok(actions)
+  }
+
+  async fn check(&self, config: &Self::Config, state: &mut Self::State) -> anyhow::Result<Option<Vec<String>>> {
+    let accounts = Accounts::read(self.root())?;
+    let mut drift = Vec::new();
+    for item in config.iter().filter(|v| v.name != "root") {
+      if !accounts.users.contains(&item.name) {
+        drift.push(format!("User {} does not exist", item.name));
+        continue;
+      }
+      let missing = accounts.missing_groups(item);
+      if !missing.is_empty() {
+        drift.push(format!("User {} is not in groups: {}", item.name, missing.join(", ")));
+      }
+    }
+    if !drift.is_empty() {
+      *state = false;
+    }
+    Ok(Some(drift))
+  }
+}
+
+impl Context {
+  fn root(&self) -> &str { self.chroot.as_deref().unwrap_or("/") }
+
+  async fn run(&self, exe: &str, args: &[&str]) -> anyhow::Result<()> {
+    if let Some(new_root) = &self.chroot {
+      // useradd_args.push("--root"); // Chroot before add user
+      // useradd_args.push(new_root.as_str());
+      // run_command_with_root(EXE_USERADD, &useradd_args, &new_root).await?;
+
+      // In some distros (e.g. Fedora), the useradd command has compatibility issues with the distro in new root.
+      // Simply tricks on linker may not works
+      run_command_with_chroot(exe, args, new_root).await?;
+    } else {
+      run_command(exe, args).await?;
+    }
+    Ok(())
+  }
+}
+
+fn useradd_args(item: &ConfigItem) -> Vec<&str> {
+  let mut args: Vec<&str> = vec![
+    item.name.as_str(),
+    "-m", // Create home directory
+    "-s",
+    "/bin/bash", // Default shell
+  ];
+
+  if let Some(groups) = &item.groups {
+    for group in groups {
+      args.push("-G"); // Add supplementary groups
+      args.push(group.as_str());
+    }
+  }
+  args
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::plugins::{Globals, Plugin};
+
+  #[tokio::test]
+  async fn check_users() {
+    let root = std::env::temp_dir().join(format!("infraplan-users-{}", std::process::id()));
+    std::fs::create_dir_all(root.join("etc")).unwrap();
+    std::fs::write(
+      root.join("etc/passwd"),
+      "root:x:0:0:root:/root:/bin/bash\nalice:x:1000:1000::/home/alice:/bin/bash\n",
+    )
+    .unwrap();
+    std::fs::write(
+      root.join("etc/group"),
+      "sudo:x:27:alice\ndocker:x:998:\nalice:x:1000:\n",
+    )
+    .unwrap();
+    let context = Context {
+      globals: Globals {
+        distro_hint: None,
+        notify: None,
+      },
+      chroot: Some(root.to_string_lossy().to_string()),
+    };
+    let user = |name: &str, groups: &[&str]| ConfigItem {
+      name: name.to_string(),
+      password: None,
+      groups: Some(groups.iter().map(|v| v.to_string()).collect()),
+    };
+
+    let mut state = true;
+    let drift = context.check(&vec![user("root", &["wheel"]), user("alice", &["sudo"])], &mut state).await.unwrap();
+    assert_eq!(drift, Some(vec![]));
+    assert!(state);
+
+    let config = vec![user("alice", &["sudo", "docker"]), user("bob", &[])];
+    let drift = context.check(&config, &mut state).await.unwrap();
+    assert_eq!(
+      drift,
+      Some(vec![
+        "User alice is not in groups: docker".to_string(),
+        "User bob does not exist".to_string()
+      ])
+    );
+    assert!(!state);
+    std::fs::remove_dir_all(root).unwrap();
+  }
 }
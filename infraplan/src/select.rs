@@ -0,0 +1,131 @@
+//! Selecting which recipes of a configuration run, from the command line.
+
+use crate::plugins::RecipeConfig;
+
+/// Recipe selectors. A recipe runs only if every selector that is set lets it through; the others are skipped.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Selection {
+  /// Run only these recipes.
+  pub only: Vec<String>,
+  /// Never run these recipes.
+  pub skip: Vec<String>,
+  /// Skip the recipes listed before this one.
+  pub from: Option<String>,
+  /// Skip the recipes listed after this one.
+  pub until: Option<String>,
+  /// Run only recipes with at least one of these tags.
+  pub tags: Vec<String>,
+  /// Never run recipes with any of these tags.
+  pub skip_tags: Vec<String>,
+}
+
+impl Selection {
+  pub fn is_empty(&self) -> bool { self == &Selection::default() }
+
+  /// Check that every recipe id the selectors name exists in `recipes`.
+  pub fn check(&self, recipes: &[RecipeConfig]) -> anyhow::Result<()> {
+    let ids = self.only.iter().chain(&self.skip).chain(&self.from).chain(&self.until);
+    for id in ids {
+      if !recipes.iter().any(|r| &r.id == id) {
+        anyhow::bail!("Unknown recipe '{id}' in recipe selection");
+      }
+    }
+    Ok(())
+  }
+
+  /// Why the recipe with `id` is not selected, or `None` if it is selected. Recipes that are not part of `recipes`
+  /// are always selected.
+  pub fn skip_reason(&self, recipes: &[RecipeConfig], id: &str) -> Option<String> {
+    let position = |id: &str| recipes.iter().position(|r| r.id == id);
+    let index = position(id)?;
+    let recipe = &recipes[index];
+    let tags = recipe.tags.as_deref().unwrap_or_default();
+
+    if !self.only.is_empty() && !self.only.iter().any(|v| v == id) {
+      return Some("Not selected by --only".to_string());
+    }
+    if self.skip.iter().any(|v| v == id) {
+      return Some("Excluded by --skip".to_string());
+    }
+    if let Some(from) = &self.from &&
+      position(from).is_some_and(|from| index < from)
+    {
+      return Some(format!("Listed before --from {from}"));
+    }
+    if let Some(until) = &self.until &&
+      position(until).is_some_and(|until| index > until)
+    {
+      return Some(format!("Listed after --until {until}"));
+    }
+    if !self.tags.is_empty() && !tags.iter().any(|v| self.tags.contains(v)) {
+      return Some(format!("Not tagged with any of --tags {}", self.tags.join(",")));
+    }
+    if let Some(tag) = tags.iter().find(|v| self.skip_tags.contains(v)) {
+      return Some(format!("Tagged {tag}, excluded by --skip-tags"));
+    }
+    None
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::plugins::Config;
+
+  #[test]
+  fn select_recipes() {
+    let config = Config::from_yaml(
+      r#"
+recipe:
+  - { id: deploy, use: shell, with: { script: "true" }, tags: [disk] }
+  - { id: sysconf, use: shell, with: { script: "true" }, tags: [config] }
+  - { id: packages, use: shell, with: { script: "true" }, tags: [config, network] }
+  - { id: reboot, use: shell, with: { script: "true" } }
+"#,
+    )
+    .unwrap();
+    let selected = |selection: &Selection| -> Vec<&str> {
+      config
+        .recipe
+        .iter()
+        .filter(|r| selection.skip_reason(&config.recipe, &r.id).is_none())
+        .map(|r| r.id.as_str())
+        .collect()
+    };
+
+    assert_eq!(selected(&Selection::default()).len(), 4);
+    let only = Selection {
+      only: vec!["sysconf".to_string()],
+      ..Default::default()
+    };
+    assert_eq!(selected(&only), vec!["sysconf"]);
+    assert_eq!(
+      only.skip_reason(&config.recipe, "deploy").as_deref(),
+      Some("Not selected by --only")
+    );
+    let range = Selection {
+      from: Some("sysconf".to_string()),
+      until: Some("packages".to_string()),
+      ..Default::default()
+    };
+    assert_eq!(selected(&range), vec!["sysconf", "packages"]);
+    let tags = Selection {
+      tags: vec!["config".to_string()],
+      skip_tags: vec!["network".to_string()],
+      ..Default::default()
+    };
+    assert_eq!(selected(&tags), vec!["sysconf"]);
+    let skip = Selection {
+      skip: vec!["reboot".to_string()],
+      ..Default::default()
+    };
+    assert_eq!(selected(&skip), vec!["deploy", "sysconf", "packages"]);
+
+    assert!(range.check(&config.recipe).is_ok());
+    let unknown = Selection {
+      until: Some("missing".to_string()),
+      ..Default::default()
+    };
+    assert!(unknown.check(&config.recipe).is_err());
+  }
+}
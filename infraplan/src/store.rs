@@ -0,0 +1,32 @@
+//! Where the state of a run is kept, so that an interrupted run can be resumed.
+//!
+//! Runs save their state after every recipe. Unless they are given a store, they save it to the configuration's
+//! `state_path`; programs embedding infraplan can keep it elsewhere by implementing `StateStore`.
+
+use std::path::Path;
+
+use crate::plugins::State;
+
+pub trait StateStore: Send + Sync {
+  /// The saved state, or `None` if nothing was saved yet.
+  fn load(&self) -> anyhow::Result<Option<State>>;
+
+  /// Save `state`. It includes the notify targets, whose headers often carry credentials: `FileStore` keeps them in a
+  /// separate file, see `notify::Targets`, and `State::take_notify` removes them.
+  fn save(&self, state: &State) -> anyhow::Result<()>;
+}
+
+/// Keeps the state in a JSON file, like `state_path`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileStore(pub String);
+
+impl StateStore for FileStore {
+  fn load(&self) -> anyhow::Result<Option<State>> {
+    if !Path::new(&self.0).exists() {
+      return Ok(None);
+    }
+    State::from_path(&self.0).map(Some)
+  }
+
+  fn save(&self, state: &State) -> anyhow::Result<()> { state.save(&self.0) }
+}
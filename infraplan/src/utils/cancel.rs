@@ -0,0 +1,119 @@
+//! Cancellation of runs, and of the whole process on SIGINT and SIGTERM.
+//!
+//! Every run has a token of its own, by default a child of the process-wide token that signals cancel, see
+//! `run_token`. Recipes run in the `scope` of their run's token, and long-running work observes it through `token()`
+//! (or calls `check()` between steps); outside of a run these fall back to the process-wide token. Work that leaves
+//! the system in a temporary state, such as a prepared chroot, holds a `CleanupGuard` that undoes it when dropped.
+//! Guards are dropped along with the work when it fails, is cancelled or times out, most recent first.
+
+use std::{fmt, sync::LazyLock};
+
+use tokio::signal::unix::{SignalKind, signal};
+use tokio_util::sync::CancellationToken;
+
+use crate::utils::{chroot::cleanup_chroot, syscall::unmount_all};
+
+/// Exit code used when a run is interrupted by a signal.
+pub const EXIT_INTERRUPTED: i32 = 130;
+
+static TOKEN: LazyLock<CancellationToken> = LazyLock::new(CancellationToken::new);
+
+tokio::task_local! {
+  static RUN: CancellationToken;
+}
+
+/// The error returned by work that stopped because the run was cancelled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Interrupted;
+
+impl fmt::Display for Interrupted {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result { write!(f, "Interrupted") }
+}
+
+impl std::error::Error for Interrupted {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Cleanup {
+  /// Unmount what `prepare_chroot` mounted in the directory.
+  Chroot(String),
+  /// Unmount everything mounted on or below the directory.
+  Unmount(String),
+}
+
+impl Cleanup {
+  fn run(&self) {
+    let result = match self {
+      Cleanup::Chroot(target) => cleanup_chroot(target),
+      Cleanup::Unmount(target) => unmount_all(target),
+    };
+    if let Err(e) = result {
+      log::warn!("Failed to clean up {self:?}: {e}");
+    }
+  }
+}
+
+/// Runs a cleanup when it is dropped, unless it was dismissed because the temporary state is meant to stay.
+pub struct CleanupGuard(Option<Cleanup>);
+
+impl CleanupGuard {
+  pub fn new(cleanup: Cleanup) -> Self { CleanupGuard(Some(cleanup)) }
+
+  pub fn dismiss(mut self) { self.0 = None; }
+}
+
+impl Drop for CleanupGuard {
+  fn drop(&mut self) {
+    if let Some(cleanup) = self.0.take() {
+      log::info!("Cleaning up {cleanup:?}");
+      cleanup.run();
+    }
+  }
+}
+
+/// A token for a new run, cancelled along with the process.
+pub fn run_token() -> CancellationToken { TOKEN.child_token() }
+
+/// Run `future` as part of the run `token` cancels.
+pub async fn scope<F: Future>(token: CancellationToken, future: F) -> F::Output { RUN.scope(token, future).await }
+
+/// The token of the current run, or the process-wide one outside of a run.
+pub fn token() -> CancellationToken { RUN.try_with(CancellationToken::clone).unwrap_or_else(|_| TOKEN.clone()) }
+
+pub fn is_cancelled() -> bool { token().is_cancelled() }
+
+/// Wait until the run is cancelled.
+pub async fn cancelled() {
+  let token = token();
+  token.cancelled().await
+}
+
+/// Fail with `Interrupted` if the run has been cancelled.
+pub fn check() -> anyhow::Result<()> {
+  if is_cancelled() {
+    return Err(Interrupted.into());
+  }
+  Ok(())
+}
+
+/// Cancel the token on the first SIGINT or SIGTERM. A second signal exits immediately.
+pub fn listen_for_signals() -> anyhow::Result<()> {
+  let mut sigint = signal(SignalKind::interrupt())?;
+  let mut sigterm = signal(SignalKind::terminate())?;
+  tokio::spawn(async move {
+    let mut received = false;
+    loop {
+      tokio::select! {
+        _ = sigint.recv() => {}
+        _ = sigterm.recv() => {}
+      }
+      if received {
+        log::error!("Received a second signal, exiting without cleaning up");
+        std::process::exit(EXIT_INTERRUPTED);
+      }
+      received = true;
+      log::warn!("Received a signal, cancelling the run");
+      TOKEN.cancel();
+    }
+  });
+  Ok(())
+}
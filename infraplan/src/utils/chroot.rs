@@ -1,3 +1,5 @@
+use std::path::Path;
+
 use crate::utils::{
   join_path_string,
   syscall::{FsType, mount, unmount},
@@ -16,7 +18,13 @@ pub fn prepare_chroot(target: &str) -> anyhow::Result<()> {
     ("sys/firmware/efi", FsType::Efivarfs),
   ];
   for (path, fstype) in mounts {
-    mount(None, join_path_string(target, path).as_str(), Some(fstype), false)?;
+    if path == "sys/firmware/efi" && !has_efi_vars() {
+      continue;
+    }
+    if let Err(e) = mount(None, join_path_string(target, path).as_str(), Some(fstype), false) {
+      cleanup_chroot(target)?;
+      return Err(e);
+    }
   }
   Ok(())
 }
@@ -25,6 +33,9 @@ pub fn cleanup_chroot(target: &str) -> anyhow::Result<()> {
   log::info!("Cleaning up chroot environment at {target}");
   let mounts = ["sys/firmware/efi", "dev/shm", "dev/pts", "dev", "sys", "proc", "run", "tmp"];
   for mount in mounts {
+    if mount == "sys/firmware/efi" && !has_efi_vars() {
+      continue;
+    }
     let path = join_path_string(target, mount);
     if let Err(e) = unmount(&path) {
       log::warn!("Failed to unmount {path}: {e}");
@@ -32,3 +43,6 @@ pub fn cleanup_chroot(target: &str) -> anyhow::Result<()> {
   }
   Ok(())
 }
+
+/// Only hosts booted with EFI have EFI variables to mount.
+fn has_efi_vars() -> bool { Path::new("/sys/firmware/efi").exists() }
@@ -0,0 +1,386 @@
+//! A small expression language for recipe conditions, evaluated against a JSON context.
+//!
+//! ```text
+//! expr    := and ( "||" and )*
+//! and     := unary ( "&&" unary )*
+//! unary   := "!" unary | compare
+//! compare := primary ( ( "==" | "!=" | "<" | "<=" | ">" | ">=" | "=~" | "in" ) primary )?
+//! primary := string | number | "true" | "false" | "null" | path | "(" expr ")" | "[" ( expr ( "," expr )* )? "]"
+//! path    := ident ( "." ident )*
+//! ```
+//!
+//! A path looks up a value in the context; missing values are `null`. Path segments that are numbers index into arrays,
+//! other segments are applied to every element of an array, so `facts.disks.name` is the list of disk names. `=~`
+//! matches the left side against a regular expression, or any element when the left side is a list. `in` tests list
+//! membership, substrings of a string, or keys of an object.
+
+use std::{cmp::Ordering, str::FromStr};
+
+use serde_json::Value;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+  Literal(Value),
+  Path(Vec<String>),
+  List(Vec<Expr>),
+  Not(Box<Expr>),
+  And(Box<Expr>, Box<Expr>),
+  Or(Box<Expr>, Box<Expr>),
+  Compare(Op, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+  Eq,
+  Ne,
+  Lt,
+  Le,
+  Gt,
+  Ge,
+  Match,
+  In,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+  Str(String),
+  Num(f64),
+  Ident(String),
+  Dot,
+  Comma,
+  LParen,
+  RParen,
+  LBracket,
+  RBracket,
+  Not,
+  And,
+  Or,
+  Op(Op),
+}
+
+fn tokenize(input: &str) -> anyhow::Result<Vec<Token>> {
+  let chars: Vec<char> = input.chars().collect();
+  let mut tokens = Vec::new();
+  let mut i = 0;
+  while i < chars.len() {
+    let c = chars[i];
+    let next = chars.get(i + 1).copied();
+    let (token, len) = match (c, next) {
+      (c, _) if c.is_whitespace() => {
+        i += 1;
+        continue;
+      }
+      ('&', Some('&')) => (Token::And, 2),
+      ('|', Some('|')) => (Token::Or, 2),
+      ('=', Some('=')) => (Token::Op(Op::Eq), 2),
+      ('=', Some('~')) => (Token::Op(Op::Match), 2),
+      ('!', Some('=')) => (Token::Op(Op::Ne), 2),
+      ('<', Some('=')) => (Token::Op(Op::Le), 2),
+      ('>', Some('=')) => (Token::Op(Op::Ge), 2),
+      ('<', _) => (Token::Op(Op::Lt), 1),
+      ('>', _) => (Token::Op(Op::Gt), 1),
+      ('!', _) => (Token::Not, 1),
+      ('.', _) => (Token::Dot, 1),
+      (',', _) => (Token::Comma, 1),
+      ('(', _) => (Token::LParen, 1),
+      (')', _) => (Token::RParen, 1),
+      ('[', _) => (Token::LBracket, 1),
+      (']', _) => (Token::RBracket, 1),
+      ('\'', _) | ('"', _) => {
+        let mut value = String::new();
+        let mut j = i + 1;
+        loop {
+          match chars.get(j) {
+            None => anyhow::bail!("Unterminated string starting at offset {i}"),
+            Some('\\') if j + 1 < chars.len() => {
+              value.push(chars[j + 1]);
+              j += 2;
+            }
+            Some(&q) if q == c => break,
+            Some(&ch) => {
+              value.push(ch);
+              j += 1;
+            }
+          }
+        }
+        (Token::Str(value), j + 1 - i)
+      }
+      (c, _) if c.is_ascii_digit() || (c == '-' && next.is_some_and(|n| n.is_ascii_digit())) => {
+        let mut j = i + 1;
+        while j < chars.len() &&
+          (chars[j].is_ascii_digit() || (chars[j] == '.' && chars.get(j + 1).is_some_and(|c| c.is_ascii_digit())))
+        {
+          j += 1;
+        }
+        let text: String = chars[i..j].iter().collect();
+        let value = text.parse::<f64>().map_err(|_| anyhow::anyhow!("Invalid number '{text}'"))?;
+        (Token::Num(value), j - i)
+      }
+      (c, _) if c.is_alphanumeric() || c == '_' => {
+        let mut j = i + 1;
+        while j < chars.len() && (chars[j].is_alphanumeric() || chars[j] == '_' || chars[j] == '-') {
+          j += 1;
+        }
+        let text: String = chars[i..j].iter().collect();
+        let token = if text == "in" {
+          Token::Op(Op::In)
+        } else {
+          Token::Ident(text)
+        };
+        (token, j - i)
+      }
+      (c, _) => anyhow::bail!("Unexpected character '{c}' at offset {i}"),
+    };
+    tokens.push(token);
+    i += len;
+  }
+  Ok(tokens)
+}
+
+struct Parser {
+  tokens: Vec<Token>,
+  pos: usize,
+}
+
+impl Parser {
+  fn peek(&self) -> Option<&Token> { self.tokens.get(self.pos) }
+
+  fn next(&mut self) -> Option<Token> {
+    let token = self.tokens.get(self.pos).cloned();
+    self.pos += 1;
+    token
+  }
+
+  fn expect(&mut self, expected: Token) -> anyhow::Result<()> {
+    match self.next() {
+      Some(token) if token == expected => Ok(()),
+      Some(token) => anyhow::bail!("Expected {expected:?}, found {token:?}"),
+      None => anyhow::bail!("Expected {expected:?}, found end of expression"),
+    }
+  }
+
+  fn or(&mut self) -> anyhow::Result<Expr> {
+    let mut lhs = self.and()?;
+    while self.peek() == Some(&Token::Or) {
+      self.next();
+      lhs = Expr::Or(Box::new(lhs), Box::new(self.and()?));
+    }
+    Ok(lhs)
+  }
+
+  fn and(&mut self) -> anyhow::Result<Expr> {
+    let mut lhs = self.unary()?;
+    while self.peek() == Some(&Token::And) {
+      self.next();
+      lhs = Expr::And(Box::new(lhs), Box::new(self.unary()?));
+    }
+    Ok(lhs)
+  }
+
+  fn unary(&mut self) -> anyhow::Result<Expr> {
+    if self.peek() == Some(&Token::Not) {
+      self.next();
+      return Ok(Expr::Not(Box::new(self.unary()?)));
+    }
+    self.compare()
+  }
+
+  fn compare(&mut self) -> anyhow::Result<Expr> {
+    let lhs = self.primary()?;
+    if let Some(Token::Op(op)) = self.peek().cloned() {
+      self.next();
+      let rhs = self.primary()?;
+      return Ok(Expr::Compare(op, Box::new(lhs), Box::new(rhs)));
+    }
+    Ok(lhs)
+  }
+
+  fn primary(&mut self) -> anyhow::Result<Expr> {
+    match self.next() {
+      Some(Token::Str(value)) => Ok(Expr::Literal(Value::String(value))),
+      Some(Token::Num(value)) => Ok(Expr::Literal(serde_json::json!(value))),
+      Some(Token::Ident(ident)) => match ident.as_str() {
+        "true" => Ok(Expr::Literal(Value::Bool(true))),
+        "false" => Ok(Expr::Literal(Value::Bool(false))),
+        "null" => Ok(Expr::Literal(Value::Null)),
+        _ => {
+          let mut path = vec![ident];
+          while self.peek() == Some(&Token::Dot) {
+            self.next();
+            match self.next() {
+              Some(Token::Ident(segment)) => path.push(segment),
+              Some(Token::Num(index)) if index >= 0.0 && index.fract() == 0.0 => path.push(index.to_string()),
+              other => anyhow::bail!("Expected path segment after '.', found {other:?}"),
+            }
+          }
+          Ok(Expr::Path(path))
+        }
+      },
+      Some(Token::LParen) => {
+        let expr = self.or()?;
+        self.expect(Token::RParen)?;
+        Ok(expr)
+      }
+      Some(Token::LBracket) => {
+        let mut items = Vec::new();
+        if self.peek() == Some(&Token::RBracket) {
+          self.next();
+          return Ok(Expr::List(items));
+        }
+        loop {
+          items.push(self.or()?);
+          match self.next() {
+            Some(Token::Comma) => continue,
+            Some(Token::RBracket) => break,
+            other => anyhow::bail!("Expected ',' or ']' in list, found {other:?}"),
+          }
+        }
+        Ok(Expr::List(items))
+      }
+      Some(token) => anyhow::bail!("Unexpected {token:?}"),
+      None => anyhow::bail!("Unexpected end of expression"),
+    }
+  }
+}
+
+impl FromStr for Expr {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let mut parser = Parser {
+      tokens: tokenize(s)?,
+      pos: 0,
+    };
+    let expr = parser.or()?;
+    if let Some(token) = parser.peek() {
+      anyhow::bail!("Unexpected {token:?} after end of expression");
+    }
+    Ok(expr)
+  }
+}
+
+/// The value at `path` in `value`, or null. A segment applied to an array indexes it if numeric, and is applied to
+/// every item otherwise.
+pub fn lookup(value: &Value, path: &[String]) -> Value {
+  let Some((segment, rest)) = path.split_first() else {
+    return value.clone();
+  };
+  match value {
+    Value::Object(map) => map.get(segment).map(|v| lookup(v, rest)).unwrap_or(Value::Null),
+    Value::Array(items) => match segment.parse::<usize>() {
+      Ok(index) => items.get(index).map(|v| lookup(v, rest)).unwrap_or(Value::Null),
+      Err(_) => Value::Array(items.iter().map(|v| lookup(v, path)).collect()),
+    },
+    _ => Value::Null,
+  }
+}
+
+pub fn is_truthy(value: &Value) -> bool {
+  match value {
+    Value::Null => false,
+    Value::Bool(b) => *b,
+    Value::Number(n) => n.as_f64().is_some_and(|n| n != 0.0),
+    Value::String(s) => !s.is_empty(),
+    Value::Array(items) => !items.is_empty(),
+    Value::Object(map) => !map.is_empty(),
+  }
+}
+
+fn equals(lhs: &Value, rhs: &Value) -> bool {
+  match (lhs, rhs) {
+    (Value::Number(a), Value::Number(b)) => a.as_f64() == b.as_f64(),
+    _ => lhs == rhs,
+  }
+}
+
+fn compare(op: Op, lhs: &Value, rhs: &Value) -> anyhow::Result<bool> {
+  match op {
+    Op::Eq => Ok(equals(lhs, rhs)),
+    Op::Ne => Ok(!equals(lhs, rhs)),
+    Op::Lt | Op::Le | Op::Gt | Op::Ge => {
+      let ordering = match (lhs, rhs) {
+        (Value::Number(a), Value::Number(b)) => a.as_f64().partial_cmp(&b.as_f64()),
+        (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+        _ => None,
+      };
+      let Some(ordering) = ordering else {
+        anyhow::bail!("Cannot compare {lhs} with {rhs}");
+      };
+      Ok(match op {
+        Op::Lt => ordering == Ordering::Less,
+        Op::Le => ordering != Ordering::Greater,
+        Op::Gt => ordering == Ordering::Greater,
+        _ => ordering != Ordering::Less,
+      })
+    }
+    Op::Match => {
+      let Value::String(pattern) = rhs else {
+        anyhow::bail!("Right side of '=~' must be a string, found {rhs}");
+      };
+      let regex = regex::Regex::new(pattern)?;
+      Ok(match lhs {
+        Value::String(s) => regex.is_match(s),
+        Value::Array(items) => items.iter().any(|v| v.as_str().is_some_and(|s| regex.is_match(s))),
+        _ => false,
+      })
+    }
+    Op::In => Ok(match (lhs, rhs) {
+      (_, Value::Array(items)) => items.iter().any(|v| equals(lhs, v)),
+      (Value::String(needle), Value::String(haystack)) => haystack.contains(needle.as_str()),
+      (Value::String(key), Value::Object(map)) => map.contains_key(key),
+      _ => false,
+    }),
+  }
+}
+
+impl Expr {
+  pub fn eval(&self, context: &Value) -> anyhow::Result<Value> {
+    Ok(match self {
+      Expr::Literal(value) => value.clone(),
+      Expr::Path(path) => lookup(context, path),
+      Expr::List(items) => Value::Array(items.iter().map(|v| v.eval(context)).collect::<anyhow::Result<_>>()?),
+      Expr::Not(inner) => Value::Bool(!inner.is_true(context)?),
+      Expr::And(lhs, rhs) => Value::Bool(lhs.is_true(context)? && rhs.is_true(context)?),
+      Expr::Or(lhs, rhs) => Value::Bool(lhs.is_true(context)? || rhs.is_true(context)?),
+      Expr::Compare(op, lhs, rhs) => Value::Bool(compare(*op, &lhs.eval(context)?, &rhs.eval(context)?)?),
+    })
+  }
+
+  pub fn is_true(&self, context: &Value) -> anyhow::Result<bool> { Ok(is_truthy(&self.eval(context)?)) }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_eval() {
+    let context = serde_json::json!({
+      "global": { "distro_hint": "ubuntu" },
+      "facts": {
+        "arch": "x86_64",
+        "memory": 8192,
+        "disks": [{ "name": "nvme0n1" }, { "name": "sda" }],
+        "dmi": { "vendor": "Dell Inc." },
+      },
+    });
+    let eval = |s: &str| s.parse::<Expr>().unwrap().is_true(&context).unwrap();
+
+    assert!(eval("global.distro_hint == 'ubuntu'"));
+    assert!(eval("global.distro_hint in ['ubuntu', \"debian\"]"));
+    assert!(!eval("global.distro_hint != 'ubuntu'"));
+    assert!(eval("facts.disks.name =~ '^nvme'"));
+    assert!(eval("'sda' in facts.disks.name"));
+    assert!(eval("facts.disks.0.name == 'nvme0n1'"));
+    assert!(eval("facts.memory >= 4096 && !(facts.arch == 'aarch64')"));
+    assert!(eval("facts.dmi.vendor == 'HPE' || 'Dell' in facts.dmi.vendor"));
+    assert!(!eval("facts.missing.value"));
+    assert!(eval("facts.missing == null"));
+
+    assert!("global.distro_hint ==".parse::<Expr>().is_err());
+    assert!("(true".parse::<Expr>().is_err());
+    assert!("a b".parse::<Expr>().is_err());
+    assert!("'unterminated".parse::<Expr>().is_err());
+    assert!("facts.arch < 1".parse::<Expr>().unwrap().eval(&context).is_err());
+  }
+}
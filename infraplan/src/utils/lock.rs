@@ -0,0 +1,138 @@
+//! Advisory locks that keep two runs from working on the same machine, or the same disk, at the same time.
+//!
+//! Locks are `flock`s on files under `/run/infraplan`, so they are released when the process exits, however it exits.
+//! The holder writes its PID and running recipes into the file, for the error message of whoever finds it locked.
+
+use std::{
+  fmt,
+  fs::File,
+  os::unix::fs::FileExt,
+  path::{Path, PathBuf},
+  sync::Mutex,
+};
+
+use nix::{
+  errno::Errno,
+  fcntl::{Flock, FlockArg},
+};
+
+pub const LOCK_DIR: &str = "/run/infraplan";
+
+static INSTANCE: Mutex<Option<Lock>> = Mutex::new(None);
+static RUNNING: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Holder {
+  pub pid: u32,
+  /// Recipes that were running when the holder last updated the lock file.
+  pub recipes: Vec<String>,
+}
+
+impl fmt::Display for Holder {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "PID {}", self.pid)?;
+    match self.recipes.as_slice() {
+      [] => write!(f, ", not running a recipe"),
+      recipes => write!(f, ", running recipe '{}'", recipes.join("', '")),
+    }
+  }
+}
+
+/// An exclusive lock, held until it is dropped.
+pub struct Lock {
+  file: Flock<File>,
+  path: PathBuf,
+}
+
+impl Lock {
+  /// Take the lock on `path`, failing right away if another process holds it.
+  pub fn acquire<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+    let path = path.as_ref().to_path_buf();
+    if let Some(parent) = path.parent() {
+      std::fs::create_dir_all(parent)?;
+    }
+    let file = File::options()
+      .read(true)
+      .write(true)
+      .create(true)
+      .truncate(false)
+      .open(&path)
+      .map_err(|e| anyhow::anyhow!("Failed to open lock file {}: {e}", path.display()))?;
+    let file = match Flock::lock(file, FlockArg::LockExclusiveNonblock) {
+      Ok(file) => file,
+      Err((_, Errno::EWOULDBLOCK)) => {
+        let holder = std::fs::read_to_string(&path)
+          .ok()
+          .and_then(|v| serde_json::from_str::<Holder>(&v).ok())
+          .map_or_else(|| "an unknown process".to_string(), |v| v.to_string());
+        anyhow::bail!("{} is locked by another infraplan run ({holder})", path.display());
+      }
+      Err((_, e)) => anyhow::bail!("Failed to lock {}: {e}", path.display()),
+    };
+
+    let lock = Lock { file, path };
+    lock.write_holder()?;
+    log::debug!("Locked {}", lock.path.display());
+    Ok(lock)
+  }
+
+  fn write_holder(&self) -> anyhow::Result<()> {
+    let holder = Holder {
+      pid: std::process::id(),
+      recipes: RUNNING.lock().unwrap().clone(),
+    };
+    let content = serde_json::to_string(&holder)?;
+    self.file.set_len(0)?;
+    self.file.write_all_at(content.as_bytes(), 0)?;
+    Ok(())
+  }
+}
+
+/// Take the lock for the whole machine, until `unlock_instance` or the end of the process.
+pub fn lock_instance() -> anyhow::Result<()> {
+  let lock = Lock::acquire(Path::new(LOCK_DIR).join("infraplan.lock"))?;
+  *INSTANCE.lock().unwrap() = Some(lock);
+  Ok(())
+}
+
+/// Release the lock taken by `lock_instance`.
+pub fn unlock_instance() { *INSTANCE.lock().unwrap() = None; }
+
+/// Take the lock for `disk`. Paths that resolve to the same device share a lock.
+pub fn lock_disk(disk: &str) -> anyhow::Result<Lock> {
+  let device = std::fs::canonicalize(disk).unwrap_or_else(|_| PathBuf::from(disk));
+  let name = device.to_string_lossy().trim_start_matches('/').replace('/', "-");
+  Lock::acquire(Path::new(LOCK_DIR).join(format!("disk-{name}.lock")))
+}
+
+/// Record that the recipe `id` started or stopped running, for the holder of the machine lock.
+pub fn set_running(id: &str, running: bool) {
+  {
+    let mut recipes = RUNNING.lock().unwrap();
+    recipes.retain(|v| v != id);
+    if running {
+      recipes.push(id.to_string());
+    }
+  }
+  if let Some(lock) = INSTANCE.lock().unwrap().as_ref() &&
+    let Err(e) = lock.write_holder()
+  {
+    log::warn!("Failed to update lock file {}: {e}", lock.path.display());
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn lock_twice() {
+    let path = std::env::temp_dir().join(format!("infraplan-lock-{}/test.lock", std::process::id()));
+    let lock = Lock::acquire(&path).unwrap();
+    let error = Lock::acquire(&path).err().unwrap().to_string();
+    assert!(error.contains(&format!("PID {}", std::process::id())), "{error}");
+    drop(lock);
+    Lock::acquire(&path).unwrap();
+    std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+  }
+}
@@ -0,0 +1,364 @@
+//! Prometheus metrics, written as a textfile for node_exporter's textfile collector at the end of every run.
+//!
+//! Counters are kept for the whole process, so in agent mode they add up across runs like any Prometheus counter.
+
+use std::{
+  collections::BTreeMap,
+  ffi::OsStr,
+  fmt::Write,
+  path::Path,
+  pin::Pin,
+  sync::{
+    Mutex,
+    atomic::{AtomicU64, Ordering},
+  },
+  task::{Context, Poll},
+  time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use tokio::io::{AsyncRead, ReadBuf};
+
+use crate::{
+  events::{RecipeStatus, Report, RunStatus},
+  utils::write_file_atomic,
+};
+
+pub static DOWNLOADED_BYTES: AtomicU64 = AtomicU64::new(0);
+pub static EXTRACTED_BYTES: AtomicU64 = AtomicU64::new(0);
+/// Nanoseconds spent waiting for tarball data over HTTP.
+pub static DOWNLOAD_NANOS: AtomicU64 = AtomicU64::new(0);
+static COMMANDS: Mutex<BTreeMap<String, CommandCount>> = Mutex::new(BTreeMap::new());
+
+const LAST_SUCCESS: &str = "infraplan_last_success_timestamp_seconds";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CommandCount {
+  pub runs: u64,
+  /// Runs that could not be started or exited with a non-zero status.
+  pub failures: u64,
+}
+
+/// A snapshot of the counters.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Counters {
+  pub downloaded_bytes: u64,
+  pub download_ms: u64,
+  pub extracted_bytes: u64,
+  /// By the file name of the command.
+  pub commands: BTreeMap<String, CommandCount>,
+}
+
+pub fn counters() -> Counters {
+  Counters {
+    downloaded_bytes: DOWNLOADED_BYTES.load(Ordering::Relaxed),
+    download_ms: DOWNLOAD_NANOS.load(Ordering::Relaxed) / 1_000_000,
+    extracted_bytes: EXTRACTED_BYTES.load(Ordering::Relaxed),
+    commands: COMMANDS.lock().unwrap().clone(),
+  }
+}
+
+/// Count a run of `command`.
+pub fn record_command(command: impl AsRef<OsStr>, success: bool) {
+  let name = Path::new(command.as_ref()).file_name().unwrap_or(command.as_ref()).to_string_lossy().to_string();
+  let mut commands = COMMANDS.lock().unwrap();
+  let count = commands.entry(name).or_default();
+  count.runs += 1;
+  if !success {
+    count.failures += 1;
+  }
+}
+
+/// Adds the bytes read through it to a counter.
+pub struct CountingReader<R> {
+  inner: R,
+  counter: &'static AtomicU64,
+  /// Adds the nanoseconds spent in reads to this counter, waiting for data included, see `timed`.
+  timer: Option<&'static AtomicU64>,
+  /// When the pending read started.
+  waiting: Option<Instant>,
+}
+
+impl<R> CountingReader<R> {
+  pub fn new(inner: R, counter: &'static AtomicU64) -> Self {
+    CountingReader {
+      inner,
+      counter,
+      timer: None,
+      waiting: None,
+    }
+  }
+
+  /// Also time the reads, from the first poll of a read until it is ready, into `timer`. Time the reader is not
+  /// being read from, such as while the data is processed, does not count.
+  pub fn timed(mut self, timer: &'static AtomicU64) -> Self {
+    self.timer = Some(timer);
+    self
+  }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for CountingReader<R> {
+  fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+    let this = self.get_mut();
+    let started = *this.waiting.get_or_insert_with(Instant::now);
+    let before = buf.filled().len();
+    let result = Pin::new(&mut this.inner).poll_read(cx, buf);
+    if result.is_pending() {
+      return result;
+    }
+    this.waiting = None;
+    this.counter.fetch_add((buf.filled().len() - before) as u64, Ordering::Relaxed);
+    if let Some(timer) = this.timer {
+      timer.fetch_add(started.elapsed().as_nanos() as u64, Ordering::Relaxed);
+    }
+    result
+  }
+}
+
+fn escape_label(value: &str) -> String { value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n") }
+
+fn unix_time(time: SystemTime) -> u64 { time.duration_since(UNIX_EPOCH).map(|v| v.as_secs()).unwrap_or(0) }
+
+/// Read the last successful run's timestamp back from a textfile written earlier.
+fn read_last_success(content: &str) -> Option<u64> {
+  content
+    .lines()
+    .find_map(|v| v.strip_prefix(LAST_SUCCESS)?.strip_prefix(' '))
+    .and_then(|v| v.trim().parse().ok())
+}
+
+/// Render the metrics of the run `report` finished at `now`.
+pub fn render(report: &Report, counters: &Counters, now: SystemTime, last_success: Option<u64>) -> String {
+  let mut out = String::new();
+  let mut metric = |name: &str, kind: &str, help: &str, samples: &[(String, f64)]| {
+    _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} {kind}");
+    for (labels, value) in samples {
+      _ = writeln!(out, "{name}{labels} {value}");
+    }
+  };
+  let seconds = |ms: u64| ms as f64 / 1000.0;
+
+  let success = report.status == RunStatus::Success;
+  metric(
+    "infraplan_run_success",
+    "gauge",
+    "Whether the last run succeeded.",
+    &[(String::new(), if success { 1.0 } else { 0.0 })],
+  );
+  metric(
+    "infraplan_run_duration_seconds",
+    "gauge",
+    "Duration of the last run.",
+    &[(String::new(), seconds(report.duration_ms))],
+  );
+  metric(
+    "infraplan_run_timestamp_seconds",
+    "gauge",
+    "When the last run finished.",
+    &[(String::new(), unix_time(now) as f64)],
+  );
+  let last_success = if success { Some(unix_time(now)) } else { last_success };
+  if let Some(last_success) = last_success {
+    metric(
+      LAST_SUCCESS,
+      "gauge",
+      "When the last successful run finished.",
+      &[(String::new(), last_success as f64)],
+    );
+  }
+
+  let recipe_labels = |recipe: &str, plugin: &str| {
+    format!(
+      "recipe=\"{}\",plugin=\"{}\"",
+      escape_label(recipe),
+      escape_label(plugin)
+    )
+  };
+  let statuses: Vec<(String, f64)> = report
+    .recipes
+    .iter()
+    .map(|v| {
+      (
+        format!("{{{},status=\"{}\"}}", recipe_labels(&v.recipe, &v.plugin), v.status),
+        1.0,
+      )
+    })
+    .collect();
+  metric(
+    "infraplan_recipe_status",
+    "gauge",
+    "Status of each recipe in the last run, as the status label.",
+    &statuses,
+  );
+  let durations: Vec<(String, f64)> = report
+    .recipes
+    .iter()
+    .filter_map(|v| {
+      Some((
+        format!("{{{}}}", recipe_labels(&v.recipe, &v.plugin)),
+        seconds(v.duration_ms?),
+      ))
+    })
+    .collect();
+  metric(
+    "infraplan_recipe_duration_seconds",
+    "gauge",
+    "Duration of each recipe that ran in the last run, including retries.",
+    &durations,
+  );
+  let attempts: Vec<(String, f64)> = report
+    .recipes
+    .iter()
+    .filter(|v| v.status != RecipeStatus::Skipped)
+    .map(|v| {
+      (
+        format!("{{{}}}", recipe_labels(&v.recipe, &v.plugin)),
+        v.attempts as f64,
+      )
+    })
+    .collect();
+  metric(
+    "infraplan_recipe_attempts",
+    "gauge",
+    "Attempts made at each recipe in the last run.",
+    &attempts,
+  );
+
+  metric(
+    "infraplan_tarball_downloaded_bytes_total",
+    "counter",
+    "Bytes of tarballs downloaded over HTTP.",
+    &[(String::new(), counters.downloaded_bytes as f64)],
+  );
+  metric(
+    "infraplan_tarball_download_seconds_total",
+    "counter",
+    "Time spent waiting for tarball data over HTTP, extraction excluded.",
+    &[(String::new(), seconds(counters.download_ms))],
+  );
+  metric(
+    "infraplan_tarball_extracted_bytes_total",
+    "counter",
+    "Uncompressed bytes of tarballs extracted.",
+    &[(String::new(), counters.extracted_bytes as f64)],
+  );
+  let command_labels = |name: &str| format!("{{command=\"{}\"}}", escape_label(name));
+  let runs: Vec<(String, f64)> =
+    counters.commands.iter().map(|(name, count)| (command_labels(name), count.runs as f64)).collect();
+  metric("infraplan_commands_total", "counter", "Commands run.", &runs);
+  let failures: Vec<(String, f64)> = counters
+    .commands
+    .iter()
+    .map(|(name, count)| (command_labels(name), count.failures as f64))
+    .collect();
+  metric(
+    "infraplan_command_failures_total",
+    "counter",
+    "Commands that could not be started or exited with a non-zero status.",
+    &failures,
+  );
+  out
+}
+
+/// Write the metrics of the run `report` to `path`, keeping the last success timestamp of the file it replaces.
+pub fn write_textfile(path: &str, report: &Report) -> anyhow::Result<()> {
+  let last_success = std::fs::read_to_string(path).ok().and_then(|v| read_last_success(&v));
+  let content = render(report, &counters(), SystemTime::now(), last_success);
+  write_file_atomic(path, content)?;
+  log::debug!("Wrote metrics to {path}");
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use std::time::Duration;
+
+  use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+  use super::*;
+  use crate::events::{Event, RecipeReport};
+
+  #[tokio::test]
+  async fn count_and_time_reads() {
+    static BYTES: AtomicU64 = AtomicU64::new(0);
+    static NANOS: AtomicU64 = AtomicU64::new(0);
+    let (mut writer, reader) = tokio::io::duplex(64);
+    tokio::spawn(async move {
+      tokio::time::sleep(Duration::from_millis(100)).await;
+      writer.write_all(b"tarball").await.unwrap();
+    });
+    let mut reader = CountingReader::new(reader, &BYTES).timed(&NANOS);
+    let mut content = Vec::new();
+    reader.read_to_end(&mut content).await.unwrap();
+    assert_eq!(content, b"tarball");
+    assert_eq!(BYTES.load(Ordering::Relaxed), 7);
+    assert!(NANOS.load(Ordering::Relaxed) >= 90_000_000);
+
+    // Time spent between reads is not counted.
+    let waited = NANOS.load(Ordering::Relaxed);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    reader.read_to_end(&mut content).await.unwrap();
+    assert!(NANOS.load(Ordering::Relaxed) - waited < 50_000_000);
+  }
+
+  #[test]
+  fn render_metrics() {
+    let mut report =
+      Report::new([("deploy".to_string(), "system_deployer".to_string()), ("motd".to_string(), "shell".to_string())]);
+    report.record(&Event::RecipeFinish {
+      recipe: "deploy".to_string(),
+      plugin: "system_deployer".to_string(),
+      duration_ms: 61500,
+      attempts: 2,
+    });
+    report.record(&Event::RecipeFail {
+      recipe: "motd".to_string(),
+      plugin: "shell".to_string(),
+      duration_ms: 20,
+      attempts: 1,
+      error: "exit status 1".to_string(),
+    });
+    report.finish(61600, &Err(anyhow::anyhow!("motd failed")), false);
+    let counters = Counters {
+      downloaded_bytes: 1024,
+      download_ms: 2000,
+      extracted_bytes: 4096,
+      commands: BTreeMap::from([("sfdisk".to_string(), CommandCount { runs: 3, failures: 1 })]),
+    };
+    let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+
+    let failed = render(&report, &counters, now, Some(1_600_000_000));
+    let lines: Vec<&str> = failed.lines().filter(|v| !v.starts_with('#')).collect();
+    assert_eq!(
+      lines,
+      vec![
+        "infraplan_run_success 0",
+        "infraplan_run_duration_seconds 61.6",
+        "infraplan_run_timestamp_seconds 1700000000",
+        "infraplan_last_success_timestamp_seconds 1600000000",
+        r#"infraplan_recipe_status{recipe="deploy",plugin="system_deployer",status="completed"} 1"#,
+        r#"infraplan_recipe_status{recipe="motd",plugin="shell",status="failed"} 1"#,
+        r#"infraplan_recipe_duration_seconds{recipe="deploy",plugin="system_deployer"} 61.5"#,
+        r#"infraplan_recipe_duration_seconds{recipe="motd",plugin="shell"} 0.02"#,
+        r#"infraplan_recipe_attempts{recipe="deploy",plugin="system_deployer"} 2"#,
+        r#"infraplan_recipe_attempts{recipe="motd",plugin="shell"} 1"#,
+        "infraplan_tarball_downloaded_bytes_total 1024",
+        "infraplan_tarball_download_seconds_total 2",
+        "infraplan_tarball_extracted_bytes_total 4096",
+        r#"infraplan_commands_total{command="sfdisk"} 3"#,
+        r#"infraplan_command_failures_total{command="sfdisk"} 1"#,
+      ]
+    );
+    assert_eq!(read_last_success(&failed), Some(1_600_000_000));
+    assert!(failed.contains("# TYPE infraplan_commands_total counter\n"));
+
+    report.recipes[1] = RecipeReport {
+      status: RecipeStatus::Skipped,
+      duration_ms: None,
+      ..report.recipes[1].clone()
+    };
+    report.finish(61600, &Ok(()), false);
+    let succeeded = render(&report, &counters, now, Some(1_600_000_000));
+    assert_eq!(read_last_success(&succeeded), Some(1_700_000_000));
+    assert!(!succeeded.contains(r#"infraplan_recipe_attempts{recipe="motd""#));
+  }
+}
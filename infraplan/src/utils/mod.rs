@@ -1,12 +1,30 @@
-use std::path::PathBuf;
+use std::{
+  io::Write,
+  os::unix::fs::OpenOptionsExt,
+  path::{Path, PathBuf},
+  sync::LazyLock,
+  time::Duration,
+};
 
 use nix::unistd::Uid;
 
+pub mod cancel;
 pub mod chroot;
+pub mod expr;
 pub mod fstab;
+pub mod lock;
+pub mod metrics;
 pub mod parted_exe;
 pub mod process;
 pub mod syscall;
+pub mod template;
+#[cfg(test)]
+pub mod test_http;
+
+static HTTP_CLIENT: LazyLock<reqwest::Client> = LazyLock::new(reqwest::Client::new);
+
+/// The HTTP client shared by everything that downloads, so connections are reused.
+pub fn http_client() -> reqwest::Client { HTTP_CLIENT.clone() }
 
 pub fn join_path_string(base: &str, path: &str) -> String {
   let mut full_path: PathBuf = PathBuf::from(base);
@@ -14,6 +32,62 @@ pub fn join_path_string(base: &str, path: &str) -> String {
   full_path.to_string_lossy().into_owned()
 }
 
+/// Write `content` to a sibling temporary file and rename it over `path`, so
+/// readers never observe a partially written file.
+pub fn write_file_atomic<P: AsRef<Path>, C: AsRef<[u8]>>(path: P, content: C) -> anyhow::Result<()> {
+  write_file_atomic_with_mode(path, content, 0o666)
+}
+
+/// `write_file_atomic`, creating the file with `mode` (less the umask), e.g. `0o600` for a file holding credentials.
+pub fn write_file_atomic_with_mode<P: AsRef<Path>, C: AsRef<[u8]>>(
+  path: P, content: C, mode: u32,
+) -> anyhow::Result<()> {
+  let path = path.as_ref();
+  if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+    std::fs::create_dir_all(parent)?;
+  }
+  let mut tmp_path = path.as_os_str().to_owned();
+  tmp_path.push(".tmp");
+  let tmp_path = PathBuf::from(tmp_path);
+
+  let mut file = std::fs::File::options().write(true).create(true).truncate(true).mode(mode).open(&tmp_path)?;
+  file.write_all(content.as_ref())?;
+  file.sync_all()?;
+  drop(file);
+  std::fs::rename(&tmp_path, path)?;
+  Ok(())
+}
+
+/// Parse a duration such as `90`, `30s`, `15m` or `1h30m`. A number without a unit is in seconds.
+pub fn parse_duration(s: &str) -> anyhow::Result<Duration> {
+  let s = s.trim();
+  if s.is_empty() {
+    anyhow::bail!("Invalid duration: empty string");
+  }
+  if let Ok(secs) = s.parse::<u64>() {
+    return Ok(Duration::from_secs(secs));
+  }
+
+  let mut total = Duration::ZERO;
+  let mut rest = s;
+  while !rest.is_empty() {
+    let digits = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+    let unit_len = rest[digits..].find(|c: char| c.is_ascii_digit()).unwrap_or(rest.len() - digits);
+    let (value, unit) = (&rest[..digits], &rest[digits..digits + unit_len]);
+    let value: u64 = value.parse().map_err(|_| anyhow::anyhow!("Invalid duration `{s}`"))?;
+    total += match unit {
+      "ms" => Duration::from_millis(value),
+      "s" => Duration::from_secs(value),
+      "m" => Duration::from_secs(value * 60),
+      "h" => Duration::from_secs(value * 3600),
+      "d" => Duration::from_secs(value * 86400),
+      _ => anyhow::bail!("Invalid duration `{s}`: unknown unit `{unit}`"),
+    };
+    rest = &rest[digits + unit_len..];
+  }
+  Ok(total)
+}
+
 pub fn elevate_privileges() -> anyhow::Result<()> {
   let euid = nix::unistd::geteuid();
   if !euid.is_root() {
@@ -21,3 +95,20 @@ pub fn elevate_privileges() -> anyhow::Result<()> {
   }
   Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_parse_duration() {
+    assert_eq!(parse_duration("90").unwrap(), Duration::from_secs(90));
+    assert_eq!(parse_duration("30s").unwrap(), Duration::from_secs(30));
+    assert_eq!(parse_duration("15m").unwrap(), Duration::from_secs(900));
+    assert_eq!(parse_duration("1h30m").unwrap(), Duration::from_secs(5400));
+    assert_eq!(parse_duration("250ms").unwrap(), Duration::from_millis(250));
+    assert!(parse_duration("").is_err());
+    assert!(parse_duration("5x").is_err());
+    assert!(parse_duration("m").is_err());
+  }
+}
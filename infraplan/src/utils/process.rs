@@ -8,8 +8,14 @@ use std::{
   // str::FromStr,
 };
 
+use nix::{
+  sys::signal::{Signal, killpg},
+  unistd::Pid,
+};
 use tokio::{io::AsyncWriteExt, process::Command};
 
+use crate::utils::{cancel, metrics};
+
 // pub fn find_executable(name: &str, root: &str) -> anyhow::Result<Option<String>> {
 //   let root = PathBuf::from_str(root)?;
 
@@ -125,25 +131,44 @@ pub async fn run_command_with<
   });
   cmd.stdout(std::process::Stdio::piped());
   cmd.stderr(std::process::Stdio::piped());
+  // Run in a process group of its own so that everything it spawns can be killed together when the future is
+  // dropped, e.g. on a recipe timeout or cancellation. This also keeps a terminal's Ctrl-C away from the children.
+  cmd.process_group(0);
 
-  let mut child = cmd.spawn()?;
+  let mut child = cmd.spawn().inspect_err(|_| metrics::record_command(&command, false))?;
+  let mut group = ProcessGroupGuard(child.id());
   if let Some(input) = input {
     let Some(mut stdin) = Option::take(&mut child.stdin) else {
       anyhow::bail!("Failed to take child stdio");
     };
 
-    stdin.write_all(input.as_ref()).await?;
+    // A command may exit without reading all of its input; its exit status tells whether it failed.
+    if let Err(e) = stdin.write_all(input.as_ref()).await &&
+      e.kind() != std::io::ErrorKind::BrokenPipe
+    {
+      return Err(e.into());
+    }
     drop(stdin); // Close stdin to signal end of input
   }
 
-  let Ok(output) = child.wait_with_output().await else {
+  let output = tokio::select! {
+    output = child.wait_with_output() => output,
+    _ = cancel::cancelled() => {
+      log::warn!("Interrupted while running command: {command}");
+      return Err(cancel::Interrupted.into());
+    }
+  };
+  let Ok(output) = output else {
+    metrics::record_command(&command, false);
     log::error!("Failed to run command: {command}");
     anyhow::bail!("Failed to run command: {command}");
   };
+  group.0 = None;
 
   let status = output.status.code().unwrap_or(-1);
   let stdout = String::from_utf8_lossy(&output.stdout).to_string();
   let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+  metrics::record_command(&command, status == 0);
 
   if status == 0 {
     log::info!("Command finished: {command}");
@@ -153,6 +178,20 @@ pub async fn run_command_with<
   Ok((status, stdout, stderr))
 }
 
+/// Kills the process group of a child that is still running when the guard is dropped.
+struct ProcessGroupGuard(Option<u32>);
+
+impl Drop for ProcessGroupGuard {
+  fn drop(&mut self) {
+    if let Some(pid) = self.0 {
+      log::warn!("Killing process group {pid}");
+      if let Err(e) = killpg(Pid::from_raw(pid as i32), Signal::SIGKILL) {
+        log::error!("Failed to kill process group {pid}: {e}");
+      }
+    }
+  }
+}
+
 #[cfg(test)]
 mod tests {
   use super::*;
@@ -174,5 +213,31 @@ mod tests {
     assert_eq!(code, 0);
     assert!(stderr.is_empty());
     assert_eq!(stdout.trim(), "Hello, World!");
+
+    // More input than the pipe holds, for a command that never reads it.
+    let (code, _, _) = run_command_with::<_, Vec<&str>, Vec<(String, String)>, _, &str>(
+      "true",
+      None,
+      None,
+      Some(vec![0u8; 1 << 20]),
+      None,
+    )
+    .await
+    .unwrap();
+    assert_eq!(code, 0);
+  }
+
+  #[tokio::test]
+  async fn test_kill_on_drop() {
+    let marker = std::env::temp_dir().join(format!("infraplan-kill-{}", std::process::id()));
+    let script = format!("(sleep 1; touch {}) & wait", marker.display());
+    let result = tokio::time::timeout(
+      std::time::Duration::from_millis(100),
+      run_command("sh", ["-c", script.as_str()]),
+    )
+    .await;
+    assert!(result.is_err());
+    tokio::time::sleep(std::time::Duration::from_millis(1500)).await;
+    assert!(!marker.exists());
   }
 }
@@ -0,0 +1,83 @@
+//! `${name}` interpolation for configuration strings. `$${` produces a literal `${`, and a `$` not followed by `{` is
+//! kept as is.
+
+use serde_json::Value;
+
+/// Replace every `${name}` in `input` with the value `lookup` returns for it. Unknown names are an error.
+pub fn interpolate<F: Fn(&str) -> Option<String>>(input: &str, lookup: &F) -> anyhow::Result<String> {
+  let mut output = String::with_capacity(input.len());
+  let mut rest = input;
+  while let Some(pos) = rest.find('$') {
+    output.push_str(&rest[..pos]);
+    rest = &rest[pos..];
+    if let Some(tail) = rest.strip_prefix("$${") {
+      output.push_str("${");
+      rest = tail;
+    } else if let Some(tail) = rest.strip_prefix("${") {
+      let end = tail.find('}').ok_or(anyhow::anyhow!("Unterminated variable reference in `{input}`"))?;
+      let name = tail[..end].trim();
+      if name.is_empty() {
+        anyhow::bail!("Empty variable reference in `{input}`");
+      }
+      let value = lookup(name).ok_or(anyhow::anyhow!("Undefined variable `{name}`"))?;
+      output.push_str(&value);
+      rest = &tail[end + 1..];
+    } else {
+      output.push('$');
+      rest = &rest[1..];
+    }
+  }
+  output.push_str(rest);
+  Ok(output)
+}
+
+/// Interpolate every string in `value`, recursing into arrays and objects. Object keys are left untouched.
+pub fn interpolate_value<F: Fn(&str) -> Option<String>>(value: &mut Value, lookup: &F) -> anyhow::Result<()> {
+  match value {
+    Value::String(s) => *s = interpolate(s, lookup)?,
+    Value::Array(items) => {
+      for item in items {
+        interpolate_value(item, lookup)?;
+      }
+    }
+    Value::Object(map) => {
+      for item in map.values_mut() {
+        interpolate_value(item, lookup)?;
+      }
+    }
+    _ => {}
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_interpolate() {
+    let lookup = |name: &str| match name {
+      "disk" => Some("/dev/vdb".to_string()),
+      "mirror" => Some("https://archive.ubuntu.com".to_string()),
+      _ => None,
+    };
+    assert_eq!(interpolate("${disk}", &lookup).unwrap(), "/dev/vdb");
+    assert_eq!(
+      interpolate("${mirror}/ubuntu", &lookup).unwrap(),
+      "https://archive.ubuntu.com/ubuntu"
+    );
+    assert_eq!(
+      interpolate("${ disk }p1 $5 $${disk}", &lookup).unwrap(),
+      "/dev/vdbp1 $5 ${disk}"
+    );
+    assert!(interpolate("${missing}", &lookup).is_err());
+    assert!(interpolate("${disk", &lookup).is_err());
+
+    let mut value = serde_json::json!({ "disk": "${disk}", "items": [{ "url": "${mirror}" }], "dhcp": true });
+    interpolate_value(&mut value, &lookup).unwrap();
+    assert_eq!(
+      value,
+      serde_json::json!({ "disk": "/dev/vdb", "items": [{ "url": "https://archive.ubuntu.com" }], "dhcp": true })
+    );
+  }
+}
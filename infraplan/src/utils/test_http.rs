@@ -0,0 +1,87 @@
+//! A minimal HTTP server and client for the tests of code that talks HTTP.
+
+use std::sync::{Arc, Mutex};
+
+use tokio::{
+  io::{AsyncReadExt, AsyncWriteExt},
+  net::TcpListener,
+};
+
+/// A request the server received.
+#[derive(Debug, Clone)]
+pub struct Request {
+  /// The request line and headers.
+  pub head: String,
+  pub body: String,
+}
+
+/// Answer one connection per `(status, body)` in `responses`, in turn. Returns the base URL of the server and the
+/// requests it received so far.
+pub async fn serve(responses: Vec<(u16, &'static str)>) -> (String, Arc<Mutex<Vec<Request>>>) {
+  let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+  let address = listener.local_addr().unwrap();
+  let requests = Arc::new(Mutex::new(Vec::new()));
+  let received = requests.clone();
+  tokio::spawn(async move {
+    for (status, body) in responses {
+      let (mut stream, _) = listener.accept().await.unwrap();
+      let request = read_request(&mut stream).await;
+      received.lock().unwrap().push(request);
+      let response = format!(
+        "HTTP/1.1 {status} OK\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}",
+        body.len()
+      );
+      stream.write_all(response.as_bytes()).await.unwrap();
+    }
+  });
+  (format!("http://{address}"), requests)
+}
+
+/// Read the head, then as much body as its content-length announces.
+async fn read_request(stream: &mut tokio::net::TcpStream) -> Request {
+  let mut request = Vec::new();
+  let mut buf = [0u8; 4096];
+  loop {
+    let read = stream.read(&mut buf).await.unwrap();
+    request.extend_from_slice(&buf[..read]);
+    let text = String::from_utf8_lossy(&request).to_string();
+    if let Some((head, body)) = text.split_once("\r\n\r\n") {
+      let length = head
+        .lines()
+        .find_map(|v| v.to_lowercase().strip_prefix("content-length:").map(|v| v.trim().to_string()))
+        .and_then(|v| v.parse::<usize>().ok())
+        .unwrap_or(0);
+      if body.len() >= length || read == 0 {
+        return Request {
+          head: head.to_string(),
+          body: body.to_string(),
+        };
+      }
+    } else if read == 0 {
+      panic!("Connection closed before the end of the request head");
+    }
+  }
+}
+
+/// A client for the server at `base`, such as the control API.
+pub struct Client {
+  base: String,
+  client: reqwest::Client,
+}
+
+impl Client {
+  pub fn new(base: impl Into<String>) -> Self {
+    Client {
+      base: base.into(),
+      client: reqwest::Client::new(),
+    }
+  }
+
+  pub async fn get(&self, path: &str) -> reqwest::Response {
+    self.client.get(format!("{}{path}", self.base)).send().await.unwrap()
+  }
+
+  pub async fn post(&self, path: &str) -> reqwest::Response {
+    self.client.post(format!("{}{path}", self.base)).send().await.unwrap()
+  }
+}
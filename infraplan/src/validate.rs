@@ -0,0 +1,238 @@
+use std::{collections::HashSet, fmt, path::Path};
+
+use crate::{
+  plugins::{Config, Distro, PluginConfig, external, reboot},
+  utils::{expr::Expr, parse_duration},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+  Warning,
+  Error,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Issue {
+  pub severity: Severity,
+  pub recipe: Option<String>,
+  pub message: String,
+}
+
+impl Issue {
+  fn error(recipe: &str, message: String) -> Self {
+    Issue {
+      severity: Severity::Error,
+      recipe: Some(recipe.to_string()),
+      message,
+    }
+  }
+
+  fn warning(recipe: &str, message: String) -> Self {
+    Issue {
+      severity: Severity::Warning,
+      recipe: Some(recipe.to_string()),
+      message,
+    }
+  }
+
+  pub fn is_error(&self) -> bool { self.severity == Severity::Error }
+}
+
+impl fmt::Display for Issue {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let severity = match self.severity {
+      Severity::Warning => "warning",
+      Severity::Error => "error",
+    };
+    match &self.recipe {
+      Some(recipe) => write!(f, "{severity}: recipe '{recipe}': {}", self.message),
+      None => write!(f, "{severity}: {}", self.message),
+    }
+  }
+}
+
+impl Config {
+  /// Run semantic checks that deserialization alone cannot catch.
+  pub fn validate(&self) -> Vec<Issue> { self.validate_with(|root| Distro::detect(root)) }
+
+  /// `validate`, detecting the distro of a recipe's root with `detect`.
+  fn validate_with(&self, detect: impl Fn(&str) -> Option<Distro>) -> Vec<Issue> {
+    let mut issues = Vec::new();
+    let mut ids = HashSet::new();
+    let mut reboot_without_handoff: Option<&str> = None;
+
+    for recipe in &self.recipe {
+      if !ids.insert(recipe.id.as_str()) {
+        issues.push(Issue::error(&recipe.id, "Duplicate recipe id".to_string()));
+      }
+      if let Some(reboot_id) = reboot_without_handoff {
+        issues.push(Issue::error(
+          &recipe.id,
+          format!("Placed after reboot recipe '{reboot_id}' without `move_state`, it will never run"),
+        ));
+      }
+
+      if let Some(when) = &recipe.when &&
+        let Err(e) = when.parse::<Expr>()
+      {
+        issues.push(Issue::error(&recipe.id, format!("Invalid condition `{when}`: {e}")));
+      }
+
+      if let Some(timeout) = &recipe.timeout &&
+        let Err(e) = parse_duration(timeout)
+      {
+        issues.push(Issue::error(&recipe.id, format!("Invalid `timeout`: {e}")));
+      }
+      if let Some(retry) = &recipe.retry {
+        if retry.attempts == 0 {
+          issues.push(Issue::error(
+            &recipe.id,
+            "Retry `attempts` must be at least 1".to_string(),
+          ));
+        }
+        if let Some(backoff) = &retry.backoff &&
+          let Err(e) = parse_duration(backoff)
+        {
+          issues.push(Issue::error(&recipe.id, format!("Invalid retry `backoff`: {e}")));
+        }
+      }
+
+      match &recipe.config {
+        PluginConfig::PackageManager(_)
+          if recipe.into_state(&self.global).global.distro_hint.is_none() &&
+            recipe.config.root().and_then(&detect).is_none() =>
+        {
+          issues.push(Issue::warning(
+            &recipe.id,
+            "Package manager has no `distro_hint` in `global` or `overrides` and no known distro is detected"
+              .to_string(),
+          ));
+        }
+        PluginConfig::Shell(config) => match (&config.script, &config.commands) {
+          (None, None) => issues.push(Issue::error(
+            &recipe.id,
+            "Shell needs `script` or `commands`".to_string(),
+          )),
+          (_, Some(commands)) if commands.iter().any(|v| v.is_empty()) => issues.push(Issue::error(
+            &recipe.id,
+            "Shell `commands` has an empty command".to_string(),
+          )),
+          _ => {}
+        },
+        PluginConfig::External(config) if config.plugin.is_empty() || config.plugin.contains('/') => {
+          issues.push(Issue::error(
+            &recipe.id,
+            format!(
+              "External `plugin` must be a file name in `plugin_dir`, got '{}'",
+              config.plugin
+            ),
+          ));
+        }
+        PluginConfig::External(config)
+          if !external::is_registered(&config.plugin) && !Path::new(&config.path()).is_file() =>
+        {
+          issues.push(Issue::warning(
+            &recipe.id,
+            format!("External plugin {} not found at {}", config.plugin, config.path()),
+          ));
+        }
+        PluginConfig::Reboot(config) => {
+          let reboot::Config::Kexec(kexec) = config;
+          match (&kexec.linux, &kexec.initrd) {
+            (Some(_), None) => issues.push(Issue::error(
+              &recipe.id,
+              "Kexec `linux` is set without `initrd`".to_string(),
+            )),
+            (None, Some(_)) => issues.push(Issue::error(
+              &recipe.id,
+              "Kexec `initrd` is set without `linux`".to_string(),
+            )),
+            _ => {}
+          }
+          if kexec.move_state.is_none() && reboot_without_handoff.is_none() {
+            reboot_without_handoff = Some(recipe.id.as_str());
+          }
+        }
+        _ => {}
+      }
+    }
+
+    let notify = self.global.iter().chain(self.recipe.iter().filter_map(|v| v.overrides.as_ref()));
+    for target in notify.filter_map(|v| v.notify.as_ref()).flatten() {
+      let message = if !target.url.starts_with("http://") && !target.url.starts_with("https://") {
+        format!("`notify` URL must start with http:// or https://, got '{}'", target.url)
+      } else if target.attempts == Some(0) {
+        format!("`notify` attempts for {} must be at least 1", target.url)
+      } else {
+        continue;
+      };
+      issues.push(Issue {
+        severity: Severity::Error,
+        recipe: None,
+        message,
+      });
+    }
+
+    if ids.len() == self.recipe.len() &&
+      let Err(e) = self.dependencies()
+    {
+      issues.push(Issue {
+        severity: Severity::Error,
+        recipe: None,
+        message: e.to_string(),
+      });
+    }
+    issues
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn validate_config() {
+    let config = Config::from_yaml(
+      r#"
+global:
+  notify: [{ url: ftp://inventory.example/hooks }]
+recipe:
+  - id: packages
+    use: package_manager
+    with:
+      install: [vim]
+  - id: reboot
+    use: reboot
+    with:
+      type: kexec
+      linux: /mnt/boot/vmlinuz
+      root: /mnt
+  - id: packages
+    use: package_manager
+    with:
+      update: false
+    when: facts.arch ==
+    timeout: 5 minutes
+"#,
+    )
+    .unwrap();
+
+    let issues = config.validate_with(|_| Some(Distro::Debian));
+    assert!(issues.iter().all(|v| v.is_error()));
+    assert_eq!(issues.len(), 6);
+    let issues = config.validate_with(|_| None);
+    assert_eq!(issues.len(), 8);
+    assert_eq!(issues.iter().filter(|v| !v.is_error()).count(), 2);
+    assert!(issues.iter().any(|v| v.message.contains("no `distro_hint`")));
+    assert!(issues.iter().any(|v| v.message == "Duplicate recipe id"));
+    assert!(issues.iter().any(|v| v.message.contains("without `initrd`")));
+    assert!(issues.iter().any(|v| v.message.contains("will never run")));
+    assert!(issues.iter().any(|v| v.message.starts_with("Invalid condition")));
+    assert!(issues.iter().any(|v| v.message.starts_with("Invalid `timeout`")));
+    assert!(issues.iter().any(|v| v.message.starts_with("`notify` URL")));
+
+    let (config, ignored) = Config::load("../examples/deploy_ubuntu.yaml").unwrap();
+    assert!(ignored.is_empty(), "Unexpected unknown fields: {ignored:?}");
+    assert_eq!(config.validate(), vec![]);
+  }
+}